@@ -57,8 +57,8 @@ case class CPUParameter(useAsyncReset: Boolean, extensions: Seq[String]) extends
 
   val ResetVector: Long = 0x80000000L
 
-  // TODO: support external interrupt
-  val NrExtIntr: Int = 0
+  // interrupt lines in the mip bit layout (MSIP=3, MTIP=7, SEIP=9, MEIP=11), driven by the testbench
+  val NrExtIntr: Int = 12
 
   val HasDTLB:   Boolean = false
   val HasITLB:   Boolean = false
@@ -221,6 +221,7 @@ class CPU(val parameter: CPUParameter)
   ibuf.io.flush := flush1
   isu.io.flush := flush1
   exu.io.flush := flush1
+  exu.io.intr := io.intr
 
   // bypass
   isu.io.forward <> exu.io.forward
@@ -248,7 +249,8 @@ class CPU(val parameter: CPUParameter)
   layer.block(layers.Verification) {
     val probeWire: CPUProbe = Wire(new CPUProbe(parameter))
     define(io.cpuProbe, ProbeValue(probeWire))
-    probeWire.retire.valid := RegNext(wbu.io.in.fire)
+    // an interrupted instruction did not execute, the handler retires next
+    probeWire.retire.valid := RegNext(wbu.io.in.fire && !wbu.io.in.bits.intr)
     probeWire.retire.bits.inst := RegNext(wbu.io.in.bits.instr)
     probeWire.retire.bits.pc := RegNext(wbu.io.in.bits.pc)
     probeWire.retire.bits.gpr := probe.read(regfile.io.probe).gpr
@@ -23,6 +23,7 @@ class WriteBackIO(parameter: CPUParameter) extends Bundle {
   val pc = UInt(parameter.VAddrBits.W)
   val isRVC = Bool()
   val skip = Bool()
+  val intr = Bool()
   val is_load = Bool()
   val is_store = Bool()
 }
@@ -37,6 +38,7 @@ class EXUInterface(parameter: CPUParameter) extends Bundle {
   val in = Flipped(Decoupled(new DecodeIO(parameter.iduParameter)))
   val out = Decoupled(new WriteBackIO(parameter))
   val flush = Input(Bool())
+  val intr = Input(UInt(parameter.NrExtIntr.W))
   val forward = new ForwardIO(parameter.LogicRegsWidth, parameter.XLEN)
   // val dmem = new AXI4RWIrrevocable(parameter.loadStoreAXIParameter)
   val dmem = new DmemInterface(parameter.PAddrBits, parameter.DataBits)
@@ -111,6 +113,11 @@ class EXU(val parameter: CPUParameter)
   csr.valid := (state === s_idle) && iscsr && !io.flush && io.in.valid
   csr.zimm := io.in.bits.instr(19, 15)
   csr.pc := io.in.bits.pc
+  csr.intr := io.intr
+  // interrupts are only taken before instructions that finish in this cycle
+  csr.instrValid := (state === s_idle) && !islsu && !ismdu && !io.flush && io.in.valid
+  // the instruction is replaced by a jump to the trap handler
+  val intrTaken = csr.intrNO(parameter.XLEN - 1)
 
   when(lsu.valid || mdu.in.valid) {
     state := s_busy
@@ -135,9 +142,9 @@ class EXU(val parameter: CPUParameter)
   val target = MuxCase(
     io.in.bits.pc + 4.U,
     Array(
+      csr.redirect.valid -> csr.redirect.target,
       (isJmp && !jmp.isAuipc) -> jmp.target,
       (isBrh && brh.taken) -> brh.target,
-      csr.redirect.valid -> csr.redirect.target,
       io.in.bits.isRVC -> (io.in.bits.pc + 2.U)
     )
   )
@@ -150,24 +157,24 @@ class EXU(val parameter: CPUParameter)
   // forward
   io.forward.rfDest := io.in.bits.ldest
   io.forward.rfData := io.out.bits.wb.data
-  io.forward.valid := io.out.valid & io.in.bits.rfWen
+  io.forward.valid := io.out.valid & io.out.bits.wb.wen
 
   // update bpu
   io.bpuUpdate.pht.bits.pc := io.in.bits.pc
   io.bpuUpdate.pht.bits.taken := brh.taken
-  io.bpuUpdate.pht.valid := isBrh & io.out.fire
+  io.bpuUpdate.pht.valid := isBrh & io.out.fire & !intrTaken
 
   io.bpuUpdate.btb.bits.pc := io.in.bits.pc
   io.bpuUpdate.btb.bits.target := target
   io.bpuUpdate.btb.bits.brtype := brtype
   io.bpuUpdate.btb.bits.isRVC := io.in.bits.isRVC
-  io.bpuUpdate.btb.valid := mistarget
+  io.bpuUpdate.btb.valid := mistarget & !intrTaken
 
   io.bpuUpdate.ras.bits.brtype := brtype
   io.bpuUpdate.ras.bits.isRVC := io.in.bits.isRVC
-  io.bpuUpdate.ras.valid := Brtype.isRas(brtype) & io.out.fire
+  io.bpuUpdate.ras.valid := Brtype.isRas(brtype) & io.out.fire & !intrTaken
 
-  io.out.bits.wb.wen := io.in.bits.rfWen
+  io.out.bits.wb.wen := io.in.bits.rfWen && !intrTaken
   io.out.bits.wb.addr := io.in.bits.ldest
   io.out.bits.wb.data := MuxCase(
     alu.result,
@@ -183,6 +190,7 @@ class EXU(val parameter: CPUParameter)
   io.out.bits.instr := io.in.bits.instr
   io.out.bits.isRVC := io.in.bits.isRVC
   io.out.bits.pc := io.in.bits.pc
+  io.out.bits.intr := intrTaken
   io.out.bits.is_load := FuType.isldu(fuType)
   io.out.bits.is_store := FuType.isstu(fuType)
   val addr = io.in.bits.src(0) + io.in.bits.imm
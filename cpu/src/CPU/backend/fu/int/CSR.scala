@@ -82,17 +82,17 @@ trait HasCSRConst {
   def ModeS     = 0x1.U
   def ModeU     = 0x0.U
 
-  def IRQ_UEIP  = 0
-  def IRQ_SEIP  = 1
-  def IRQ_MEIP  = 3
+  def IRQ_USIP  = 0
+  def IRQ_SSIP  = 1
+  def IRQ_MSIP  = 3
 
   def IRQ_UTIP  = 4
   def IRQ_STIP  = 5
   def IRQ_MTIP  = 7
 
-  def IRQ_USIP  = 8
-  def IRQ_SSIP  = 9
-  def IRQ_MSIP  = 11
+  def IRQ_UEIP  = 8
+  def IRQ_SEIP  = 9
+  def IRQ_MEIP  = 11
 
   val IntPriority = Seq(
     IRQ_MEIP, IRQ_MSIP, IRQ_MTIP,
@@ -149,13 +149,16 @@ class CSRInterface(parameter: CPUParameter) extends Bundle {
   val result = Output(UInt(parameter.XLEN.W))
   val redirect = new RedirectIO(parameter.VAddrBits)
   val probe = Output(Probe(new CSRProbe(parameter), layers.Verification))
+  // interrupt lines in the mip bit layout
+  val intr = Input(UInt(parameter.NrExtIntr.W))
+  // an instruction that may be interrupted is in the stage
+  val instrValid = Input(Bool())
+  // cause of the interrupt taken in this cycle, 0 if none
+  val intrNO = Output(UInt(parameter.XLEN.W))
 
   //val redirect = new RedirectIO
   // for exception check
-  //val instrValid = Input(Bool())
   //val isBackendException = Input(Bool())
-  //for differential testing
-  //val intrNO = Output(UInt(XLEN.W))
   //val imemMMU = Flipped(new MMUIO)
   //val dmemMMU = Flipped(new MMUIO)
   //val wenFix = Output(Bool())
@@ -237,6 +240,10 @@ class CSR(val parameter: CPUParameter)
   val mipReg  = RegInit(0.U(64.W))
   //val mipFixMask = "h77f".U(64.W)
   val mip = (mipWire.asUInt | mipReg).asTypeOf(new Interrupt)
+  mipWire.s.m := io.intr(IRQ_MSIP)
+  mipWire.t.m := io.intr(IRQ_MTIP)
+  mipWire.e.s := io.intr(IRQ_SEIP)
+  mipWire.e.m := io.intr(IRQ_MEIP)
 
   private def GenMask(i: Int): UInt = GenMask(i, i)
   private def GenMask(i: Int, j: Int): UInt = ZeroExt(Fill(i - j + 1, true.B) << j, 64)
@@ -372,12 +379,28 @@ class CSR(val parameter: CPUParameter)
 
   )
 
+  // interrupts
+  val intrVecEnable = Wire(Vec(12, Bool()))
+  val ideleg = mideleg & mip.asUInt
+  def privilegedEnableDetect(x: Bool): Bool = Mux(
+    x,
+    ((privilegeMode === ModeS) && mstatusStruct.ie.s) || (privilegeMode < ModeS),
+    ((privilegeMode === ModeM) && mstatusStruct.ie.m) || (privilegeMode < ModeM)
+  )
+  intrVecEnable.zip(ideleg.asBools).map { case (x, y) => x := privilegedEnableDetect(y) }
+  val intrVec = mie(11, 0) & mip.asUInt & intrVecEnable.asUInt
+  val raiseIntr = intrVec.orR && io.instrValid
+  val intrNO = IntPriority.foldRight(0.U)((i: Int, sum: UInt) => Mux(intrVec(i), i.U, sum))
+
+  // an interrupted instruction has no other effect
+  val valid = io.valid && !raiseIntr
+
   val addr = io.src(1)(11, 0)
   val func = io.func
   val src1 = io.src(0)
   val csri = ZeroExt(io.zimm, XLEN)
   val rdata = Wire(UInt(XLEN.W))
-  val wen = CSROpType.isCsrAccess(func) && io.valid
+  val wen = CSROpType.isCsrAccess(func) && valid
   val wdata = LookupTree(func, List(
     CSROpType.csrrw  -> src1,
     CSROpType.csrrs  -> (rdata | src1),
@@ -393,28 +416,23 @@ class CSR(val parameter: CPUParameter)
 
   // CSR inst decode
   val ret = Wire(Bool())
-  val isEbreak = addr === privEbreak && CSROpType.isSystemOp(func) && io.valid
-  val isEcall = addr === privEcall && CSROpType.isSystemOp(func) && io.valid
-  val isMret = addr === privMret   && CSROpType.isSystemOp(func) && io.valid
-  val isSret = addr === privSret   && CSROpType.isSystemOp(func) && io.valid
-  val isUret = addr === privUret   && CSROpType.isSystemOp(func) && io.valid
+  val isEbreak = addr === privEbreak && CSROpType.isSystemOp(func) && valid
+  val isEcall = addr === privEcall && CSROpType.isSystemOp(func) && valid
+  val isMret = addr === privMret   && CSROpType.isSystemOp(func) && valid
+  val isSret = addr === privSret   && CSROpType.isSystemOp(func) && valid
+  val isUret = addr === privUret   && CSROpType.isSystemOp(func) && valid
 
 
   // Exception and Intr
 
-  // interrupts
-  val raiseIntr = mip.asUInt.orR
-  val intrVec = 0.U(9.W)
-  val intrNO = IntPriority.foldRight(0.U)((i: Int, sum: UInt) => Mux(intrVec(i), i.U, sum))
-
   // exceptions
 
   val csrExceptionVec = Wire(Vec(16, Bool()))
   csrExceptionVec.map(_ := false.B)
-  csrExceptionVec(breakPoint) := io.valid && isEbreak
-  csrExceptionVec(ecallM) := privilegeMode === ModeM && io.valid && isEcall
-  csrExceptionVec(ecallS) := privilegeMode === ModeS && io.valid && isEcall
-  csrExceptionVec(ecallU) := privilegeMode === ModeU && io.valid && isEcall
+  csrExceptionVec(breakPoint) := valid && isEbreak
+  csrExceptionVec(ecallM) := privilegeMode === ModeM && valid && isEcall
+  csrExceptionVec(ecallS) := privilegeMode === ModeS && valid && isEcall
+  csrExceptionVec(ecallU) := privilegeMode === ModeU && valid && isEcall
   //csrExceptionVec(illegalInstr) := (isIllegalAddr || isIllegalAccess) && wen //&& !io.isBackendException // Trigger an illegal instr exception when unimplemented csr is being read/written or not having enough privilege
   //csrExceptionVec(loadPageFault) := hasLoadPageFault
   //csrExceptionVec(storePageFault) := hasStorePageFault
@@ -425,12 +443,12 @@ class CSR(val parameter: CPUParameter)
   //io.wenFix := raiseException
 
   val causeNO = (raiseIntr << (XLEN-1)) | Mux(raiseIntr, intrNO, exceptionNO)
-  //io.intrNO := Mux(raiseIntr, causeNO, 0.U)
+  io.intrNO := Mux(raiseIntr, causeNO, 0.U)
 
-  val raiseExceptionIntr = (raiseException || raiseIntr) && io.valid
+  val raiseExceptionIntr = (raiseException && valid) || raiseIntr
   val retTarget = Wire(UInt(VAddrBits.W))
   val trapTarget = Wire(UInt(VAddrBits.W))
-  io.redirect.valid := (io.valid && func === CSROpType.jmp) || raiseExceptionIntr || resetSatp
+  io.redirect.valid := (valid && func === CSROpType.jmp) || raiseExceptionIntr || resetSatp
   io.redirect.target := Mux(resetSatp, io.pc + 4.U, Mux(raiseExceptionIntr, trapTarget, retTarget))
 
   // Branch control
@@ -451,7 +469,7 @@ class CSR(val parameter: CPUParameter)
   // TODO redirect target
   // val illegalEret = TODO
 
-  when (io.valid && isMret) {
+  when (valid && isMret) {
     val mstatusOld = WireInit(mstatus.asTypeOf(new StatusStruct))
     val mstatusNew = WireInit(mstatus.asTypeOf(new StatusStruct))
     // mstatusNew.mpp.m := ModeU //TODO: add mode U
@@ -464,7 +482,7 @@ class CSR(val parameter: CPUParameter)
     retTarget := mepc(VAddrBits-1, 0)
   }
 
-  when (io.valid && isSret) {
+  when (valid && isSret) {
     val mstatusOld = WireInit(mstatus.asTypeOf(new StatusStruct))
     val mstatusNew = WireInit(mstatus.asTypeOf(new StatusStruct))
     // mstatusNew.mpp.m := ModeU //TODO: add mode U
@@ -477,7 +495,7 @@ class CSR(val parameter: CPUParameter)
     retTarget := sepc(VAddrBits-1, 0)
   }
 
-  when (io.valid && isUret) {
+  when (valid && isUret) {
     val mstatusOld = WireInit(mstatus.asTypeOf(new StatusStruct))
     val mstatusNew = WireInit(mstatus.asTypeOf(new StatusStruct))
     // mstatusNew.mpp.m := ModeU //TODO: add mode U
@@ -1,12 +1,8 @@
 package cpu.cpuemu.vip
 
 import chisel3._
-import chisel3.util.circt.dpi.{
-  RawClockedNonVoidFunctionCall,
-  RawClockedVoidFunctionCall,
-  RawUnclockedNonVoidFunctionCall
-}
-import chisel3.util.{isPow2, log2Ceil}
+import chisel3.util.circt.dpi.{RawClockedNonVoidFunctionCall, RawClockedVoidFunctionCall}
+import chisel3.util.log2Ceil
 import chisel3.experimental.hierarchy.{instantiable, public, Instance, Instantiate}
 import chisel3.experimental.{SerializableModule, SerializableModuleParameter}
 import amba.axi4._
@@ -20,6 +16,25 @@ class WritePayload(length: Int, dataWidth: Int) extends Bundle {
 // TODO: consider adding the latency of the read transaction
 class ReadPayload(length: Int, dataWidth: Int) extends Bundle {
   val data = Vec(length, UInt(dataWidth.W))
+  // RRESP of each beat, aligned to u8 for a simple C-API
+  val resp = Vec(length, UInt(8.W))
+}
+
+/** Up to `length` beats of an R burst handed out by `axi_read_poll`, `valid` is 0 when no response is ready.
+  * Bit 1 of `valid` is set when more beats of the burst follow in the next poll.
+  */
+class ReadResponse(length: Int, dataWidth: Int) extends Bundle {
+  val payload = new ReadPayload(length, dataWidth)
+  val id = UInt(16.W)
+  val len = UInt(8.W)
+  val valid = UInt(8.W)
+}
+
+/** A B handed out by `axi_write_poll`, `valid` is 0 when no response is ready. */
+class WriteResponse extends Bundle {
+  val id = UInt(16.W)
+  val resp = UInt(8.W)
+  val valid = UInt(8.W)
 }
 
 case class AXI4VIPParameter(
@@ -39,6 +54,16 @@ class AXI4VIPInterface(parameter: AXI4VIPParameter) extends Bundle {
 class AXI4VIP(parameter: AXI4VIPParameter) extends FixedIORawModule[AXI4VIPInterface](new AXI4VIPInterface(parameter)) {
   dontTouch(io)
 
+  /** Handshakes to hold back this cycle, randomized by the `+axi-fuzz` stress mode, 0 otherwise.
+    * Bits must match cpuemu/src/axi/fuzz.rs.
+    */
+  private val stall = RawClockedNonVoidFunctionCall(s"axi_stall", UInt(8.W))(io.clock, true.B, io.channelId)
+  private val stallArReady = stall(0)
+  private val stallAwReady = stall(1)
+  private val stallWReady = stall(2)
+  private val stallRValid = stall(3)
+  private val stallBValid = stall(4)
+
   io.channel match {
     case channel: AXI4RWIrrevocable =>
       new WriteManager(channel)
@@ -53,9 +78,15 @@ class AXI4VIP(parameter: AXI4VIPParameter) extends FixedIORawModule[AXI4VIPInter
       /** There is an aw in the register. */
       val awIssued = RegInit(false.B)
 
-      /** There is a w in the register. */
+      /** The last w of the burst is in [[writePayload]]. */
       val last = RegInit(false.B)
 
+      /** [[writePayload]] is full before the last w, it goes to `axi_write_chunk` this cycle. */
+      val full = RegInit(false.B)
+
+      /** Bursts committed to the memory model whose B has not fired yet. */
+      val inflight = RegInit(0.U(log2Ceil(parameter.outstanding + 1).W))
+
       /** memory to store the write payload
         * @todo
         *   limit the payload size based on the RTL configuration.
@@ -63,7 +94,7 @@ class AXI4VIP(parameter: AXI4VIPParameter) extends FixedIORawModule[AXI4VIPInter
       val writePayload =
         RegInit(0.U.asTypeOf(new WritePayload(parameter.writePayloadSize, parameter.axiParameter.dataWidth)))
 
-      /** AWID, latch at AW fire, used at B fire. */
+      /** AW fields, latched at AW fire, passed to `axi_write` at commit. */
       val awid = RegInit(0.U.asTypeOf(chiselTypeOf(channel.aw.bits.id)))
       val awaddr = RegInit(0.U.asTypeOf(chiselTypeOf(channel.aw.bits.addr)))
       val awlen = RegInit(0.U.asTypeOf(chiselTypeOf(channel.aw.bits.len)))
@@ -80,13 +111,12 @@ class AXI4VIP(parameter: AXI4VIPParameter) extends FixedIORawModule[AXI4VIPInter
       val writeIdx = RegInit(0.U.asTypeOf(UInt(8.W)))
       val bFire = channel.b.ready && channel.b.valid
       val awFire = channel.aw.ready && channel.aw.valid
-      val wLastFire = channel.w.valid && channel.w.ready && channel.w.bits.last
-      val awExist = channel.aw.valid || awIssued
-      val wExist = channel.w.valid && channel.w.bits.last || last
+      val wFire = channel.w.valid && channel.w.ready
 
       // AW
-      channel.aw.ready := !awIssued || (wExist && channel.b.ready)
-      when(channel.aw.ready && channel.aw.valid) {
+      channel.aw.ready := !awIssued && !stallAwReady
+      when(awFire) {
+        awIssued := true.B
         awid := channel.aw.bits.id
         awaddr := channel.aw.bits.addr
         awlen := channel.aw.bits.len
@@ -99,115 +129,163 @@ class AXI4VIP(parameter: AXI4VIPParameter) extends FixedIORawModule[AXI4VIPInter
         awregion := channel.aw.bits.region
         awuser := channel.aw.bits.user
       }
-      when(awFire ^ bFire) {
-        awIssued := awFire
-      }
 
       // W
-      val writePayloadUpdate = WireDefault(writePayload)
-      channel.w.ready := !last || (awExist && channel.b.ready)
-      when(channel.w.valid && channel.w.ready) {
+      channel.w.ready := !last && !full && !stallWReady
+      when(wFire) {
         writePayload.data(writeIdx) := channel.w.bits.data
-        writePayloadUpdate.data(writeIdx) := channel.w.bits.data
         writePayload.strb(writeIdx) := channel.w.bits.strb.pad(writePayload.strb.getWidth)
-        writePayloadUpdate.strb(writeIdx) := channel.w.bits.strb.pad(writePayload.strb.getWidth)
         writeIdx := writeIdx + 1.U
         when(channel.w.bits.last) {
           writeIdx := 0.U
+          last := true.B
+        }.elsewhen(writeIdx === (parameter.writePayloadSize - 1).U) {
+          writeIdx := 0.U
+          full := true.B
         }
       }
-      when(channel.w.valid && channel.w.ready && channel.w.bits.last ^ channel.b.ready) {
-        last := channel.w.bits.last
+
+      // Hand a full payload of a long INCR burst over before taking more w, the rest comes with `axi_write`.
+      RawClockedVoidFunctionCall(s"axi_write_chunk")(io.clock, full, io.channelId, writePayload)
+      when(full) {
+        full := false.B
+      }
+
+      // Commit the whole burst once AW and the last W are in, the response is polled for later.
+      val commit = awIssued && last && inflight < parameter.outstanding.U
+      RawClockedVoidFunctionCall(s"axi_write")(
+        io.clock,
+        commit,
+        io.channelId,
+        awid.asTypeOf(UInt(64.W)),
+        awaddr.asTypeOf(UInt(64.W)),
+        awlen.asTypeOf(UInt(64.W)),
+        awsize.asTypeOf(UInt(64.W)),
+        awburst.asTypeOf(UInt(64.W)),
+        awlock.asTypeOf(UInt(64.W)),
+        awcache.asTypeOf(UInt(64.W)),
+        awprot.asTypeOf(UInt(64.W)),
+        awqos.asTypeOf(UInt(64.W)),
+        awregion.asTypeOf(UInt(64.W)),
+        writePayload
+      )
+      when(commit) {
+        awIssued := false.B
+        last := false.B
+      }
+
+      // B, polled from the scheduler whenever the B register is free
+      val bValid = RegInit(false.B)
+      val bid = RegInit(0.U.asTypeOf(chiselTypeOf(channel.b.bits.id)))
+      val bresp = RegInit(0.U.asTypeOf(chiselTypeOf(channel.b.bits.resp)))
+
+      /** The DPI result is only defined right after the call, don't poll again before taking it. */
+      val poll = Wire(Bool())
+      val response = RawClockedNonVoidFunctionCall(s"axi_write_poll", new WriteResponse)(
+        io.clock,
+        poll,
+        io.channelId
+      )
+      val polled = RegNext(poll, false.B)
+      val take = polled && response.valid(0)
+      poll := !bValid && !take
+      when(take) {
+        bValid := true.B
+        bid := response.id.asTypeOf(bid)
+        bresp := response.resp.asTypeOf(bresp)
       }
 
-      // B
-      channel.b.valid := awExist && wExist
-      channel.b.bits.id := Mux(awIssued, awid, channel.aw.bits.id)
-      channel.b.bits.resp := 0.U(2.W) // OK
+      /** A valid may be held back before it shows up, but must stay up until it fires. */
+      val bShown = RegInit(false.B)
+      channel.b.valid := bValid && (bShown || !stallBValid)
+      channel.b.bits.id := bid
+      channel.b.bits.resp := bresp
       channel.b.bits.user := DontCare
-      // TODO: add latency to the write transaction reply
-      when(channel.b.valid && channel.b.ready) {
-        RawClockedVoidFunctionCall(s"axi_write")(
-          io.clock,
-          when.cond,
-          io.channelId,
-          // handle AW and W at same beat.
-          Mux(awIssued, awid.asTypeOf(UInt(64.W)), channel.aw.bits.id),
-          Mux(awIssued, awaddr.asTypeOf(UInt(64.W)), channel.aw.bits.addr),
-          Mux(awIssued, awlen.asTypeOf(UInt(64.W)), channel.aw.bits.len),
-          Mux(awIssued, awsize.asTypeOf(UInt(64.W)), channel.aw.bits.size),
-          Mux(awIssued, awburst.asTypeOf(UInt(64.W)), channel.aw.bits.burst),
-          Mux(awIssued, awlock.asTypeOf(UInt(64.W)), channel.aw.bits.lock),
-          Mux(awIssued, awcache.asTypeOf(UInt(64.W)), channel.aw.bits.cache),
-          Mux(awIssued, awprot.asTypeOf(UInt(64.W)), channel.aw.bits.prot),
-          Mux(awIssued, awqos.asTypeOf(UInt(64.W)), channel.aw.bits.qos),
-          Mux(awIssued, awregion.asTypeOf(UInt(64.W)), channel.aw.bits.region),
-          writePayloadUpdate
-        )
+      when(channel.b.valid) {
+        bShown := true.B
+      }
+      when(bFire) {
+        bValid := false.B
+        bShown := false.B
       }
+      inflight := inflight + commit.asUInt - bFire.asUInt
     }
   }
 
   // read manager
   private class ReadManager(channel: HasAR with HasR) {
     withClockAndReset(io.clock, io.reset) {
-      class CAMValue extends Bundle {
-        val arid = UInt(16.W)
-        val arlen = UInt(8.W)
-        val readPayload = new ReadPayload(parameter.readPayloadSize, parameter.axiParameter.dataWidth)
-        val readPayloadIndex = UInt(8.W)
-        val valid = Bool()
-      }
 
-      /** CAM to maintain order of read requests. This is maintained as FIFO. */
-      val cam: Vec[CAMValue] = RegInit(0.U.asTypeOf(Vec(parameter.outstanding, new CAMValue)))
-      require(isPow2(parameter.outstanding), "Need to handle pointers")
-      val arPtr = RegInit(0.U.asTypeOf(UInt(log2Ceil(parameter.outstanding).W)))
-      val rPtr = RegInit(0.U.asTypeOf(UInt(log2Ceil(parameter.outstanding).W)))
-
-      // AR
-      channel.ar.ready := !cam(arPtr).valid
-      when(channel.ar.ready && channel.ar.valid) {
-        cam(arPtr).arid := channel.ar.bits.id
-        cam(arPtr).arlen := channel.ar.bits.len
-        cam(arPtr).readPayload := RawUnclockedNonVoidFunctionCall( // Unclocked or Clocked?
-          s"axi_read",
-          new ReadPayload(parameter.readPayloadSize, parameter.axiParameter.dataWidth)
-        )(
-          // io.clock,
-          when.cond,
-          io.channelId,
-          channel.ar.bits.id.asTypeOf(UInt(64.W)),
-          channel.ar.bits.addr.asTypeOf(UInt(64.W)),
-          channel.ar.bits.len.asTypeOf(UInt(64.W)),
-          channel.ar.bits.size.asTypeOf(UInt(64.W)),
-          channel.ar.bits.burst.asTypeOf(UInt(64.W)),
-          channel.ar.bits.lock.asTypeOf(UInt(64.W)),
-          channel.ar.bits.cache.asTypeOf(UInt(64.W)),
-          channel.ar.bits.prot.asTypeOf(UInt(64.W)),
-          channel.ar.bits.qos.asTypeOf(UInt(64.W)),
-          channel.ar.bits.region.asTypeOf(UInt(64.W))
-        )
-        cam(arPtr).readPayloadIndex := 0.U
-        cam(arPtr).valid := true.B
-        arPtr := arPtr + 1.U
+      /** Bursts requested whose last R has not fired yet. */
+      val inflight = RegInit(0.U(log2Ceil(parameter.outstanding + 1).W))
+      val arFire = channel.ar.ready && channel.ar.valid
+      val rLastFire = channel.r.ready && channel.r.valid && channel.r.bits.last
+
+      // AR, served right away, the response is polled for later
+      channel.ar.ready := inflight < parameter.outstanding.U && !stallArReady
+      RawClockedVoidFunctionCall(s"axi_read")(
+        io.clock,
+        arFire,
+        io.channelId,
+        channel.ar.bits.id.asTypeOf(UInt(64.W)),
+        channel.ar.bits.addr.asTypeOf(UInt(64.W)),
+        channel.ar.bits.len.asTypeOf(UInt(64.W)),
+        channel.ar.bits.size.asTypeOf(UInt(64.W)),
+        channel.ar.bits.burst.asTypeOf(UInt(64.W)),
+        channel.ar.bits.lock.asTypeOf(UInt(64.W)),
+        channel.ar.bits.cache.asTypeOf(UInt(64.W)),
+        channel.ar.bits.prot.asTypeOf(UInt(64.W)),
+        channel.ar.bits.qos.asTypeOf(UInt(64.W)),
+        channel.ar.bits.region.asTypeOf(UInt(64.W))
+      )
+
+      // R, a burst is polled from the scheduler whenever the previous one is done,
+      // bursts longer than the payload come in several polls
+      val rValid = RegInit(false.B)
+      val current =
+        RegInit(0.U.asTypeOf(new ReadResponse(parameter.readPayloadSize, parameter.axiParameter.dataWidth)))
+      val readPayloadIndex = RegInit(0.U(8.W))
+
+      /** The DPI result is only defined right after the call, don't poll again before taking it. */
+      val poll = Wire(Bool())
+      val response = RawClockedNonVoidFunctionCall(
+        s"axi_read_poll",
+        new ReadResponse(parameter.readPayloadSize, parameter.axiParameter.dataWidth)
+      )(
+        io.clock,
+        poll,
+        io.channelId
+      )
+      val polled = RegNext(poll, false.B)
+      val take = polled && response.valid(0)
+      poll := !rValid && !take
+      when(take) {
+        rValid := true.B
+        current := response
       }
 
-      // R
-      channel.r.valid := cam(rPtr).valid
-      channel.r.bits.id := cam(rPtr).arid
-      channel.r.bits.data := cam(rPtr).readPayload.data(cam(rPtr).readPayloadIndex)
-      channel.r.bits.resp := 0.U // OK
-      channel.r.bits.last := (cam(rPtr).arlen === cam(rPtr).readPayloadIndex) && cam(rPtr).valid
+      /** A valid may be held back before it shows up, but must stay up until it fires. */
+      val rShown = RegInit(false.B)
+      channel.r.valid := rValid && (rShown || !stallRValid)
+      when(channel.r.valid) {
+        rShown := true.B
+      }
+      channel.r.bits.id := current.id.asTypeOf(chiselTypeOf(channel.r.bits.id))
+      channel.r.bits.data := current.payload.data(readPayloadIndex)
+      channel.r.bits.resp := current.payload.resp(readPayloadIndex)(1, 0)
+      val chunkEnd = current.len === readPayloadIndex
+      channel.r.bits.last := chunkEnd && !current.valid(1)
       channel.r.bits.user := DontCare
       when(channel.r.ready && channel.r.valid) {
         // increase index
-        cam(rPtr).readPayloadIndex := cam(rPtr).readPayloadIndex + 1.U
-        when(channel.r.bits.last) {
-          cam(rPtr).valid := false.B
-          rPtr := rPtr + 1.U
+        readPayloadIndex := readPayloadIndex + 1.U
+        rShown := false.B
+        when(chunkEnd) {
+          readPayloadIndex := 0.U
+          rValid := false.B
         }
       }
+      inflight := inflight + arFire.asUInt - rLastFire.asUInt
     }
   }
 }
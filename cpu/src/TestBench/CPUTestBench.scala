@@ -68,7 +68,11 @@ class CPUTestBench(val parameter: CPUTestBenchParameter)
   // DUT
   dut.io.clock := implicitClock
   dut.io.reset := implicitReset
-  dut.io.intr := 0.U
+  // interrupt lines of the devices on the ShadowBus
+  dut.io.intr := RawClockedNonVoidFunctionCall("interrupt_lines", UInt(parameter.cpuParameter.NrExtIntr.W))(
+    implicitClock,
+    true.B
+  )
 
   // AXI4VIP
   val instructionFetchAXI = Module(
@@ -77,8 +81,9 @@ class CPUTestBench(val parameter: CPUTestBenchParameter)
         name = "instructionFetchAXI",
         axiParameter = parameter.cpuParameter.instructionFetchParameter,
         outstanding = 4,
-        readPayloadSize = 1,
-        writePayloadSize = 1
+        // must match AXI_PAYLOAD_BEATS in cpuemu/src/axi/mod.rs
+        readPayloadSize = 16,
+        writePayloadSize = 16
       )
     )
   )
@@ -92,8 +97,9 @@ class CPUTestBench(val parameter: CPUTestBenchParameter)
         name = "loadStoreAXI",
         axiParameter = parameter.cpuParameter.loadStoreAXIParameter,
         outstanding = 4,
-        readPayloadSize = 1,
-        writePayloadSize = 1
+        // must match AXI_PAYLOAD_BEATS in cpuemu/src/axi/mod.rs
+        readPayloadSize = 16,
+        writePayloadSize = 16
       )
     )
   )
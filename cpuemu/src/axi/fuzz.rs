@@ -0,0 +1,116 @@
+use std::collections::HashMap;
+
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use tracing::warn;
+
+// stall bits handed to the VIP by `axi_stall`, must match AXI4VIP.scala
+const STALL_AR_READY: u8 = 1 << 0;
+const STALL_AW_READY: u8 = 1 << 1;
+const STALL_W_READY: u8 = 1 << 2;
+const STALL_R_VALID: u8 = 1 << 3;
+const STALL_B_VALID: u8 = 1 << 4;
+const STALL_ALL: [u8; 5] = [
+  STALL_AR_READY,
+  STALL_AW_READY,
+  STALL_W_READY,
+  STALL_R_VALID,
+  STALL_B_VALID,
+];
+
+/// Highest `+axi-fuzz-stall`, a handshake stalled every cycle would never fire
+const MAX_STALL_PERCENT: u64 = 99;
+
+/// Seeded randomness for the AXI stress mode: extra response delays and
+/// per-cycle ready/valid stalls. The same seed replays the same timing.
+pub(crate) struct AxiFuzz {
+  seed: u64,
+  rng: StdRng,
+  /// one stream per channel, so a channel's stalls don't depend on the order
+  /// the VIPs are evaluated in
+  stall_rngs: HashMap<u64, StdRng>,
+  /// extra response delay is drawn from 0..=max_delay ticks
+  max_delay: u64,
+  /// chance for each handshake signal to stall in a cycle
+  stall: f64,
+}
+
+impl AxiFuzz {
+  pub fn new(seed: u64, max_delay: u64, stall_percent: u64) -> Self {
+    if stall_percent > MAX_STALL_PERCENT {
+      warn!(
+        "+axi-fuzz-stall={stall_percent} would deadlock the handshakes, using {MAX_STALL_PERCENT}"
+      );
+    }
+    Self {
+      seed,
+      rng: StdRng::seed_from_u64(seed),
+      stall_rngs: HashMap::new(),
+      max_delay,
+      stall: stall_percent.min(MAX_STALL_PERCENT) as f64 / 100.0,
+    }
+  }
+
+  pub fn delay(&mut self) -> u64 {
+    self.rng.gen_range(0..=self.max_delay)
+  }
+
+  /// Stall bits for one cycle of `channel_id`
+  pub fn stall(&mut self, channel_id: u64) -> u8 {
+    let seed = self.seed;
+    let rng = self
+      .stall_rngs
+      .entry(channel_id)
+      .or_insert_with(|| StdRng::seed_from_u64(seed ^ (channel_id + 1).rotate_left(32)));
+    STALL_ALL.iter().filter(|_| rng.gen_bool(self.stall)).fold(0, |bits, bit| bits | bit)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn stalls(fuzz: &mut AxiFuzz, channel_id: u64) -> Vec<u8> {
+    (0..64).map(|_| fuzz.stall(channel_id)).collect()
+  }
+
+  #[test]
+  fn same_seed_same_timing() {
+    let mut a = AxiFuzz::new(42, 16, 30);
+    let mut b = AxiFuzz::new(42, 16, 30);
+    let delays: Vec<_> = (0..64).map(|_| a.delay()).collect();
+    assert_eq!(delays, (0..64).map(|_| b.delay()).collect::<Vec<_>>());
+    assert!(delays.iter().all(|d| *d <= 16));
+    assert_eq!(stalls(&mut a, 0), stalls(&mut b, 0));
+    assert_ne!(
+      stalls(&mut AxiFuzz::new(43, 16, 30), 0),
+      stalls(&mut AxiFuzz::new(42, 16, 30), 0)
+    );
+  }
+
+  #[test]
+  fn channels_do_not_depend_on_the_call_order() {
+    let mut a = AxiFuzz::new(7, 0, 50);
+    let mut b = AxiFuzz::new(7, 0, 50);
+    let a0 = stalls(&mut a, 0);
+    let a1 = stalls(&mut a, 1);
+    let b1 = stalls(&mut b, 1);
+    let b0 = stalls(&mut b, 0);
+    assert_eq!((a0, a1), (b0, b1));
+  }
+
+  #[test]
+  fn stall_percent_is_clamped() {
+    let mut fuzz = AxiFuzz::new(1, 0, 0);
+    assert!(stalls(&mut fuzz, 0).iter().all(|bits| *bits == 0));
+    for percent in [MAX_STALL_PERCENT + 1, 100, 250] {
+      let fuzz = AxiFuzz::new(1, 0, percent);
+      assert_eq!(fuzz.stall, MAX_STALL_PERCENT as f64 / 100.0);
+    }
+    // every handshake still gets through now and then
+    let mut fuzz = AxiFuzz::new(1, 0, 100);
+    let all = STALL_ALL.iter().fold(0, |bits, bit| bits | bit);
+    let stalls: Vec<_> = (0..10000).map(|_| fuzz.stall(0)).collect();
+    assert!(STALL_ALL.iter().all(|bit| stalls.iter().any(|bits| bits & bit == 0)));
+    assert!(stalls.iter().any(|bits| *bits != all) && stalls.contains(&all));
+  }
+}
@@ -0,0 +1,168 @@
+//! AXI4 burst addressing, as in the AMBA AXI spec (IHI0022) A3.4
+
+mod fuzz;
+pub(crate) use fuzz::*;
+
+mod monitor;
+pub(crate) use monitor::*;
+
+mod scheduler;
+pub(crate) use scheduler::*;
+
+/// Beats the testbench payload has room for, must match `readPayloadSize`/`writePayloadSize`
+/// of the AXI4VIPs in `CPUTestBench.scala`. Longer INCR bursts (up to 256 beats) are moved
+/// over several DPI calls.
+pub(crate) const AXI_PAYLOAD_BEATS: usize = 16;
+
+/// AR or AW of one transaction, as handed over by the testbench
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct AxiRequest {
+  /// 0 = instruction fetch, 1 = load/store
+  pub channel_id: u64,
+  pub id: u64,
+  pub addr: u32,
+  pub len: u64,
+  pub size: u64,
+  pub burst: u64,
+  /// 1 = exclusive access
+  pub lock: u64,
+  /// AxPROT, bit 2 set = instruction access
+  pub prot: u64,
+}
+
+impl AxiRequest {
+  pub fn exclusive(&self) -> bool {
+    self.lock & 1 != 0
+  }
+
+  /// Instruction fetch rather than a data access, by AxPROT or the channel it came from
+  pub fn fetch(&self) -> bool {
+    self.prot & 0b100 != 0 || self.channel_id == 0
+  }
+
+  /// Bytes moved by the whole burst
+  pub fn bytes(&self) -> u32 {
+    (self.len as u32 + 1) << self.size
+  }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum AxiBurst {
+  Fixed,
+  Incr,
+  Wrap,
+}
+
+impl AxiBurst {
+  pub fn from_bits(burst: u64) -> anyhow::Result<Self> {
+    match burst {
+      0 => Ok(AxiBurst::Fixed),
+      1 => Ok(AxiBurst::Incr),
+      2 => Ok(AxiBurst::Wrap),
+      _ => anyhow::bail!("reserved burst type {burst}"),
+    }
+  }
+}
+
+/// RRESP/BRESP, ordered by severity
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub(crate) enum AxiResp {
+  Okay = 0,
+  ExOkay = 1,
+  SlvErr = 2,
+  DecErr = 3,
+}
+
+/// Address of every beat of a burst of `len + 1` beats of `1 << size` bytes.
+/// The first beat keeps the (possibly unaligned) start address, later INCR beats are aligned.
+pub(crate) fn burst_addrs(
+  addr: u32,
+  len: u64,
+  size: u64,
+  burst: AxiBurst,
+) -> anyhow::Result<Vec<u32>> {
+  let beats = len as usize + 1;
+  let bytes = 1u32 << size;
+  let addrs = match burst {
+    AxiBurst::Fixed => {
+      if beats > 16 {
+        anyhow::bail!("FIXED burst of {beats} beats at {addr:#x}, at most 16");
+      }
+      vec![addr; beats]
+    }
+    AxiBurst::Incr => {
+      let aligned = addr & !(bytes - 1);
+      (0..beats as u32).map(|n| if n == 0 { addr } else { aligned + n * bytes }).collect()
+    }
+    AxiBurst::Wrap => {
+      if !matches!(beats, 2 | 4 | 8 | 16) {
+        anyhow::bail!("WRAP burst of {beats} beats at {addr:#x}, must be 2, 4, 8 or 16");
+      }
+      if addr & (bytes - 1) != 0 {
+        anyhow::bail!("WRAP burst at {addr:#x} is not aligned to its {bytes}B beats");
+      }
+      let window = bytes * beats as u32;
+      let lower = addr / window * window;
+      (0..beats as u32).map(|n| lower + (addr - lower + n * bytes) % window).collect()
+    }
+  };
+  Ok(addrs)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn incr_aligns_every_beat_but_the_first() {
+    let addrs = burst_addrs(0x1003, 3, 2, AxiBurst::Incr).unwrap();
+    assert_eq!(addrs, [0x1003, 0x1004, 0x1008, 0x100c]);
+    // longer than the DPI payload
+    assert_eq!(
+      burst_addrs(0x2000, 255, 3, AxiBurst::Incr).unwrap().len(),
+      256
+    );
+  }
+
+  #[test]
+  fn wrap_turns_around_at_the_window() {
+    // 4 beats of 4 bytes, window [0x1000, 0x1010)
+    let addrs = burst_addrs(0x1008, 3, 2, AxiBurst::Wrap).unwrap();
+    assert_eq!(addrs, [0x1008, 0x100c, 0x1000, 0x1004]);
+    // 16 beats of 8 bytes, window [0x2000, 0x2080)
+    let addrs = burst_addrs(0x2078, 15, 3, AxiBurst::Wrap).unwrap();
+    assert_eq!(addrs[0], 0x2078);
+    assert_eq!(addrs[1], 0x2000);
+    assert_eq!(addrs[15], 0x2070);
+    // a start on the window keeps counting up
+    assert_eq!(
+      burst_addrs(0x1000, 1, 3, AxiBurst::Wrap).unwrap(),
+      [0x1000, 0x1008]
+    );
+  }
+
+  #[test]
+  fn wrap_rejects_bad_lengths_and_unaligned_starts() {
+    assert!(burst_addrs(0x1000, 2, 2, AxiBurst::Wrap).is_err());
+    assert!(burst_addrs(0x1000, 31, 2, AxiBurst::Wrap).is_err());
+    assert!(burst_addrs(0x1002, 3, 2, AxiBurst::Wrap).is_err());
+  }
+
+  #[test]
+  fn fixed_repeats_the_address() {
+    assert_eq!(
+      burst_addrs(0x1001, 3, 0, AxiBurst::Fixed).unwrap(),
+      [0x1001; 4]
+    );
+    assert_eq!(
+      burst_addrs(0x1000, 15, 2, AxiBurst::Fixed).unwrap().len(),
+      16
+    );
+    assert!(burst_addrs(0x1000, 16, 2, AxiBurst::Fixed).is_err());
+  }
+
+  #[test]
+  fn reserved_burst_type() {
+    assert!(AxiBurst::from_bits(3).is_err());
+  }
+}
@@ -0,0 +1,276 @@
+use std::str::FromStr;
+
+use tracing::{error, info, warn};
+
+use super::{burst_addrs, AxiBurst, AxiRequest};
+
+const BOUNDARY_4K: u64 = 4096;
+
+/// How violations found by a checker are reported, e.g. `+axi-check=off|warn|fatal`
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum CheckLevel {
+  Off,
+  #[default]
+  Warn,
+  /// the first violation ends the simulation with BadTrap
+  Fatal,
+}
+
+impl FromStr for CheckLevel {
+  type Err = anyhow::Error;
+
+  fn from_str(s: &str) -> anyhow::Result<Self> {
+    match s {
+      "off" => Ok(CheckLevel::Off),
+      "warn" => Ok(CheckLevel::Warn),
+      "fatal" => Ok(CheckLevel::Fatal),
+      _ => anyhow::bail!("unknown check level `{s}`, expect off, warn or fatal"),
+    }
+  }
+}
+
+/// Checks every AR/AW (and W strobes) crossing the DPI boundary against the AXI4 rules
+pub(crate) struct AxiMonitor {
+  level: CheckLevel,
+  /// data bus width in bytes
+  bus_bytes: u32,
+  violations: u64,
+}
+
+impl AxiMonitor {
+  pub fn new(level: CheckLevel, bus_bytes: u32) -> Self {
+    Self { level, bus_bytes, violations: 0 }
+  }
+
+  pub fn check_read(&mut self, tick: u64, req: &AxiRequest) -> anyhow::Result<()> {
+    if self.level == CheckLevel::Off {
+      return Ok(());
+    }
+    let problems = self.check_addr(req);
+    self.report(tick, "AR", req, problems)
+  }
+
+  /// `strobe` holds `len + 1` bus-width beats
+  pub fn check_write(
+    &mut self,
+    tick: u64,
+    req: &AxiRequest,
+    strobe: &[bool],
+  ) -> anyhow::Result<()> {
+    if self.level == CheckLevel::Off {
+      return Ok(());
+    }
+    let mut problems = self.check_addr(req);
+    if problems.is_empty() {
+      problems.extend(self.check_strobe(req, strobe));
+    }
+    self.report(tick, "AW", req, problems)
+  }
+
+  pub fn summary(&self) {
+    if self.violations != 0 {
+      info!("axi monitor: {} protocol violations", self.violations);
+    }
+  }
+
+  fn check_addr(&self, req: &AxiRequest) -> Vec<String> {
+    let mut problems = Vec::new();
+    let bytes = 1u64 << req.size;
+    let beats = req.len + 1;
+    if bytes > self.bus_bytes as u64 {
+      problems.push(format!(
+        "size of {bytes}B is wider than the {}B bus",
+        self.bus_bytes
+      ));
+    }
+    match AxiBurst::from_bits(req.burst) {
+      Err(e) => problems.push(e.to_string()),
+      Ok(AxiBurst::Wrap) => {
+        if !matches!(beats, 2 | 4 | 8 | 16) {
+          problems.push(format!(
+            "WRAP burst of {beats} beats, must be 2, 4, 8 or 16"
+          ));
+        }
+        if req.addr as u64 & (bytes - 1) != 0 {
+          problems.push(format!(
+            "WRAP burst start is not aligned to its {bytes}B beats"
+          ));
+        }
+      }
+      Ok(AxiBurst::Incr) => {
+        let last = (req.addr as u64 & !(bytes - 1)) + beats * bytes - 1;
+        if req.addr as u64 / BOUNDARY_4K != last / BOUNDARY_4K {
+          problems.push(format!(
+            "INCR burst up to {last:#x} crosses a 4KiB boundary"
+          ));
+        }
+      }
+      Ok(AxiBurst::Fixed) => {
+        if beats > 16 {
+          problems.push(format!("FIXED burst of {beats} beats, at most 16"));
+        }
+      }
+    }
+    if req.exclusive() {
+      let total = req.bytes() as u64;
+      if !total.is_power_of_two() || total > 128 {
+        problems.push(format!(
+          "exclusive access of {total}B, must be a power of two up to 128B"
+        ));
+      } else if req.addr as u64 & (total - 1) != 0 {
+        problems.push(format!("exclusive access is not aligned to its {total}B"));
+      }
+    }
+    problems
+  }
+
+  /// Strobes may only be set on the byte lanes each beat transfers
+  fn check_strobe(&self, req: &AxiRequest, strobe: &[bool]) -> Vec<String> {
+    let bus = self.bus_bytes as usize;
+    let bytes = 1usize << req.size;
+    let Ok(addrs) = AxiBurst::from_bits(req.burst)
+      .and_then(|burst| burst_addrs(req.addr, req.len, req.size, burst))
+    else {
+      return Vec::new();
+    };
+    addrs
+      .iter()
+      .zip(strobe.chunks(bus))
+      .enumerate()
+      .filter_map(|(beat, (&addr, lanes))| {
+        let addr = addr as usize;
+        let lo = addr % bus;
+        let hi = (addr & !(bytes - 1)) % bus + bytes;
+        let stray: Vec<usize> =
+          (0..lanes.len()).filter(|&lane| lanes[lane] && (lane < lo || lane >= hi)).collect();
+        (!stray.is_empty()).then(|| {
+          format!(
+            "beat {beat} at {addr:#x} strobes lanes {stray:?} outside the active lanes {lo}..{hi}"
+          )
+        })
+      })
+      .collect()
+  }
+
+  fn report(
+    &mut self,
+    tick: u64,
+    channel: &str,
+    req: &AxiRequest,
+    problems: Vec<String>,
+  ) -> anyhow::Result<()> {
+    if problems.is_empty() {
+      return Ok(());
+    }
+    self.violations += problems.len() as u64;
+    let msg = format!(
+      "[{tick}] axi protocol violation on {channel} (channel_id={}, id={}, addr={:#x}, len={}, size={}, burst={}, lock={}): {}",
+      req.channel_id,
+      req.id,
+      req.addr,
+      req.len,
+      req.size,
+      req.burst,
+      req.lock,
+      problems.join("; ")
+    );
+    match self.level {
+      CheckLevel::Fatal => {
+        error!("{msg}");
+        anyhow::bail!("AXI protocol violation")
+      }
+      _ => {
+        warn!("{msg}");
+        Ok(())
+      }
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn req(addr: u32, len: u64, size: u64, burst: u64) -> AxiRequest {
+    AxiRequest {
+      channel_id: 1,
+      id: 0,
+      addr,
+      len,
+      size,
+      burst,
+      lock: 0,
+      prot: 0,
+    }
+  }
+
+  fn problems(req: AxiRequest) -> Vec<String> {
+    AxiMonitor::new(CheckLevel::Warn, 8).check_addr(&req)
+  }
+
+  #[test]
+  fn legal_bursts_pass() {
+    assert!(problems(req(0x1000, 255, 3, 1)).is_empty());
+    assert!(problems(req(0x1008, 3, 3, 2)).is_empty());
+    assert!(problems(req(0x1003, 15, 0, 0)).is_empty());
+  }
+
+  #[test]
+  fn size_wider_than_the_bus() {
+    assert_eq!(problems(req(0x1000, 0, 4, 1)).len(), 1);
+  }
+
+  #[test]
+  fn incr_crossing_4k() {
+    assert_eq!(problems(req(0x1ff8, 1, 3, 1)).len(), 1);
+    assert!(problems(req(0x1ff8, 0, 3, 1)).is_empty());
+  }
+
+  #[test]
+  fn wrap_length_and_alignment() {
+    assert_eq!(problems(req(0x1000, 2, 3, 2)).len(), 1);
+    assert_eq!(problems(req(0x1004, 3, 3, 2)).len(), 1);
+  }
+
+  #[test]
+  fn fixed_and_reserved_bursts() {
+    assert_eq!(problems(req(0x1000, 16, 3, 0)).len(), 1);
+    assert_eq!(problems(req(0x1000, 0, 3, 3)).len(), 1);
+  }
+
+  #[test]
+  fn exclusive_size_and_alignment() {
+    let exclusive = |addr, len, size| AxiRequest { lock: 1, ..req(addr, len, size, 1) };
+    assert!(problems(exclusive(0x1010, 1, 3)).is_empty());
+    // 24 bytes, not a power of two
+    assert_eq!(problems(exclusive(0x1000, 2, 3)).len(), 1);
+    // 256 bytes
+    assert_eq!(problems(exclusive(0x1000, 31, 3)).len(), 1);
+    assert_eq!(problems(exclusive(0x1008, 1, 3)).len(), 1);
+  }
+
+  #[test]
+  fn strobes_outside_the_active_lanes() {
+    let monitor = AxiMonitor::new(CheckLevel::Warn, 8);
+    // 2 beats of 4 bytes from 0x1004: lanes 4..8, then 0..4
+    let aw = req(0x1004, 1, 2, 1);
+    let mut strobe = vec![false; 16];
+    strobe[4..8].fill(true);
+    strobe[8..12].fill(true);
+    assert!(monitor.check_strobe(&aw, &strobe).is_empty());
+    strobe[0] = true;
+    strobe[15] = true;
+    assert_eq!(monitor.check_strobe(&aw, &strobe).len(), 2);
+  }
+
+  #[test]
+  fn levels() {
+    let bad = req(0x1000, 2, 3, 2);
+    assert!(AxiMonitor::new(CheckLevel::Warn, 8).check_read(0, &bad).is_ok());
+    assert!(AxiMonitor::new(CheckLevel::Fatal, 8).check_read(0, &bad).is_err());
+    let mut off = AxiMonitor::new(CheckLevel::Off, 8);
+    assert!(off.check_read(0, &bad).is_ok());
+    assert_eq!(off.violations, 0);
+    assert!("loud".parse::<CheckLevel>().is_err());
+  }
+}
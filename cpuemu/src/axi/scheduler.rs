@@ -0,0 +1,171 @@
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::str::FromStr;
+
+/// Which ID gets its response out next, from `+axi-order=`
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum AxiOrder {
+  /// strictly in arrival order, across IDs too
+  #[default]
+  InOrder,
+  /// rotate over the IDs with a response ready
+  RoundRobin,
+  /// the most recent request whose response is ready goes first
+  Youngest,
+}
+
+impl FromStr for AxiOrder {
+  type Err = anyhow::Error;
+
+  fn from_str(s: &str) -> anyhow::Result<Self> {
+    match s {
+      "in-order" => Ok(AxiOrder::InOrder),
+      "round-robin" => Ok(AxiOrder::RoundRobin),
+      "youngest" => Ok(AxiOrder::Youngest),
+      _ => anyhow::bail!("unknown AXI order `{s}`, expect in-order, round-robin or youngest"),
+    }
+  }
+}
+
+struct Pending<T> {
+  /// arrival order over the whole channel
+  seq: u64,
+  /// first tick the response may be handed out
+  ready_at: u64,
+  resp: T,
+}
+
+struct Channel<T> {
+  /// responses of each ID, in arrival order
+  queues: BTreeMap<u64, VecDeque<Pending<T>>>,
+  /// ID served last, for round robin
+  last_id: Option<u64>,
+}
+
+/// Holds the responses of every channel until the testbench polls them,
+/// in order within an ID and in `order` across IDs.
+pub(crate) struct AxiScheduler<T> {
+  order: AxiOrder,
+  /// ticks a response is held at least, lets later requests overtake it
+  hold: u64,
+  channels: HashMap<u64, Channel<T>>,
+  seq: u64,
+}
+
+impl<T> AxiScheduler<T> {
+  pub fn new(order: AxiOrder, hold: u64) -> Self {
+    Self { order, hold, channels: HashMap::new(), seq: 0 }
+  }
+
+  /// Queue the response of a request arriving at `tick`, held for at least `delay` ticks
+  pub fn push(&mut self, channel_id: u64, id: u64, tick: u64, delay: u64, resp: T) {
+    let pending = Pending {
+      seq: self.seq,
+      ready_at: tick + delay.max(self.hold),
+      resp,
+    };
+    self.seq += 1;
+    let channel = self
+      .channels
+      .entry(channel_id)
+      .or_insert_with(|| Channel { queues: BTreeMap::new(), last_id: None });
+    channel.queues.entry(id).or_default().push_back(pending);
+  }
+
+  /// The next response of `channel_id` ready at `tick`, with its ID
+  pub fn pop(&mut self, channel_id: u64, tick: u64) -> Option<(u64, T)> {
+    let channel = self.channels.get_mut(&channel_id)?;
+    // heads of the ID queues, ordered by ID
+    let heads = channel.queues.iter().filter_map(|(&id, queue)| Some((id, queue.front()?)));
+    let id = match self.order {
+      AxiOrder::InOrder => {
+        let (id, head) = heads.min_by_key(|(_, head)| head.seq)?;
+        (head.ready_at <= tick).then_some(id)?
+      }
+      AxiOrder::RoundRobin => {
+        let ready: Vec<u64> =
+          heads.filter(|(_, head)| head.ready_at <= tick).map(|(id, _)| id).collect();
+        let after_last = ready.iter().find(|&&id| channel.last_id.is_some_and(|last| id > last));
+        *after_last.or(ready.first())?
+      }
+      AxiOrder::Youngest => {
+        let ready = heads.filter(|(_, head)| head.ready_at <= tick);
+        ready.max_by_key(|(_, head)| head.seq)?.0
+      }
+    };
+
+    channel.last_id = Some(id);
+    let queue = channel.queues.get_mut(&id)?;
+    let pending = queue.pop_front()?;
+    if queue.is_empty() {
+      channel.queues.remove(&id);
+    }
+    Some((id, pending.resp))
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  /// (channel, id, resp) popped at `tick` until nothing is ready
+  fn drain(sched: &mut AxiScheduler<u32>, channel_id: u64, tick: u64) -> Vec<(u64, u32)> {
+    std::iter::from_fn(|| sched.pop(channel_id, tick)).collect()
+  }
+
+  fn three_ids(order: AxiOrder) -> AxiScheduler<u32> {
+    let mut sched = AxiScheduler::new(order, 0);
+    sched.push(0, 2, 0, 0, 0);
+    sched.push(0, 1, 0, 0, 1);
+    sched.push(0, 2, 0, 0, 2);
+    sched.push(0, 3, 0, 0, 3);
+    sched
+  }
+
+  #[test]
+  fn in_order_follows_arrival() {
+    let mut sched = three_ids(AxiOrder::InOrder);
+    assert_eq!(drain(&mut sched, 0, 0), [(2, 0), (1, 1), (2, 2), (3, 3)]);
+  }
+
+  #[test]
+  fn in_order_waits_for_the_oldest() {
+    let mut sched = AxiScheduler::new(AxiOrder::InOrder, 0);
+    sched.push(0, 1, 0, 10, 0);
+    sched.push(0, 2, 0, 0, 1);
+    assert_eq!(sched.pop(0, 5), None);
+    assert_eq!(drain(&mut sched, 0, 10), [(1, 0), (2, 1)]);
+  }
+
+  #[test]
+  fn round_robin_rotates_over_ids() {
+    let mut sched = three_ids(AxiOrder::RoundRobin);
+    assert_eq!(drain(&mut sched, 0, 0), [(1, 1), (2, 0), (3, 3), (2, 2)]);
+  }
+
+  #[test]
+  fn youngest_goes_first_but_keeps_id_order() {
+    let mut sched = three_ids(AxiOrder::Youngest);
+    // the second response of ID 2 never overtakes the first one
+    assert_eq!(drain(&mut sched, 0, 0), [(3, 3), (1, 1), (2, 0), (2, 2)]);
+  }
+
+  #[test]
+  fn hold_and_delay_keep_responses_back() {
+    let mut sched = AxiScheduler::new(AxiOrder::Youngest, 4);
+    sched.push(0, 1, 0, 0, 0);
+    sched.push(0, 2, 1, 8, 1);
+    assert_eq!(sched.pop(0, 3), None);
+    assert_eq!(drain(&mut sched, 0, 4), [(1, 0)]);
+    assert_eq!(drain(&mut sched, 0, 9), [(2, 1)]);
+  }
+
+  #[test]
+  fn channels_are_independent() {
+    let mut sched = AxiScheduler::new(AxiOrder::InOrder, 0);
+    sched.push(0, 1, 0, 10, 0);
+    sched.push(1, 1, 0, 0, 1);
+    assert_eq!(sched.pop(0, 0), None);
+    assert_eq!(sched.pop(1, 0), Some((1, 1)));
+    assert_eq!(sched.pop(2, 100), None);
+  }
+}
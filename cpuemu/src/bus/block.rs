@@ -0,0 +1,330 @@
+use std::{fs, os::unix::fs::FileExt, path::Path};
+
+use anyhow::Context;
+use tracing::{debug, warn};
+
+use super::{regs::*, DmaMemory, MappedFile, ShadowDevice};
+
+pub(super) const SECTOR_SIZE: usize = 512;
+
+// 64-bit registers
+const CAPACITY: usize = 0x00;
+const SECTOR: usize = 0x08;
+const BUFFER: usize = 0x10;
+const COUNT: usize = 0x18;
+const CMD: usize = 0x20;
+const STATUS: usize = 0x28;
+const IRQ_ENABLE: usize = 0x30;
+
+const CMD_READ: u64 = 1;
+const CMD_WRITE: u64 = 2;
+const CMD_FLUSH: u64 = 3;
+
+const STATUS_BUSY: u64 = 1 << 0;
+const STATUS_DONE: u64 = 1 << 1;
+const STATUS_ERROR: u64 = 1 << 2;
+
+/// A disk image, either written through or behind a copy-on-write overlay
+pub(super) struct Disk {
+  backing: Backing,
+  sectors: u64,
+}
+
+enum Backing {
+  File(fs::File),
+  /// private mapping of the image, writes stay in memory
+  Overlay(MappedFile),
+}
+
+impl Disk {
+  pub fn open(path: &Path, cow: bool) -> anyhow::Result<Self> {
+    let file = fs::OpenOptions::new()
+      .read(true)
+      .write(!cow)
+      .open(path)
+      .with_context(|| format!("opening {}", path.display()))?;
+    let len = file.metadata()?.len() as usize;
+    let sectors = (len / SECTOR_SIZE) as u64;
+    if sectors == 0 {
+      anyhow::bail!("{} holds no whole sector", path.display());
+    }
+    let backing = if cow {
+      let overlay =
+        MappedFile::private(&file, len).with_context(|| format!("mapping {}", path.display()))?;
+      Backing::Overlay(overlay)
+    } else {
+      Backing::File(file)
+    };
+    Ok(Self { backing, sectors })
+  }
+
+  pub fn sectors(&self) -> u64 {
+    self.sectors
+  }
+
+  /// Byte offset of `sector`, if `len` bytes from there are on the disk
+  pub(super) fn range(&self, sector: u64, len: usize) -> anyhow::Result<usize> {
+    let end = sector.checked_mul(SECTOR_SIZE as u64).and_then(|s| s.checked_add(len as u64));
+    match end {
+      Some(end) if end <= self.sectors * SECTOR_SIZE as u64 => Ok(sector as usize * SECTOR_SIZE),
+      _ => anyhow::bail!(
+        "sector {sector} + {len:#x} bytes is past the {} sectors of the disk",
+        self.sectors
+      ),
+    }
+  }
+
+  pub fn read(&self, sector: u64, buf: &mut [u8]) -> anyhow::Result<()> {
+    let offset = self.range(sector, buf.len())?;
+    match &self.backing {
+      Backing::File(file) => file.read_exact_at(buf, offset as u64)?,
+      Backing::Overlay(mem) => buf.copy_from_slice(&mem.as_slice()[offset..offset + buf.len()]),
+    }
+    Ok(())
+  }
+
+  pub fn write(&mut self, sector: u64, data: &[u8]) -> anyhow::Result<()> {
+    let offset = self.range(sector, data.len())?;
+    match &mut self.backing {
+      Backing::File(file) => file.write_all_at(data, offset as u64)?,
+      Backing::Overlay(mem) => {
+        mem.as_mut_slice()[offset..offset + data.len()].copy_from_slice(data)
+      }
+    }
+    Ok(())
+  }
+
+  pub fn flush(&mut self) -> anyhow::Result<()> {
+    if let Backing::File(file) = &self.backing {
+      file.sync_data()?;
+    }
+    Ok(())
+  }
+}
+
+/// Block device moving whole sectors between the disk and memory by DMA.
+/// Program SECTOR, BUFFER (physical address) and COUNT (sectors), then write CMD;
+/// STATUS reports BUSY until the transfer is over, then DONE (and ERROR if it failed).
+/// Writing STATUS clears DONE/ERROR and the interrupt raised with IRQ_ENABLE set.
+pub(super) struct BlockDevice {
+  name: String,
+  disk: Disk,
+  sector: u64,
+  buffer: u64,
+  count: u64,
+  irq_enable: u64,
+  /// command waiting for its DMA, 0 = none
+  cmd: u64,
+  status: u64,
+}
+
+impl BlockDevice {
+  pub fn new(name: &str, disk: Disk) -> Self {
+    Self {
+      name: name.to_string(),
+      disk,
+      sector: 0,
+      buffer: 0,
+      count: 0,
+      irq_enable: 0,
+      cmd: 0,
+      status: 0,
+    }
+  }
+
+  fn execute(&mut self, mem: &mut dyn DmaMemory) -> anyhow::Result<()> {
+    let addr = self.buffer as usize;
+    let len = (self.count as usize)
+      .checked_mul(SECTOR_SIZE)
+      .ok_or_else(|| anyhow::anyhow!("{} sectors overflow", self.count))?;
+    if matches!(self.cmd, CMD_READ | CMD_WRITE) {
+      // before any buffer of `len` bytes is allocated
+      self.disk.range(self.sector, len)?;
+    }
+    match self.cmd {
+      CMD_READ => {
+        let mut data = vec![0u8; len];
+        self.disk.read(self.sector, &mut data)?;
+        mem.write(addr, &data)
+      }
+      CMD_WRITE => {
+        let data = mem.read(addr, len)?;
+        self.disk.write(self.sector, &data)
+      }
+      CMD_FLUSH => self.disk.flush(),
+      cmd => anyhow::bail!("unknown command {cmd}"),
+    }
+  }
+}
+
+impl ShadowDevice for BlockDevice {
+  fn read_mem(&mut self, addr: usize, size: usize) -> Vec<u8> {
+    reg_read(8, addr, size, |offset| match offset {
+      CAPACITY => self.disk.sectors(),
+      SECTOR => self.sector,
+      BUFFER => self.buffer,
+      COUNT => self.count,
+      STATUS => self.status,
+      IRQ_ENABLE => self.irq_enable,
+      _ => 0,
+    })
+  }
+
+  fn write_mem_chunk(&mut self, addr: usize, size: usize, strobe: Option<&[bool]>, data: &[u8]) {
+    for reg in reg_writes(8, addr, size, strobe, data) {
+      match reg.offset {
+        SECTOR => self.sector = reg.merge(self.sector),
+        BUFFER => self.buffer = reg.merge(self.buffer),
+        COUNT => self.count = reg.merge(self.count),
+        IRQ_ENABLE => self.irq_enable = reg.merge(self.irq_enable),
+        // a command while busy is dropped
+        CMD if self.status & STATUS_BUSY == 0 => {
+          self.cmd = reg.merge(0);
+          self.status = STATUS_BUSY;
+        }
+        STATUS => self.status &= STATUS_BUSY,
+        _ => {}
+      }
+    }
+  }
+
+  fn wants_dma(&self) -> bool {
+    self.status & STATUS_BUSY != 0
+  }
+
+  fn run_dma(&mut self, mem: &mut dyn DmaMemory) {
+    self.status = match self.execute(mem) {
+      Ok(()) => {
+        debug!(
+          "{}: command {} sector={} count={} buffer={:#x} done",
+          self.name, self.cmd, self.sector, self.count, self.buffer
+        );
+        STATUS_DONE
+      }
+      Err(e) => {
+        warn!("{}: command {} failed: {e:#}", self.name, self.cmd);
+        STATUS_DONE | STATUS_ERROR
+      }
+    };
+    self.cmd = 0;
+  }
+
+  fn irq_level(&self) -> bool {
+    self.irq_enable != 0 && self.status & STATUS_DONE != 0
+  }
+
+  fn flush(&mut self) {
+    if let Err(e) = self.disk.flush() {
+      warn!("{}: fail flushing disk: {e:#}", self.name);
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::super::{BusConfig, MemInit, ShadowBus, SparseMemDevice};
+  use super::*;
+
+  const BLOCK: usize = 0x1000_0000;
+  const RAM: usize = 0x8000_0000;
+
+  /// A bus with 64KiB of RAM and a block device over a COW disk of `sectors` sectors,
+  /// sector `i` filled with `i + 1`
+  fn bus_with_disk(name: &str, sectors: u8) -> (ShadowBus, usize) {
+    let path = std::env::temp_dir().join(format!("cpuemu-{name}-{}.img", std::process::id()));
+    let image: Vec<u8> = (1..=sectors).flat_map(|i| [i; SECTOR_SIZE]).collect();
+    fs::write(&path, image).unwrap();
+    let disk = Disk::open(&path, true).unwrap();
+    fs::remove_file(path).unwrap();
+
+    let config = BusConfig {
+      devices: Vec::new(),
+      on_unmapped: Default::default(),
+    };
+    let mut bus = ShadowBus::from_config(&config, Path::new(""), MemInit::Zero, false).unwrap();
+    bus.register_device("ram", RAM, 0x10000, Box::new(SparseMemDevice::new(0))).unwrap();
+    let block = BlockDevice::new("block", disk);
+    let id = bus.register_device("block", BLOCK, 0x1000, Box::new(block)).unwrap();
+    (bus, id)
+  }
+
+  fn set(bus: &mut ShadowBus, reg: usize, value: u64) {
+    let addr = (BLOCK + reg) as u32;
+    bus.write_mem_axi(addr, 8, 8, &[true; 8], &value.to_le_bytes()).unwrap();
+  }
+
+  fn get(bus: &mut ShadowBus, reg: usize) -> u64 {
+    let (_, data) = bus.read_mem_axi((BLOCK + reg) as u32, 8, 8).unwrap();
+    u64::from_le_bytes(data.try_into().unwrap())
+  }
+
+  fn command(bus: &mut ShadowBus, cmd: u64, sector: u64, buffer: usize, count: u64) -> u64 {
+    set(bus, SECTOR, sector);
+    set(bus, BUFFER, buffer as u64);
+    set(bus, COUNT, count);
+    set(bus, CMD, cmd);
+    assert_eq!(get(bus, STATUS), STATUS_BUSY);
+    bus.tick(1);
+    get(bus, STATUS)
+  }
+
+  fn ram(bus: &mut ShadowBus, addr: usize, len: usize) -> Vec<u8> {
+    bus.read(addr, len).unwrap()
+  }
+
+  #[test]
+  fn read_copies_sectors_into_memory() {
+    let (mut bus, _) = bus_with_disk("block-read", 4);
+    assert_eq!(get(&mut bus, CAPACITY), 4);
+    assert_eq!(command(&mut bus, CMD_READ, 1, RAM + 0x200, 2), STATUS_DONE);
+    assert_eq!(ram(&mut bus, RAM, 0x200), vec![0; 0x200]);
+    assert_eq!(ram(&mut bus, RAM + 0x200, 0x200), vec![2; 0x200]);
+    assert_eq!(ram(&mut bus, RAM + 0x400, 0x200), vec![3; 0x200]);
+    assert_eq!(ram(&mut bus, RAM + 0x600, 0x200), vec![0; 0x200]);
+  }
+
+  #[test]
+  fn write_copies_memory_into_sectors() {
+    let (mut bus, _) = bus_with_disk("block-write", 4);
+    bus.load_mem_seg(RAM, &[0xaa; SECTOR_SIZE]).unwrap();
+    assert_eq!(command(&mut bus, CMD_WRITE, 3, RAM, 1), STATUS_DONE);
+    assert_eq!(command(&mut bus, CMD_READ, 2, RAM + 0x1000, 2), STATUS_DONE);
+    assert_eq!(ram(&mut bus, RAM + 0x1000, 0x200), vec![3; 0x200]);
+    assert_eq!(ram(&mut bus, RAM + 0x1200, 0x200), vec![0xaa; 0x200]);
+  }
+
+  #[test]
+  fn out_of_range_transfers_fail() {
+    let (mut bus, _) = bus_with_disk("block-range", 4);
+    let error = STATUS_DONE | STATUS_ERROR;
+    // past the last sector, nothing reaches memory
+    assert_eq!(command(&mut bus, CMD_READ, 3, RAM, 2), error);
+    assert_eq!(ram(&mut bus, RAM, 0x400), vec![0; 0x400]);
+    assert_eq!(command(&mut bus, CMD_READ, u64::MAX, RAM, 1), error);
+    // a buffer outside of the RAM
+    assert_eq!(command(&mut bus, CMD_READ, 0, RAM + 0xff00, 1), error);
+    assert_eq!(command(&mut bus, CMD_WRITE, 0, 0x2000_0000, 1), error);
+    assert_eq!(command(&mut bus, 7, 0, RAM, 1), error);
+    // writing STATUS clears it
+    set(&mut bus, STATUS, 0);
+    assert_eq!(get(&mut bus, STATUS), 0);
+  }
+
+  #[test]
+  fn completion_raises_the_irq_until_acknowledged() {
+    let (mut bus, id) = bus_with_disk("block-irq", 1);
+    assert_eq!(command(&mut bus, CMD_READ, 0, RAM, 1), STATUS_DONE);
+    // masked
+    assert!(!bus.devices[id].device.irq_level());
+    set(&mut bus, IRQ_ENABLE, 1);
+    assert!(bus.devices[id].device.irq_level());
+    set(&mut bus, STATUS, 0);
+    assert!(!bus.devices[id].device.irq_level());
+    // not while the transfer is in flight
+    set(&mut bus, CMD, CMD_FLUSH);
+    assert!(!bus.devices[id].device.irq_level());
+    bus.tick(2);
+    assert_eq!(get(&mut bus, STATUS), STATUS_DONE);
+    assert!(bus.devices[id].device.irq_level());
+  }
+}
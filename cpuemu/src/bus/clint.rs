@@ -0,0 +1,118 @@
+use super::{regs::*, ShadowDevice, MIP_MSIP, MIP_MTIP};
+
+// same layout as cpu/src/CPU/device/clint.scala
+const MSIP: usize = 0x0;
+const MTIMECMP: usize = 0x4000;
+const FREQ: usize = 0x8000;
+const INC: usize = 0x8008;
+const MTIME: usize = 0xbff8;
+
+/// Core-local interruptor, `mtime` advances by `inc` every `freq` clock cycles
+pub(super) struct Clint {
+  msip: u64,
+  mtimecmp: u64,
+  mtime: u64,
+  freq: u64,
+  inc: u64,
+
+  last_cycle: u64,
+  /// cycles since `mtime` last advanced
+  cnt: u64,
+}
+
+impl Clint {
+  pub fn new(freq: u64, inc: u64) -> Self {
+    Self {
+      msip: 0,
+      // no timer interrupt until software programs a deadline
+      mtimecmp: u64::MAX,
+      mtime: 0,
+      freq,
+      inc,
+      last_cycle: 0,
+      cnt: 0,
+    }
+  }
+}
+
+impl ShadowDevice for Clint {
+  fn read_mem(&mut self, addr: usize, size: usize) -> Vec<u8> {
+    reg_read(8, addr, size, |offset| match offset {
+      MSIP => self.msip,
+      MTIMECMP => self.mtimecmp,
+      FREQ => self.freq,
+      INC => self.inc,
+      MTIME => self.mtime,
+      _ => 0,
+    })
+  }
+
+  fn write_mem_chunk(&mut self, addr: usize, size: usize, strobe: Option<&[bool]>, data: &[u8]) {
+    for reg in reg_writes(8, addr, size, strobe, data) {
+      match reg.offset {
+        MSIP => self.msip = reg.merge(self.msip),
+        MTIMECMP => self.mtimecmp = reg.merge(self.mtimecmp),
+        // only the low 16 bits are implemented in the RTL
+        FREQ => self.freq = reg.merge(self.freq) & 0xffff,
+        INC => self.inc = reg.merge(self.inc) & 0xffff,
+        MTIME => self.mtime = reg.merge(self.mtime),
+        _ => {}
+      }
+    }
+  }
+
+  fn tick(&mut self, tick: u64) {
+    // a tick is half a clock cycle
+    let cycle = tick / 2;
+    self.cnt += cycle.saturating_sub(self.last_cycle);
+    self.last_cycle = cycle;
+    if self.freq != 0 && self.cnt >= self.freq {
+      self.mtime = self.mtime.wrapping_add(self.cnt / self.freq * self.inc);
+      self.cnt %= self.freq;
+    }
+  }
+
+  fn interrupts(&self) -> u64 {
+    let mut mip = 0;
+    if self.msip != 0 {
+      mip |= MIP_MSIP;
+    }
+    if self.mtime >= self.mtimecmp {
+      mip |= MIP_MTIP;
+    }
+    mip
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn read(clint: &mut Clint, offset: usize) -> u64 {
+    u64::from_le_bytes(clint.read_mem(offset, 8).try_into().unwrap())
+  }
+
+  #[test]
+  fn timer_is_quiet_after_reset() {
+    let mut clint = Clint::new(40, 1);
+    assert_eq!(read(&mut clint, MTIMECMP), u64::MAX);
+    assert_eq!(clint.interrupts(), 0);
+    clint.write_mem_chunk(MSIP, 8, None, &1u64.to_le_bytes());
+    assert_eq!(clint.interrupts(), MIP_MSIP);
+  }
+
+  #[test]
+  fn mtime_counts_clock_cycles() {
+    let mut clint = Clint::new(4, 3);
+    clint.write_mem_chunk(MTIMECMP, 8, None, &6u64.to_le_bytes());
+    // 7 cycles are 14 ticks
+    clint.tick(14);
+    assert_eq!(read(&mut clint, MTIME), 3);
+    assert_eq!(clint.interrupts(), 0);
+    clint.tick(15);
+    assert_eq!(read(&mut clint, MTIME), 3);
+    clint.tick(16);
+    assert_eq!(read(&mut clint, MTIME), 6);
+    assert_eq!(clint.interrupts(), MIP_MTIP);
+  }
+}
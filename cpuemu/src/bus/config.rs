@@ -0,0 +1,447 @@
+use std::{fs, path::Path};
+
+use anyhow::Context;
+use serde::{Deserialize, Deserializer};
+
+use super::{ConsoleInput, FrameFormat, KeyClock, PixelFormat, RtcClock};
+
+/// Bus layout, loaded from `+bus-config=<file>` (`.toml` or `.json`).
+///
+/// ```toml
+/// [[devices]]
+/// name = "uart"
+/// kind = "uart-lite"
+/// base = 0x40600000
+/// size = 0x10
+/// input = "pty"
+///
+/// [[devices]]
+/// name = "serial"
+/// kind = "uart16550"
+/// base = 0x10000000
+/// size = 0x100
+/// input = "stdin"
+/// stride = 4
+///
+/// [[devices]]
+/// name = "ram"
+/// kind = "mem"
+/// base = 0x80000000
+/// size = 0x08000000
+///
+/// [[devices]]
+/// name = "ddr"
+/// kind = "sparse-mem"
+/// base = 0xc0000000
+/// size = 0x40000000
+/// fill = 0
+/// [devices.timing]
+/// latency = 4
+/// bandwidth = 8
+/// dram = { row_size = 2048, banks = 8, row_hit = 10, row_miss = 30 }
+///
+/// [[devices]]
+/// name = "flash"
+/// kind = "image"
+/// base = 0x30000000
+/// size = 0x08000000
+/// path = "/tmp/images/{elf}.img"
+///
+/// [[devices]]
+/// name = "clint"
+/// kind = "clint"
+/// base = 0x38000000
+/// size = 0x10000
+/// freq = 40
+///
+/// [[devices]]
+/// name = "plic"
+/// kind = "plic"
+/// base = 0x3c000000
+/// size = 0x4000000
+/// sources = 31
+/// contexts = 2
+///
+/// [[devices]]
+/// name = "vga"
+/// kind = "vga"
+/// base = 0xa0000100
+/// size = 0x8
+/// fb = 0xa1000000
+/// width = 400
+/// height = 300
+/// format = "xrgb8888"
+/// dump = "png"
+/// dump_dir = "frames"
+/// keep = 16
+///
+/// [[devices]]
+/// name = "keyboard"
+/// kind = "keyboard"
+/// base = 0xa0000060
+/// size = 0x8
+/// script = "keys.txt"
+/// clock = "tick"
+///
+/// [[devices]]
+/// name = "rtc"
+/// kind = "rtc"
+/// base = 0xa0000048
+/// size = 0x8
+/// clock = "sim"
+/// tick_ns = 10
+///
+/// [[devices]]
+/// name = "disk"
+/// kind = "block"
+/// base = 0x10001000
+/// size = 0x1000
+/// image = "disk.img"
+/// cow = true
+///
+/// [[devices]]
+/// name = "virtio-console"
+/// kind = "virtio-console"
+/// base = 0x10008000
+/// size = 0x1000
+/// input = "pty"
+/// irq = 1
+///
+/// [[devices]]
+/// name = "virtio-blk"
+/// kind = "virtio-blk"
+/// base = 0x10009000
+/// size = 0x1000
+/// image = "rootfs.img"
+/// cow = true
+/// irq = 2
+/// ```
+///
+/// Any device may add a `timing` table to delay its AXI responses (zero latency otherwise),
+/// `irq = <source>` to wire its interrupt output to the PLIC,
+/// and `on_error = "error"` to answer its misaligned accesses with SLVERR instead of
+/// aborting the simulation. A top-level `on_unmapped = "error"` does the same with
+/// DECERR for accesses that hit no device.
+#[derive(Debug, Deserialize)]
+pub(crate) struct BusConfig {
+  pub devices: Vec<DeviceConfig>,
+  #[serde(default)]
+  pub on_unmapped: ErrorPolicy,
+}
+
+/// What the bus does with an access it cannot serve
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum ErrorPolicy {
+  /// log it and end the simulation with BadTrap
+  #[default]
+  Abort,
+  /// answer with an AXI error response, so the DUT can take an access fault
+  Error,
+}
+
+#[derive(Debug, Deserialize)]
+pub(crate) struct DeviceConfig {
+  pub name: String,
+  #[serde(deserialize_with = "deserialize_addr")]
+  pub base: usize,
+  #[serde(deserialize_with = "deserialize_addr")]
+  pub size: usize,
+  /// PLIC source the interrupt output of this device is wired to
+  #[serde(default)]
+  pub irq: Option<u32>,
+  /// how misaligned accesses to this device are answered
+  #[serde(default)]
+  pub on_error: ErrorPolicy,
+  /// how long its AXI responses take, None = answered right away
+  #[serde(default)]
+  pub timing: Option<TimingConfig>,
+  /// `kind` selects the device model, its options are the remaining keys of the entry
+  #[serde(flatten)]
+  pub kind: DeviceKind,
+}
+
+/// Latency and bandwidth of a region, in simulation ticks
+#[derive(Debug, Clone, Deserialize)]
+pub(crate) struct TimingConfig {
+  /// ticks from request to response, on top of the DRAM row latency if any
+  #[serde(default)]
+  pub latency: u64,
+  /// bytes the region moves per tick, None = unlimited
+  #[serde(default)]
+  pub bandwidth: Option<u64>,
+  #[serde(default)]
+  pub dram: Option<DramConfig>,
+}
+
+/// Row-buffer model: one open row per bank, banks interleaved row by row
+#[derive(Debug, Clone, Deserialize)]
+pub(crate) struct DramConfig {
+  #[serde(
+    default = "default_dram_row_size",
+    deserialize_with = "deserialize_addr"
+  )]
+  pub row_size: usize,
+  #[serde(default = "default_dram_banks")]
+  pub banks: usize,
+  /// ticks when the row is already open
+  pub row_hit: u64,
+  /// ticks to close the open row and open another one
+  pub row_miss: u64,
+}
+
+fn default_dram_row_size() -> usize {
+  2048
+}
+
+fn default_dram_banks() -> usize {
+  8
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "kind", rename_all = "kebab-case")]
+pub(crate) enum DeviceKind {
+  /// Xilinx UART-Lite (rxfifo/txfifo/stat/ctrl)
+  UartLite {
+    /// rx source: "none", "stdin" or "pty"
+    #[serde(default)]
+    input: ConsoleInput,
+  },
+  /// 16550-compatible UART (rbr/thr/ier/iir/fcr/lcr/mcr/lsr/msr/scr)
+  Uart16550 {
+    /// rx source: "none", "stdin" or "pty"
+    #[serde(default)]
+    input: ConsoleInput,
+    /// byte distance between two registers, 1 or 4
+    #[serde(default = "default_uart16550_stride")]
+    stride: usize,
+  },
+  /// Plain RAM, allocated up front and filled as given by `+mem-init=`
+  Mem,
+  /// RAM allocated page by page on first write, for large DRAM regions
+  SparseMem {
+    /// value read from untouched memory
+    #[serde(default)]
+    fill: u8,
+  },
+  /// RAM mapped copy-on-write from a raw image prebuilt from the ELF.
+  /// `{elf}` in the path is replaced by the ELF file name, the image is
+  /// (re)built unless it was made from the same ELF file for the same region.
+  /// `+mem-image-build=1` only builds the images and exits.
+  Image { path: String },
+  /// Core-local interruptor (msip/mtimecmp/mtime), as cpu/src/CPU/device/clint.scala
+  Clint {
+    /// clock cycles per `mtime` step
+    #[serde(default = "default_clint_freq")]
+    freq: u64,
+    /// `mtime` increment per step
+    #[serde(default = "default_clint_inc")]
+    inc: u64,
+  },
+  /// Platform-level interrupt controller, context 0/1 drive MEIP/SEIP.
+  Plic {
+    /// number of interrupt sources, not counting the reserved source 0
+    #[serde(default = "default_plic_sources")]
+    sources: usize,
+    #[serde(default = "default_plic_contexts")]
+    contexts: usize,
+  },
+  /// Block device moving sectors of a disk image to and from memory by DMA
+  Block {
+    image: String,
+    /// keep the writes in memory, the image stays untouched
+    #[serde(default)]
+    cow: bool,
+  },
+  /// virtio-mmio console with a single port, for `virtio_console`/`hvc0`
+  VirtioConsole {
+    /// rx source: "none", "stdin" or "pty"
+    #[serde(default)]
+    input: ConsoleInput,
+  },
+  /// virtio-mmio block device over a disk image, for `virtio_blk`
+  VirtioBlk {
+    image: String,
+    /// keep the writes in memory, the image stays untouched
+    #[serde(default)]
+    cow: bool,
+  },
+  /// nexus-am timer: uptime in microseconds, then the UTC date if the region holds 0x20 bytes
+  Rtc {
+    /// "sim" for simulation ticks, "host" for the host wall clock
+    #[serde(default)]
+    clock: RtcClock,
+    /// nanoseconds a simulation tick lasts, in "sim" mode
+    #[serde(default = "default_rtc_tick_ns")]
+    tick_ns: u64,
+  },
+  /// nexus-am keyboard replaying `script`, whose lines are `<time> down|up <key>`
+  Keyboard {
+    script: String,
+    /// what the script times count: "tick" or "instret"
+    #[serde(default)]
+    clock: KeyClock,
+  },
+  /// VGA control registers (screen size, sync) at `base`, its framebuffer mapped at `fb`.
+  /// Every sync saves the framebuffer as `<dump_dir>/<name>-<frame>.<dump>`.
+  Vga {
+    #[serde(deserialize_with = "deserialize_addr")]
+    fb: usize,
+    #[serde(default = "default_vga_width")]
+    width: usize,
+    #[serde(default = "default_vga_height")]
+    height: usize,
+    /// "xrgb8888" or "rgb565"
+    #[serde(default)]
+    format: PixelFormat,
+    /// "ppm" or "png"
+    #[serde(default)]
+    dump: FrameFormat,
+    #[serde(default = "default_vga_dump_dir")]
+    dump_dir: String,
+    /// keep only the last `keep` frames on disk, 0 = keep all
+    #[serde(default)]
+    keep: usize,
+  },
+}
+
+fn default_uart16550_stride() -> usize {
+  1
+}
+
+fn default_clint_freq() -> u64 {
+  40
+}
+
+fn default_clint_inc() -> u64 {
+  1
+}
+
+fn default_plic_sources() -> usize {
+  31
+}
+
+fn default_plic_contexts() -> usize {
+  2
+}
+
+fn default_rtc_tick_ns() -> u64 {
+  10
+}
+
+fn default_vga_width() -> usize {
+  400
+}
+
+fn default_vga_height() -> usize {
+  300
+}
+
+fn default_vga_dump_dir() -> String {
+  "frames".to_string()
+}
+
+impl DeviceKind {
+  pub fn name(&self) -> &'static str {
+    match self {
+      DeviceKind::UartLite { .. } => "uart-lite",
+      DeviceKind::Uart16550 { .. } => "uart16550",
+      DeviceKind::Mem => "mem",
+      DeviceKind::SparseMem { .. } => "sparse-mem",
+      DeviceKind::Image { .. } => "image",
+      DeviceKind::Clint { .. } => "clint",
+      DeviceKind::Plic { .. } => "plic",
+      DeviceKind::Block { .. } => "block",
+      DeviceKind::VirtioConsole { .. } => "virtio-console",
+      DeviceKind::VirtioBlk { .. } => "virtio-blk",
+      DeviceKind::Rtc { .. } => "rtc",
+      DeviceKind::Keyboard { .. } => "keyboard",
+      DeviceKind::Vga { .. } => "vga",
+    }
+  }
+}
+
+impl Default for BusConfig {
+  /// The devices on the bus as specified in `nexus-am`
+  fn default() -> Self {
+    Self {
+      devices: vec![
+        DeviceConfig {
+          name: "uart".to_string(),
+          base: 0x40600000,
+          size: 0x10,
+          irq: None,
+          on_error: ErrorPolicy::Abort,
+          timing: None,
+          kind: DeviceKind::UartLite { input: ConsoleInput::None },
+        },
+        DeviceConfig {
+          name: "clint".to_string(),
+          base: 0x38000000,
+          size: 0x10000,
+          irq: None,
+          on_error: ErrorPolicy::Abort,
+          timing: None,
+          kind: DeviceKind::Clint {
+            freq: default_clint_freq(),
+            inc: default_clint_inc(),
+          },
+        },
+        DeviceConfig {
+          name: "ram".to_string(),
+          base: 0x80000000,
+          size: 0x08000000,
+          irq: None,
+          on_error: ErrorPolicy::Abort,
+          timing: None,
+          kind: DeviceKind::Mem,
+        },
+      ],
+      on_unmapped: ErrorPolicy::Abort,
+    }
+  }
+}
+
+impl BusConfig {
+  pub fn load(path: &Path) -> anyhow::Result<Self> {
+    let text =
+      fs::read_to_string(path).with_context(|| format!("reading bus config {}", path.display()))?;
+    match path.extension().and_then(|ext| ext.to_str()) {
+      Some("json") => serde_json::from_str(&text)
+        .with_context(|| format!("parsing bus config {}", path.display())),
+      Some("toml") => {
+        toml::from_str(&text).with_context(|| format!("parsing bus config {}", path.display()))
+      }
+      _ => anyhow::bail!(
+        "bus config {} should be a .toml or .json file",
+        path.display()
+      ),
+    }
+  }
+}
+
+/// Accept both integers and strings like "0x8000_0000", JSON has no hex literals
+fn deserialize_addr<'de, D: Deserializer<'de>>(deserializer: D) -> Result<usize, D::Error> {
+  #[derive(Deserialize)]
+  #[serde(untagged)]
+  enum Addr {
+    Int(u64),
+    Str(String),
+  }
+
+  let value = match Addr::deserialize(deserializer)? {
+    Addr::Int(v) => v,
+    Addr::Str(s) => parse_int(&s).map_err(serde::de::Error::custom)?,
+  };
+  usize::try_from(value).map_err(serde::de::Error::custom)
+}
+
+pub(crate) fn parse_int(s: &str) -> anyhow::Result<u64> {
+  let s = s.trim().replace('_', "");
+  let parsed = if let Some(hex) = s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+    u64::from_str_radix(hex, 16)
+  } else {
+    s.parse()
+  };
+  parsed.with_context(|| format!("invalid integer `{s}`"))
+}
@@ -0,0 +1,263 @@
+use std::{
+  collections::VecDeque,
+  ffi::CStr,
+  io::{self, Write},
+  os::fd::{AsRawFd, FromRawFd, OwnedFd},
+};
+
+use anyhow::Context;
+use serde::Deserialize;
+use tracing::info;
+
+/// ticks between two polls of the host input when the program is not reading
+pub(super) const RX_POLL_INTERVAL: u64 = 1024;
+
+/// Where a serial device takes its input from
+#[derive(Debug, Default, Clone, Copy, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum ConsoleInput {
+  /// no input, the rx side stays empty
+  #[default]
+  None,
+  /// the simulator's stdin
+  Stdin,
+  /// a fresh pseudo terminal, its path is printed at startup
+  Pty,
+}
+
+/// Host side of a serial device: non-blocking input, output to stdout or the PTY
+pub(super) struct HostConsole {
+  input: Option<OwnedFd>,
+  /// Some(master) in PTY mode, output goes there instead of stdout
+  pty: Option<OwnedFd>,
+  /// keeps the PTY open while no terminal is attached, so the master does not see a hangup
+  _pty_slave: Option<OwnedFd>,
+  /// tick of the last `poll_on_tick` that polled
+  last_poll: u64,
+}
+
+impl HostConsole {
+  pub fn new(name: &str, input: ConsoleInput) -> anyhow::Result<Self> {
+    match input {
+      ConsoleInput::None => Ok(Self {
+        input: None,
+        pty: None,
+        _pty_slave: None,
+        last_poll: 0,
+      }),
+      ConsoleInput::Stdin => {
+        let stdin = unsafe { libc::dup(libc::STDIN_FILENO) };
+        if stdin < 0 {
+          return Err(io::Error::last_os_error()).context("duplicating stdin");
+        }
+        let stdin = unsafe { OwnedFd::from_raw_fd(stdin) };
+        Ok(Self {
+          input: Some(stdin),
+          pty: None,
+          _pty_slave: None,
+          last_poll: 0,
+        })
+      }
+      ConsoleInput::Pty => {
+        let (master, slave, path) = open_pty().context("creating PTY")?;
+        info!("{name}: console attached to {path}");
+        let input = master.try_clone()?;
+        Ok(Self {
+          input: Some(input),
+          pty: Some(master),
+          _pty_slave: Some(slave),
+          last_poll: 0,
+        })
+      }
+    }
+  }
+
+  /// Read one byte if the host has one ready, never blocks
+  pub fn read_byte(&mut self) -> Option<u8> {
+    let fd = self.input.as_ref()?.as_raw_fd();
+    let mut pfd = libc::pollfd { fd, events: libc::POLLIN, revents: 0 };
+    // a closed pipe only reports POLLHUP, read it to see the EOF
+    let ready = libc::POLLIN | libc::POLLHUP;
+    if unsafe { libc::poll(&mut pfd, 1, 0) } <= 0 || pfd.revents & ready == 0 {
+      return None;
+    }
+    let mut byte = 0u8;
+    match unsafe { libc::read(fd, &mut byte as *mut u8 as *mut libc::c_void, 1) } {
+      1 => Some(byte),
+      // EOF: stop polling a closed input
+      0 => {
+        self.input = None;
+        None
+      }
+      _ => None,
+    }
+  }
+
+  /// Top `fifo` up to `depth` bytes from the host, returns true if it went from empty to non-empty
+  pub fn poll_into(&mut self, fifo: &mut VecDeque<u8>, depth: usize) -> bool {
+    let was_empty = fifo.is_empty();
+    while fifo.len() < depth {
+      match self.read_byte() {
+        Some(byte) => fifo.push_back(byte),
+        None => break,
+      }
+    }
+    was_empty && !fifo.is_empty()
+  }
+
+  /// `poll_into` from a device tick, at most once every `RX_POLL_INTERVAL` ticks
+  pub fn poll_on_tick(&mut self, tick: u64, fifo: &mut VecDeque<u8>, depth: usize) -> bool {
+    if tick < self.last_poll + RX_POLL_INTERVAL {
+      return false;
+    }
+    self.last_poll = tick;
+    self.poll_into(fifo, depth)
+  }
+
+  pub fn write_byte(&mut self, byte: u8) {
+    match &self.pty {
+      Some(master) => {
+        unsafe {
+          libc::write(
+            master.as_raw_fd(),
+            &byte as *const u8 as *const libc::c_void,
+            1,
+          )
+        };
+      }
+      None => {
+        print!("{}", byte as char);
+        io::stdout().flush().ok();
+      }
+    }
+  }
+}
+
+/// Queue a byte the program wrote into a device FIFO of `depth` bytes.
+/// A write to a full FIFO is lost, as on the real hardware.
+pub(super) fn push_lossy(fifo: &mut VecDeque<u8>, byte: u8, depth: usize) {
+  if fifo.len() < depth {
+    fifo.push_back(byte);
+  }
+}
+
+/// Open a raw-mode PTY pair, returns (master, slave, slave path)
+fn open_pty() -> anyhow::Result<(OwnedFd, OwnedFd, String)> {
+  unsafe {
+    let master = libc::posix_openpt(libc::O_RDWR | libc::O_NOCTTY);
+    if master < 0 {
+      return Err(io::Error::last_os_error().into());
+    }
+    let master = OwnedFd::from_raw_fd(master);
+    // drop output instead of stalling the simulation when nobody drains the PTY
+    let flags = libc::fcntl(master.as_raw_fd(), libc::F_GETFL);
+    libc::fcntl(master.as_raw_fd(), libc::F_SETFL, flags | libc::O_NONBLOCK);
+    if libc::grantpt(master.as_raw_fd()) != 0 || libc::unlockpt(master.as_raw_fd()) != 0 {
+      return Err(io::Error::last_os_error().into());
+    }
+    let name = libc::ptsname(master.as_raw_fd());
+    if name.is_null() {
+      return Err(io::Error::last_os_error().into());
+    }
+    let path = CStr::from_ptr(name).to_string_lossy().into_owned();
+
+    let slave = libc::open(name, libc::O_RDWR | libc::O_NOCTTY);
+    if slave < 0 {
+      return Err(io::Error::last_os_error().into());
+    }
+    let slave = OwnedFd::from_raw_fd(slave);
+    let mut termios = std::mem::zeroed::<libc::termios>();
+    if libc::tcgetattr(slave.as_raw_fd(), &mut termios) == 0 {
+      libc::cfmakeraw(&mut termios);
+      libc::tcsetattr(slave.as_raw_fd(), libc::TCSANOW, &termios);
+    }
+    Ok((master, slave, path))
+  }
+}
+
+#[cfg(test)]
+impl HostConsole {
+  /// Console reading from a pipe, returns it with the write end of the pipe
+  pub fn piped() -> (Self, std::fs::File) {
+    let mut fds = [0; 2];
+    assert_eq!(unsafe { libc::pipe(fds.as_mut_ptr()) }, 0);
+    let (rx, tx) = unsafe { (OwnedFd::from_raw_fd(fds[0]), OwnedFd::from_raw_fd(fds[1])) };
+    (
+      Self {
+        input: Some(rx),
+        pty: None,
+        _pty_slave: None,
+        last_poll: 0,
+      },
+      tx.into(),
+    )
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn reads_what_the_host_sends() {
+    let (mut console, mut host) = HostConsole::piped();
+    assert_eq!(console.read_byte(), None);
+    host.write_all(b"hi").unwrap();
+    assert_eq!(console.read_byte(), Some(b'h'));
+    assert_eq!(console.read_byte(), Some(b'i'));
+    assert_eq!(console.read_byte(), None);
+  }
+
+  #[test]
+  fn stops_polling_at_eof() {
+    let (mut console, mut host) = HostConsole::piped();
+    host.write_all(b"x").unwrap();
+    drop(host);
+    assert_eq!(console.read_byte(), Some(b'x'));
+    assert_eq!(console.read_byte(), None);
+    assert!(console.input.is_none());
+  }
+
+  #[test]
+  fn poll_into_tops_the_fifo_up() {
+    let (mut console, mut host) = HostConsole::piped();
+    let mut fifo = VecDeque::new();
+    assert!(!console.poll_into(&mut fifo, 4));
+    host.write_all(b"abcdef").unwrap();
+    assert!(console.poll_into(&mut fifo, 4));
+    assert_eq!(fifo, b"abcd");
+    // the FIFO was not empty, so nothing new arrived
+    fifo.pop_front();
+    assert!(!console.poll_into(&mut fifo, 4));
+    assert_eq!(fifo, b"bcde");
+  }
+
+  #[test]
+  fn poll_on_tick_waits_for_the_interval() {
+    let (mut console, mut host) = HostConsole::piped();
+    let mut fifo = VecDeque::new();
+    host.write_all(b"ab").unwrap();
+    assert!(!console.poll_on_tick(RX_POLL_INTERVAL - 1, &mut fifo, 1));
+    assert!(console.poll_on_tick(RX_POLL_INTERVAL, &mut fifo, 1));
+    assert_eq!(fifo, b"a");
+    fifo.clear();
+    assert!(!console.poll_on_tick(2 * RX_POLL_INTERVAL - 1, &mut fifo, 1));
+    assert!(console.poll_on_tick(2 * RX_POLL_INTERVAL, &mut fifo, 1));
+    assert_eq!(fifo, b"b");
+  }
+
+  #[test]
+  fn full_fifos_drop_writes() {
+    let mut fifo = VecDeque::new();
+    for byte in b"abc" {
+      push_lossy(&mut fifo, *byte, 2);
+    }
+    assert_eq!(fifo, b"ab");
+  }
+
+  #[test]
+  fn no_input() {
+    let mut console = HostConsole::new("test", ConsoleInput::None).unwrap();
+    assert_eq!(console.read_byte(), None);
+  }
+}
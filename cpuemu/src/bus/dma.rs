@@ -0,0 +1,76 @@
+use super::{ShadowBus, ShadowBusDevice, ShadowDevice};
+
+/// Memory as seen by a device doing DMA, addresses are physical
+pub trait DmaMemory {
+  fn read(&mut self, addr: usize, len: usize) -> anyhow::Result<Vec<u8>>;
+  fn write(&mut self, addr: usize, data: &[u8]) -> anyhow::Result<()>;
+}
+
+/// Stands in for a device while it runs its DMA, so it cannot reach itself through the bus
+struct Detached;
+
+impl ShadowDevice for Detached {
+  fn read_mem(&mut self, _addr: usize, size: usize) -> Vec<u8> {
+    vec![0; size]
+  }
+
+  fn write_mem_chunk(
+    &mut self,
+    _addr: usize,
+    _size: usize,
+    _strobe: Option<&[bool]>,
+    _data: &[u8],
+  ) {
+  }
+}
+
+impl DmaMemory for ShadowBus {
+  fn read(&mut self, addr: usize, len: usize) -> anyhow::Result<Vec<u8>> {
+    let end = addr
+      .checked_add(len)
+      .ok_or_else(|| anyhow::anyhow!("DMA read of {len:#x} bytes at {addr:#x} wraps around"))?;
+    let id = self
+      .decode(addr, end)
+      .ok_or_else(|| anyhow::anyhow!("DMA read [{addr:#x}, {end:#x}) leads to nowhere"))?;
+    let ShadowBusDevice { base, device, .. } = &mut self.devices[id];
+    Ok(device.read_mem(addr - *base, len))
+  }
+
+  fn write(&mut self, addr: usize, data: &[u8]) -> anyhow::Result<()> {
+    let end = addr.checked_add(data.len()).ok_or_else(|| {
+      anyhow::anyhow!(
+        "DMA write of {:#x} bytes at {addr:#x} wraps around",
+        data.len()
+      )
+    })?;
+    let id = self
+      .decode(addr, end)
+      .ok_or_else(|| anyhow::anyhow!("DMA write [{addr:#x}, {end:#x}) leads to nowhere"))?;
+    let ShadowBusDevice { base, device, .. } = &mut self.devices[id];
+    device.write_mem_chunk(addr - *base, data.len(), None, data);
+    self.exclusive.clear(addr, end);
+    #[cfg(feature = "difftest")]
+    self.dma_writes.push((addr, data.to_vec()));
+    Ok(())
+  }
+}
+
+impl ShadowBus {
+  /// Let every device with pending DMA work access the rest of the bus
+  pub(super) fn run_dma(&mut self) {
+    for id in 0..self.devices.len() {
+      if !self.devices[id].device.wants_dma() {
+        continue;
+      }
+      let mut device = std::mem::replace(&mut self.devices[id].device, Box::new(Detached));
+      device.run_dma(self);
+      self.devices[id].device = device;
+    }
+  }
+
+  /// Memory written by DMA since the last call, for the reference model
+  #[cfg(feature = "difftest")]
+  pub fn take_dma_writes(&mut self) -> Vec<(usize, Vec<u8>)> {
+    std::mem::take(&mut self.dma_writes)
+  }
+}
@@ -0,0 +1,61 @@
+use std::collections::HashMap;
+
+/// Who holds a reservation: (channel id, AXI ID)
+pub(crate) type ExclusiveId = (u64, u64);
+
+/// AXI exclusive access monitor, one reservation per ID.
+/// An exclusive read reserves its bytes, an exclusive write only succeeds if its ID still
+/// holds a reservation of exactly the same bytes, and any write clears the reservations it touches.
+#[derive(Default)]
+pub(super) struct ExclusiveMonitor {
+  /// [start, end) reserved by each ID
+  reservations: HashMap<ExclusiveId, (usize, usize)>,
+}
+
+impl ExclusiveMonitor {
+  pub fn reserve(&mut self, id: ExclusiveId, start: usize, end: usize) {
+    self.reservations.insert(id, (start, end));
+  }
+
+  pub fn holds(&self, id: ExclusiveId, start: usize, end: usize) -> bool {
+    self.reservations.get(&id) == Some(&(start, end))
+  }
+
+  /// A write to [start, end) landed, drop every reservation overlapping it
+  pub fn clear(&mut self, start: usize, end: usize) {
+    self.reservations.retain(|_, &mut (s, e)| e <= start || end <= s);
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn holds_needs_the_same_id_and_bytes() {
+    let mut monitor = ExclusiveMonitor::default();
+    monitor.reserve((0, 1), 0x10, 0x18);
+    assert!(monitor.holds((0, 1), 0x10, 0x18));
+    assert!(!monitor.holds((0, 2), 0x10, 0x18));
+    assert!(!monitor.holds((0, 1), 0x10, 0x14));
+    // a new exclusive read moves the reservation
+    monitor.reserve((0, 1), 0x20, 0x28);
+    assert!(!monitor.holds((0, 1), 0x10, 0x18));
+    assert!(monitor.holds((0, 1), 0x20, 0x28));
+  }
+
+  #[test]
+  fn clear_drops_only_overlapping_reservations() {
+    let mut monitor = ExclusiveMonitor::default();
+    monitor.reserve((0, 1), 0x10, 0x18);
+    monitor.reserve((0, 2), 0x20, 0x28);
+    // touching the edges is not overlapping
+    monitor.clear(0x8, 0x10);
+    monitor.clear(0x18, 0x20);
+    assert!(monitor.holds((0, 1), 0x10, 0x18));
+    assert!(monitor.holds((0, 2), 0x20, 0x28));
+    monitor.clear(0x17, 0x18);
+    assert!(!monitor.holds((0, 1), 0x10, 0x18));
+    assert!(monitor.holds((0, 2), 0x20, 0x28));
+  }
+}
@@ -0,0 +1,344 @@
+use std::{
+  fs, io,
+  os::{fd::AsRawFd, unix::fs::FileExt},
+  path::{Path, PathBuf},
+  ptr, slice,
+  time::UNIX_EPOCH,
+};
+
+use anyhow::Context;
+use elf::{abi::PT_LOAD, endian::LittleEndian, ElfStream};
+use tracing::info;
+
+use super::{BusConfig, DeviceKind, ShadowDevice};
+
+/// A private, copy-on-write mapping of a file: writes never reach the file,
+/// and untouched pages stay shared in the page cache between processes.
+pub(super) struct MappedFile {
+  ptr: *mut u8,
+  len: usize,
+}
+
+// SAFETY: the mapping is owned by this struct, and only accessed through &self/&mut self
+unsafe impl Send for MappedFile {}
+unsafe impl Sync for MappedFile {}
+
+impl MappedFile {
+  /// Map the first `len` bytes of `file`, anything past the end of the file reads as zero
+  pub fn private(file: &fs::File, len: usize) -> anyhow::Result<Self> {
+    let file_len = (file.metadata()?.len() as usize).min(len);
+
+    let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as usize;
+    let map_len = len.next_multiple_of(page_size);
+    unsafe {
+      // reserve the whole region first, so the file mapping can never run past it
+      let anon = libc::mmap(
+        ptr::null_mut(),
+        map_len,
+        libc::PROT_READ | libc::PROT_WRITE,
+        libc::MAP_PRIVATE | libc::MAP_ANONYMOUS | libc::MAP_NORESERVE,
+        -1,
+        0,
+      );
+      if anon == libc::MAP_FAILED {
+        return Err(io::Error::last_os_error()).context("reserving memory for image");
+      }
+      if file_len != 0 {
+        let mapped = libc::mmap(
+          anon,
+          file_len,
+          libc::PROT_READ | libc::PROT_WRITE,
+          libc::MAP_PRIVATE | libc::MAP_FIXED,
+          file.as_raw_fd(),
+          0,
+        );
+        if mapped == libc::MAP_FAILED {
+          let err = io::Error::last_os_error();
+          libc::munmap(anon, map_len);
+          return Err(err).context("mapping image file");
+        }
+      }
+      Ok(Self { ptr: anon as *mut u8, len })
+    }
+  }
+
+  pub fn as_slice(&self) -> &[u8] {
+    unsafe { slice::from_raw_parts(self.ptr, self.len) }
+  }
+
+  pub fn as_mut_slice(&mut self) -> &mut [u8] {
+    unsafe { slice::from_raw_parts_mut(self.ptr, self.len) }
+  }
+}
+
+impl Drop for MappedFile {
+  fn drop(&mut self) {
+    let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as usize;
+    unsafe {
+      libc::munmap(
+        self.ptr as *mut libc::c_void,
+        self.len.next_multiple_of(page_size),
+      )
+    };
+  }
+}
+
+/// Where the image of a region goes, `{elf}` in `pattern` is replaced by the ELF file name
+pub(super) fn image_path(pattern: &str, elf_file: &Path) -> PathBuf {
+  let elf_name = elf_file.file_name().unwrap_or_default().to_string_lossy();
+  PathBuf::from(pattern.replace("{elf}", &elf_name))
+}
+
+/// What an image was built from, stored right after the region contents:
+/// the full ELF path, its length and mtime, and the region it was cut for
+fn image_key(elf_file: &Path, base: usize, size: usize) -> anyhow::Result<Vec<u8>> {
+  let path = fs::canonicalize(elf_file).with_context(|| "resolving ELF path")?;
+  let meta = fs::metadata(&path).with_context(|| "reading ELF file")?;
+  let mtime = meta.modified()?.duration_since(UNIX_EPOCH).unwrap_or_default().as_nanos();
+  let key = format!(
+    "cpuemu-image\nelf={}\nelf_len={}\nelf_mtime={mtime}\nbase={base:#x}\nsize={size:#x}\n",
+    path.display(),
+    meta.len()
+  );
+  Ok(key.into_bytes())
+}
+
+/// Whether `file` is an image of the region built from the ELF `key` describes
+fn key_matches(file: &fs::File, size: usize, key: &[u8]) -> anyhow::Result<bool> {
+  if file.metadata()?.len() != (size + key.len()) as u64 {
+    return Ok(false);
+  }
+  let mut stored = vec![0u8; key.len()];
+  file.read_exact_at(&mut stored, size as u64)?;
+  Ok(stored == key)
+}
+
+/// Write the PT_LOAD segments of `elf_file` falling into [base, base + size) into a raw image,
+/// followed by `key`. The image is written aside and renamed into place, so parallel
+/// simulations can race on it.
+fn build_image(
+  elf_file: &Path,
+  image: &Path,
+  base: usize,
+  size: usize,
+  key: &[u8],
+) -> anyhow::Result<()> {
+  let tmp = image.with_extension(format!("tmp.{}", std::process::id()));
+  let result = write_image(elf_file, &tmp, base, size, key).and_then(|()| {
+    fs::rename(&tmp, image).with_context(|| format!("renaming {} into place", tmp.display()))
+  });
+  if result.is_err() {
+    fs::remove_file(&tmp).ok();
+  }
+  result
+}
+
+fn write_image(
+  elf_file: &Path,
+  out: &Path,
+  base: usize,
+  size: usize,
+  key: &[u8],
+) -> anyhow::Result<()> {
+  let file = fs::File::open(elf_file).with_context(|| "reading ELF file")?;
+  let elf: ElfStream<LittleEndian, _> =
+    ElfStream::open_stream(&file).with_context(|| "parsing ELF file")?;
+
+  let out = fs::File::create(out).with_context(|| format!("creating {}", out.display()))?;
+  let mut load_buffer = Vec::new();
+  for phdr in elf.segments().iter().filter(|phdr| phdr.p_type == PT_LOAD) {
+    let vaddr = phdr.p_vaddr as usize;
+    let filesz = phdr.p_filesz as usize;
+    if vaddr + filesz <= base || base + size <= vaddr {
+      // belongs to another device
+      continue;
+    }
+    if vaddr < base || base + size < vaddr + filesz {
+      anyhow::bail!(
+        "segment [{vaddr:#x}, {:#x}) crosses the image region [{base:#x}, {:#x})",
+        vaddr + filesz,
+        base + size
+      );
+    }
+
+    load_buffer.resize(filesz, 0u8);
+    file.read_exact_at(&mut load_buffer, phdr.p_offset)?;
+    out.write_all_at(&load_buffer, (vaddr - base) as u64)?;
+  }
+  out.write_all_at(key, size as u64)?;
+  out.sync_all()?;
+  Ok(())
+}
+
+/// Open the image of [base, base + size), (re)building it from `elf_file` first unless it
+/// was built from the same ELF (path, length and mtime) for the same region
+pub(super) fn open_image(
+  image: &Path,
+  elf_file: &Path,
+  base: usize,
+  size: usize,
+) -> anyhow::Result<fs::File> {
+  let key = image_key(elf_file, base, size)?;
+  if let Ok(file) = fs::File::open(image) {
+    if key_matches(&file, size, &key)? {
+      return Ok(file);
+    }
+  }
+  info!(
+    "building memory image {} from {}",
+    image.display(),
+    elf_file.display()
+  );
+  build_image(elf_file, image, base, size, &key)?;
+  let file = fs::File::open(image).with_context(|| format!("opening {}", image.display()))?;
+  // the file name is shared, a simulation of another ELF may have replaced it since
+  if !key_matches(&file, size, &key)? {
+    anyhow::bail!(
+      "{} was rebuilt from another ELF meanwhile, give the region a per-ELF path",
+      image.display()
+    );
+  }
+  Ok(file)
+}
+
+/// Build the images of every `image` region ahead of the simulations, for `+mem-image-build`
+pub(crate) fn build_images(config: &BusConfig, elf_file: &Path) -> anyhow::Result<()> {
+  for dev in &config.devices {
+    if let DeviceKind::Image { path } = &dev.kind {
+      let image = image_path(path, elf_file);
+      open_image(&image, elf_file, dev.base, dev.size)
+        .map_err(|e| anyhow::anyhow!("bus config: image `{}`: {e:#}", dev.name))?;
+      info!("image `{}`: {} is up to date", dev.name, image.display());
+    }
+  }
+  Ok(())
+}
+
+/// RAM initialized from a raw image file prebuilt from the ELF, see [`open_image`].
+pub(super) struct ImageMemDevice {
+  mem: MappedFile,
+}
+
+impl ImageMemDevice {
+  pub fn new(image: &Path, elf_file: &Path, base: usize, size: usize) -> anyhow::Result<Self> {
+    let file = open_image(image, elf_file, base, size)?;
+    let mem =
+      MappedFile::private(&file, size).with_context(|| format!("mapping {}", image.display()))?;
+    Ok(Self { mem })
+  }
+}
+
+impl ShadowDevice for ImageMemDevice {
+  fn read_mem(&mut self, addr: usize, size: usize) -> Vec<u8> {
+    self.mem.as_slice()[addr..addr + size].to_vec()
+  }
+
+  fn write_mem_chunk(&mut self, addr: usize, size: usize, strobe: Option<&[bool]>, data: &[u8]) {
+    let mem = self.mem.as_mut_slice();
+    if let Some(masks) = strobe {
+      masks.iter().enumerate().for_each(|(i, mask)| {
+        if *mask {
+          mem[addr + i] = data[i];
+        }
+      })
+    } else {
+      mem[addr..addr + size].copy_from_slice(data);
+    }
+  }
+
+  fn preloaded(&self) -> bool {
+    true
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  /// Minimal RISC-V executable with one PT_LOAD segment of `data` at `vaddr`
+  fn write_elf(path: &Path, vaddr: u64, data: &[u8]) {
+    let mut elf = Vec::new();
+    elf.extend_from_slice(b"\x7fELF\x02\x01\x01\0\0\0\0\0\0\0\0\0");
+    elf.extend_from_slice(&2u16.to_le_bytes()); // ET_EXEC
+    elf.extend_from_slice(&0xf3u16.to_le_bytes()); // EM_RISCV
+    elf.extend_from_slice(&1u32.to_le_bytes());
+    elf.extend_from_slice(&vaddr.to_le_bytes()); // e_entry
+    elf.extend_from_slice(&64u64.to_le_bytes()); // e_phoff
+    elf.extend_from_slice(&0u64.to_le_bytes()); // e_shoff
+    elf.extend_from_slice(&0u32.to_le_bytes());
+    for half in [64u16, 56, 1, 64, 0, 0] {
+      elf.extend_from_slice(&half.to_le_bytes());
+    }
+    elf.extend_from_slice(&PT_LOAD.to_le_bytes());
+    elf.extend_from_slice(&7u32.to_le_bytes());
+    for word in [120, vaddr, vaddr, data.len() as u64, data.len() as u64, 8] {
+      elf.extend_from_slice(&word.to_le_bytes());
+    }
+    elf.extend_from_slice(data);
+    fs::write(path, elf).unwrap();
+  }
+
+  fn scratch_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("cpuemu-{name}-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    dir
+  }
+
+  #[test]
+  fn image_is_reused_only_for_the_same_elf_and_region() {
+    let dir = scratch_dir("image-key");
+    let elf = dir.join("prog");
+    let image = dir.join("prog.img");
+    write_elf(&elf, 0x3000_0010, b"abcd");
+
+    let file = open_image(&image, &elf, 0x3000_0000, 0x1000).unwrap();
+    let mut data = [0u8; 4];
+    file.read_exact_at(&mut data, 0x10).unwrap();
+    assert_eq!(&data, b"abcd");
+
+    // a reused image keeps what was written into it
+    file_at(&image).write_all_at(b"x", 0).unwrap();
+    let file = open_image(&image, &elf, 0x3000_0000, 0x1000).unwrap();
+    file.read_exact_at(&mut data[..1], 0).unwrap();
+    assert_eq!(data[0], b'x');
+
+    // another region, or another ELF with the same name, rebuilds it
+    let file = open_image(&image, &elf, 0x3000_0000, 0x2000).unwrap();
+    file.read_exact_at(&mut data[..1], 0).unwrap();
+    assert_eq!(data[0], 0);
+    let other = scratch_dir("image-key-other").join("prog");
+    write_elf(&other, 0x3000_0010, b"efgh");
+    let file = open_image(&image, &other, 0x3000_0000, 0x2000).unwrap();
+    file.read_exact_at(&mut data, 0x10).unwrap();
+    assert_eq!(&data, b"efgh");
+    fs::remove_dir_all(dir).unwrap();
+    fs::remove_dir_all(other.parent().unwrap()).unwrap();
+  }
+
+  #[test]
+  fn failed_build_leaves_no_temporary_file() {
+    let dir = scratch_dir("image-fail");
+    let elf = dir.join("prog");
+    let image = dir.join("prog.img");
+    // the segment crosses the end of the region
+    write_elf(&elf, 0x3000_0ffe, b"abcd");
+    assert!(open_image(&image, &elf, 0x3000_0000, 0x1000).is_err());
+    assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+    fs::remove_dir_all(dir).unwrap();
+  }
+
+  #[test]
+  fn image_contents_count_as_initialized() {
+    let dir = scratch_dir("image-uninit");
+    let elf = dir.join("prog");
+    write_elf(&elf, 0x3000_0010, b"abcd");
+    let mem = ImageMemDevice::new(&dir.join("prog.img"), &elf, 0x3000_0000, 0x1000).unwrap();
+    // the bytes no segment covers are part of the prebuilt image too
+    assert_eq!(mem.uninitialized(0x0, 0x1000), None);
+    fs::remove_dir_all(dir).unwrap();
+  }
+
+  fn file_at(path: &Path) -> fs::File {
+    fs::OpenOptions::new().write(true).open(path).unwrap()
+  }
+}
@@ -0,0 +1,189 @@
+use std::{collections::VecDeque, fs, path::Path};
+
+use anyhow::Context;
+use serde::Deserialize;
+
+use super::{parse_int, regs::*, ShadowDevice};
+
+// same layout as the keyboard of NEMU/nexus-am: one register holding the next event
+const KEYCODE: usize = 0x0;
+
+const KEYDOWN_MASK: u32 = 0x8000;
+
+/// Key names of nexus-am, in keycode order starting at 1 (0 is "no key")
+#[rustfmt::skip]
+const AM_KEYS: &[&str] = &[
+  "ESCAPE", "F1", "F2", "F3", "F4", "F5", "F6", "F7", "F8", "F9", "F10", "F11", "F12",
+  "GRAVE", "1", "2", "3", "4", "5", "6", "7", "8", "9", "0", "MINUS", "EQUALS", "BACKSPACE",
+  "TAB", "Q", "W", "E", "R", "T", "Y", "U", "I", "O", "P", "LEFTBRACKET", "RIGHTBRACKET", "BACKSLASH",
+  "CAPSLOCK", "A", "S", "D", "F", "G", "H", "J", "K", "L", "SEMICOLON", "APOSTROPHE", "RETURN",
+  "LSHIFT", "Z", "X", "C", "V", "B", "N", "M", "COMMA", "PERIOD", "SLASH", "RSHIFT",
+  "LCTRL", "APPLICATION", "LALT", "SPACE", "RALT", "RCTRL",
+  "UP", "DOWN", "LEFT", "RIGHT", "INSERT", "DELETE", "HOME", "END", "PAGEUP", "PAGEDOWN",
+];
+
+/// What the times in a key script count
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum KeyClock {
+  /// simulation ticks
+  #[default]
+  Tick,
+  /// instructions retired by the DUT
+  Instret,
+}
+
+/// Keyboard replaying a script of timed key events.
+/// Each line of the script is `<time> down|up <key>`, `#` starts a comment;
+/// `key` is a nexus-am key name (`A`, `SPACE`, `RETURN`, ...) or a raw keycode.
+pub(super) struct Keyboard {
+  clock: KeyClock,
+  /// (time, AM keycode with KEYDOWN_MASK), sorted by time
+  script: VecDeque<(u64, u32)>,
+  /// events due but not read yet
+  pending: VecDeque<u32>,
+}
+
+impl Keyboard {
+  pub fn new(script: &Path, clock: KeyClock) -> anyhow::Result<Self> {
+    let text =
+      fs::read_to_string(script).with_context(|| format!("reading {}", script.display()))?;
+    let mut events = Vec::new();
+    for (i, line) in text.lines().enumerate() {
+      let line = line.split('#').next().unwrap_or_default().trim();
+      if line.is_empty() {
+        continue;
+      }
+      let event = parse_event(line).with_context(|| format!("{}:{}", script.display(), i + 1))?;
+      events.push(event);
+    }
+    // stable, events at the same time keep the script order
+    events.sort_by_key(|&(time, _)| time);
+    Ok(Self {
+      clock,
+      script: events.into(),
+      pending: VecDeque::new(),
+    })
+  }
+
+  /// Queue the events whose time has come
+  fn advance(&mut self, now: u64) {
+    while let Some(&(time, code)) = self.script.front() {
+      if time > now {
+        break;
+      }
+      self.pending.push_back(code);
+      self.script.pop_front();
+    }
+  }
+}
+
+fn parse_event(line: &str) -> anyhow::Result<(u64, u32)> {
+  let fields: Vec<&str> = line.split_whitespace().collect();
+  let [time, action, key] = fields[..] else {
+    anyhow::bail!("expect `<time> down|up <key>`, got `{line}`");
+  };
+  let time = parse_int(time)?;
+  let down = match action {
+    "down" => KEYDOWN_MASK,
+    "up" => 0,
+    _ => anyhow::bail!("unknown key action `{action}`, expect down or up"),
+  };
+  let code = match AM_KEYS.iter().position(|name| name.eq_ignore_ascii_case(key)) {
+    Some(index) => index as u32 + 1,
+    None => parse_int(key).map_err(|_| anyhow::anyhow!("unknown key `{key}`"))? as u32,
+  };
+  if code == 0 || code >= KEYDOWN_MASK {
+    anyhow::bail!("keycode {code} out of range");
+  }
+  Ok((time, code | down))
+}
+
+impl ShadowDevice for Keyboard {
+  fn read_mem(&mut self, addr: usize, size: usize) -> Vec<u8> {
+    reg_read(4, addr, size, |offset| match offset {
+      // 0 = no key
+      KEYCODE => self.pending.pop_front().unwrap_or(0) as u64,
+      _ => 0,
+    })
+  }
+
+  fn write_mem_chunk(
+    &mut self,
+    _addr: usize,
+    _size: usize,
+    _strobe: Option<&[bool]>,
+    _data: &[u8],
+  ) {
+    // read-only
+  }
+
+  fn tick(&mut self, tick: u64) {
+    if self.clock == KeyClock::Tick {
+      self.advance(tick);
+    }
+  }
+
+  fn retire(&mut self, instret: u64) {
+    if self.clock == KeyClock::Instret {
+      self.advance(instret);
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn parse_event_takes_names_and_raw_codes() {
+    assert_eq!(parse_event("0 down ESCAPE").unwrap(), (0, 1 | KEYDOWN_MASK));
+    assert_eq!(parse_event("100 up a").unwrap(), (100, 43));
+    assert_eq!(
+      parse_event("0x10  down\tA").unwrap(),
+      (0x10, 43 | KEYDOWN_MASK)
+    );
+    assert_eq!(parse_event("5 up 0x7fff").unwrap(), (5, 0x7fff));
+  }
+
+  #[test]
+  fn parse_event_rejects_malformed_lines() {
+    for line in [
+      "100 down",
+      "100 down A B",
+      "soon down A",
+      "100 press A",
+      "100 down NOSUCHKEY",
+      "100 down 0x0",
+      "100 down 0x8000",
+    ] {
+      assert!(parse_event(line).is_err(), "{line}");
+    }
+  }
+
+  #[test]
+  fn script_skips_comments_and_replays_in_time_order() {
+    let path = std::env::temp_dir().join(format!("cpuemu-keys-{}", std::process::id()));
+    fs::write(
+      &path,
+      "# warm up\n\n20 up A # let go\n  10 down A\n20 down SPACE\n",
+    )
+    .unwrap();
+    let mut keyboard = Keyboard::new(&path, KeyClock::Tick).unwrap();
+    let mut next = |tick| {
+      keyboard.tick(tick);
+      u32::from_le_bytes(keyboard.read_mem(KEYCODE, 4).try_into().unwrap())
+    };
+    assert_eq!(next(9), 0);
+    assert_eq!(next(10), 43 | KEYDOWN_MASK);
+    assert_eq!(next(11), 0);
+    // same time, script order
+    assert_eq!(next(30), 43);
+    assert_eq!(next(30), 70 | KEYDOWN_MASK);
+
+    fs::write(&path, "10 down A\n\n# the next line is bad\n20 down\n").unwrap();
+    let e = Keyboard::new(&path, KeyClock::Tick).err().unwrap();
+    assert!(format!("{e:#}").contains(":4: expect"), "{e:#}");
+    fs::remove_file(path).unwrap();
+  }
+}
@@ -1,18 +1,34 @@
-use super::ShadowDevice;
+use super::{MemInit, ShadowDevice};
 
-pub(super) struct MemDevice<const SIZE: usize> {
-  mem: Box<[u8; SIZE]>,
+pub(super) struct MemDevice {
+  mem: Box<[u8]>,
+  /// one bit per byte, set once the byte is loaded or written,
+  /// only kept when reads of uninitialized memory are checked
+  valid: Option<Box<[u64]>>,
 }
 
-impl<const SIZE: usize> ShadowDevice for MemDevice<SIZE> {
-  fn new() -> Box<dyn ShadowDevice>
-  where
-    Self: Sized,
-  {
-    Box::new(Self { mem: vec![0u8; SIZE].try_into().unwrap() })
+impl MemDevice {
+  /// `base` is where the device is mapped, the initial contents depend on it
+  pub fn new(size: usize, base: usize, init: MemInit, track_valid: bool) -> Self {
+    let mut mem = vec![0u8; size].into_boxed_slice();
+    if init != MemInit::Zero {
+      init.fill(base, &mut mem);
+    }
+    Self {
+      mem,
+      valid: track_valid.then(|| vec![0u64; size.div_ceil(64)].into_boxed_slice()),
+    }
   }
 
-  fn read_mem(&self, addr: usize, size: usize) -> Vec<u8> {
+  fn set_valid(&mut self, start: usize, end: usize) {
+    if let Some(valid) = self.valid.as_mut() {
+      (start..end).for_each(|i| valid[i / 64] |= 1 << (i % 64));
+    }
+  }
+}
+
+impl ShadowDevice for MemDevice {
+  fn read_mem(&mut self, addr: usize, size: usize) -> Vec<u8> {
     let start = addr;
     let end = addr + size;
     self.mem[start..end].to_vec()
@@ -29,12 +45,50 @@ impl<const SIZE: usize> ShadowDevice for MemDevice<SIZE> {
       masks.iter().enumerate().for_each(|(i, mask)| {
         if *mask {
           self.mem[addr + i] = data[i];
+          self.set_valid(addr + i, addr + i + 1);
         }
       })
     } else {
       let start = addr;
       let end = addr + size;
       self.mem[start..end].copy_from_slice(data);
+      self.set_valid(start, end);
     }
   }
+
+  fn uninitialized(&self, addr: usize, size: usize) -> Option<usize> {
+    let valid = self.valid.as_ref()?;
+    (addr..addr + size).find(|&i| valid[i / 64] & (1 << (i % 64)) == 0)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn bytes_are_valid_once_written() {
+    let mut mem = MemDevice::new(0x100, 0x8000_0000, MemInit::Zero, true);
+    assert_eq!(mem.uninitialized(0x0, 0x100), Some(0x0));
+    // a load without strobes crosses a bitmap word
+    mem.write_mem_chunk(0x3c, 8, None, &[1; 8]);
+    assert_eq!(mem.uninitialized(0x3c, 8), None);
+    assert_eq!(mem.uninitialized(0x38, 8), Some(0x38));
+    assert_eq!(mem.uninitialized(0x40, 8), Some(0x44));
+    // only the strobed bytes of a write count
+    let strobe = [true, false, true, true, true, true, true, true];
+    mem.write_mem_chunk(0x80, 8, Some(&strobe), &[2; 8]);
+    assert_eq!(mem.uninitialized(0x80, 8), Some(0x81));
+    assert_eq!(mem.uninitialized(0x82, 6), None);
+    assert_eq!(mem.uninitialized(0xf8, 8), Some(0xf8));
+  }
+
+  #[test]
+  fn nothing_is_tracked_unless_asked() {
+    let mut mem = MemDevice::new(0x100, 0x8000_0000, MemInit::Zero, false);
+    assert!(mem.valid.is_none());
+    assert_eq!(mem.uninitialized(0x0, 0x100), None);
+    mem.write_mem_chunk(0x0, 8, None, &[1; 8]);
+    assert_eq!(mem.uninitialized(0x0, 0x100), None);
+  }
 }
@@ -0,0 +1,113 @@
+use std::str::FromStr;
+
+/// Initial contents of plain RAM, from `+mem-init=`.
+/// Every byte is a function of its address only, so any sub-range can be
+/// regenerated on its own, e.g. to mirror it into the reference model.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum MemInit {
+  #[default]
+  Zero,
+  /// a 64-bit little endian word repeated on every aligned doubleword
+  Pattern(u64),
+  /// pseudo random bytes derived from the seed
+  Random(u64),
+}
+
+impl FromStr for MemInit {
+  type Err = anyhow::Error;
+
+  fn from_str(s: &str) -> anyhow::Result<Self> {
+    match s.split_once(':') {
+      None if s == "zero" => Ok(MemInit::Zero),
+      None if s == "random" => Ok(MemInit::Random(rand::random())),
+      Some(("pattern", value)) => {
+        let value = value.trim_start_matches("0x");
+        u64::from_str_radix(value, 16)
+          .map(MemInit::Pattern)
+          .map_err(|e| anyhow::anyhow!("bad mem-init pattern `{value}`: {e}"))
+      }
+      Some(("random", seed)) => seed
+        .parse()
+        .map(MemInit::Random)
+        .map_err(|e| anyhow::anyhow!("bad mem-init seed `{seed}`: {e}")),
+      _ => {
+        anyhow::bail!("unknown mem-init `{s}`, expect zero, pattern:<hex>, random or random:<seed>")
+      }
+    }
+  }
+}
+
+impl MemInit {
+  /// Fill `buf`, which is mapped at physical address `base`
+  pub fn fill(&self, base: usize, buf: &mut [u8]) {
+    match *self {
+      MemInit::Zero => buf.fill(0),
+      MemInit::Pattern(word) => buf
+        .iter_mut()
+        .enumerate()
+        .for_each(|(i, byte)| *byte = (word >> (((base + i) & 7) * 8)) as u8),
+      MemInit::Random(seed) => buf.iter_mut().enumerate().for_each(|(i, byte)| {
+        let addr = base + i;
+        *byte = (splitmix64(seed ^ (addr as u64 >> 3)) >> ((addr & 7) * 8)) as u8
+      }),
+    }
+  }
+}
+
+/// Stateless 64-bit mixer, good enough to scramble memory
+fn splitmix64(x: u64) -> u64 {
+  let mut z = x.wrapping_add(0x9e37_79b9_7f4a_7c15);
+  z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+  z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+  z ^ (z >> 31)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn parse() {
+    assert_eq!("zero".parse::<MemInit>().unwrap(), MemInit::Zero);
+    assert_eq!(
+      "pattern:0xdeadbeef".parse::<MemInit>().unwrap(),
+      MemInit::Pattern(0xdead_beef)
+    );
+    assert_eq!("random:42".parse::<MemInit>().unwrap(), MemInit::Random(42));
+    assert!(matches!(
+      "random".parse::<MemInit>().unwrap(),
+      MemInit::Random(_)
+    ));
+    for bad in ["", "ones", "pattern:xyz", "random:-1", "zero:1"] {
+      assert!(bad.parse::<MemInit>().is_err(), "{bad}");
+    }
+  }
+
+  #[test]
+  fn pattern_follows_the_address() {
+    let mut buf = [0xffu8; 6];
+    MemInit::Pattern(0x0807_0605_0403_0201).fill(0x1006, &mut buf);
+    assert_eq!(buf, [7, 8, 1, 2, 3, 4]);
+  }
+
+  #[test]
+  fn random_depends_on_address_and_seed_only() {
+    let mut whole = vec![0u8; 64];
+    MemInit::Random(7).fill(0x8000_0000, &mut whole);
+    // any sub-range regenerates the same bytes
+    let mut part = vec![0u8; 13];
+    MemInit::Random(7).fill(0x8000_0011, &mut part);
+    assert_eq!(part, whole[0x11..0x1e]);
+    let mut other = vec![0u8; 64];
+    MemInit::Random(8).fill(0x8000_0000, &mut other);
+    assert_ne!(whole, other);
+    assert!(whole.iter().any(|&byte| byte != 0));
+  }
+
+  #[test]
+  fn zero_clears() {
+    let mut buf = [0xaau8; 4];
+    MemInit::Zero.fill(0, &mut buf);
+    assert_eq!(buf, [0; 4]);
+  }
+}
@@ -1,89 +1,528 @@
+mod config;
+pub(crate) use config::*;
+
+mod regs;
+
+mod exclusive;
+pub(crate) use exclusive::ExclusiveId;
+use exclusive::ExclusiveMonitor;
+
+mod console;
+pub(crate) use console::ConsoleInput;
+use console::HostConsole;
+
+mod block;
+use block::*;
+
+mod clint;
+use clint::*;
+
+mod dma;
+use dma::DmaMemory;
+
+mod plic;
+use plic::*;
+
+mod image_mem;
+pub(crate) use image_mem::build_images;
+use image_mem::*;
+
+mod keyboard;
+pub(crate) use keyboard::KeyClock;
+use keyboard::*;
+
 mod mem;
 use mem::*;
 
+mod mem_init;
+pub(crate) use mem_init::MemInit;
+
+mod rtc;
+pub(crate) use rtc::RtcClock;
+use rtc::*;
+
+mod sparse_mem;
+use sparse_mem::*;
+
+mod timing;
+use timing::Timing;
+
 mod uart;
 use uart::*;
 
+mod uart16550;
+use uart16550::*;
+
+mod virtio;
+use virtio::VirtioMmio;
+
+mod vga;
+use vga::*;
+pub(crate) use vga::{FrameFormat, PixelFormat};
+
+mod watch;
+pub(crate) use watch::{parse_watchpoints, Watchpoint};
+
+use std::path::{Path, PathBuf};
+
 use anyhow;
-use tracing::trace;
+use tracing::{debug, trace, warn};
+
+use crate::axi::AxiResp;
+
+/// Every region must start and end on an AXI data bus boundary, since
+/// `write_mem_axi` always hands a whole bus-width chunk to the device.
+pub(crate) const REGION_ALIGN: usize = 8;
+
+// interrupt lines, in `mip` bit layout
+pub(crate) const MIP_MSIP: u64 = 1 << 3;
+pub(crate) const MIP_MTIP: u64 = 1 << 7;
+pub(crate) const MIP_SEIP: u64 = 1 << 9;
+pub(crate) const MIP_MEIP: u64 = 1 << 11;
+/// Lines the testbench drives into the DUT's `intr` input
+pub(crate) const DUT_IRQ_LINES: u64 = MIP_MSIP | MIP_MTIP | MIP_SEIP | MIP_MEIP;
 
 // 抽象设备
-trait ShadowDevice: Send + Sync {
-  fn new() -> Box<dyn ShadowDevice>
-  where
-    Self: Sized;
+pub trait ShadowDevice: Send + Sync {
   /// addr: offset respect to the base of this device
-  fn read_mem(&self, addr: usize, size: usize) -> Vec<u8>;
+  fn read_mem(&mut self, addr: usize, size: usize) -> Vec<u8>;
   /// addr: offset respect to the base of this device
   /// strobe: signals which element in data is valid, None = all valid
   fn write_mem_chunk(&mut self, addr: usize, size: usize, strobe: Option<&[bool]>, data: &[u8]);
+  /// Contents already hold the ELF segments, `load_mem_seg` leaves it alone
+  fn preloaded(&self) -> bool {
+    false
+  }
+  /// First byte of [addr, addr + size) never loaded nor written,
+  /// None when all of them are or the device does not keep track
+  fn uninitialized(&self, _addr: usize, _size: usize) -> Option<usize> {
+    None
+  }
+  /// Advance the device to simulation tick `tick`
+  fn tick(&mut self, _tick: u64) {}
+  /// The DUT has retired `instret` instructions
+  fn retire(&mut self, _instret: u64) {}
+  /// Interrupt lines driven by the device, in `mip` bit layout
+  fn interrupts(&self) -> u64 {
+    0
+  }
+  /// Interrupt output of the device, routed into the interrupt controller
+  fn irq_level(&self) -> bool {
+    false
+  }
+  /// Whether the device has DMA work for `run_dma`
+  fn wants_dma(&self) -> bool {
+    false
+  }
+  /// Carry out the pending DMA transfers, the device itself is not reachable through `mem`
+  fn run_dma(&mut self, _mem: &mut dyn DmaMemory) {}
+  /// Drive interrupt `source` of an interrupt controller
+  fn set_irq_source(&mut self, _source: u32, _level: bool) {}
+  /// Push out any buffered output, the simulation is about to end
+  fn flush(&mut self) {}
 }
 
 struct ShadowBusDevice {
+  name: String,
   base: usize,
-  size: usize,
   device: Box<dyn ShadowDevice>,
+  on_error: ErrorPolicy,
+  timing: Option<Timing>,
 }
 
 // 所有设备
-const MAX_DEVICES: usize = 2;
 pub(crate) struct ShadowBus {
-  devices: [ShadowBusDevice; MAX_DEVICES],
+  /// in registration order, the index is the device id
+  devices: Vec<ShadowBusDevice>,
+  /// (base, end, device id), sorted by base and never overlapping
+  decode: Vec<(usize, usize, usize)>,
+  /// device id of the interrupt controller
+  irq_controller: Option<usize>,
+  /// (device id, controller source)
+  irq_routes: Vec<(usize, u32)>,
+  on_unmapped: ErrorPolicy,
+  exclusive: ExclusiveMonitor,
+  #[cfg_attr(not(feature = "difftest"), allow(dead_code))]
+  mem_init: MemInit,
+  /// (base, size) of the RAM filled by `mem_init`
+  mem_init_regions: Vec<(usize, usize)>,
+  /// indexed by watchpoint id, None once removed
+  watchpoints: Vec<Option<Watchpoint>>,
+  /// (addr, data) written by DMA, mirrored into the reference model
+  #[cfg(feature = "difftest")]
+  dma_writes: Vec<(usize, Vec<u8>)>,
 }
 
 impl ShadowBus {
-  /// Initiate the devices on the bus as specified in `nexus-am`
-  pub fn new() -> Self {
-    //const SCALAR_SIZE: usize = 0x20000000;
-    //const DDR_SIZE: usize = 0x80000000;
-    //const SRAM_SIZE: usize = 0x00400000;
-
-    Self {
-      devices: [
-        ShadowBusDevice {
-          base: 0x40600000,
-          size: 0x10,
-          device: Uart::<0x10>::new(),
-        },
-        ShadowBusDevice {
-          base: 0x80000000,
-          size: 0x08000000,
-          device: MemDevice::<0x08000000>::new(),
-        },
-        //ShadowBusDevice {
-        //  base: 0x20000000,
-        //  size: SCALAR_SIZE,
-        //  device: MemDevice::<SCALAR_SIZE>::new(),
-        //},
-        //ShadowBusDevice {
-        //  base: 0x40000000,
-        //  size: DDR_SIZE,
-        //  device: MemDevice::<DDR_SIZE>::new(),
-        //},
-        //ShadowBusDevice {
-        //  base: 0xc0000000,
-        //  size: SRAM_SIZE,
-        //  device: MemDevice::<SRAM_SIZE>::new(),
-        //},
-      ],
-    }
-  }
-
-  pub fn read_mem_axi(&self, addr: u32, size: u32, bus_size: u32) -> anyhow::Result<Vec<u8>> {
-    if addr % size != 0 || bus_size % size != 0 {
-      return Ok(vec![0xde, 0xad, 0xbe, 0xef]);
-      // anyhow::bail!("read_mem_axi addr={addr:#x} size={size}B dlen={bus_size}B");
+  /// Initiate the devices on the bus, `elf_file` is the program image devices may be built from
+  /// and `mem_init` the initial contents of plain RAM. Plain RAM only tracks which bytes were
+  /// written when `track_uninit` is set.
+  pub fn from_config(
+    config: &BusConfig,
+    elf_file: &Path,
+    mem_init: MemInit,
+    track_uninit: bool,
+  ) -> anyhow::Result<Self> {
+    let mut bus = Self {
+      devices: Vec::new(),
+      decode: Vec::new(),
+      irq_controller: None,
+      irq_routes: Vec::new(),
+      on_unmapped: config.on_unmapped,
+      exclusive: ExclusiveMonitor::default(),
+      mem_init,
+      mem_init_regions: Vec::new(),
+      watchpoints: Vec::new(),
+      #[cfg(feature = "difftest")]
+      dma_writes: Vec::new(),
+    };
+    let mut irq_sources = 0;
+    let mut irqs = Vec::new();
+    for dev in &config.devices {
+      let device: Box<dyn ShadowDevice> = match &dev.kind {
+        DeviceKind::UartLite { input } => {
+          if dev.size < 0x10 {
+            anyhow::bail!(
+              "bus config: uart-lite `{}` needs at least 0x10 bytes",
+              dev.name
+            );
+          }
+          let console = HostConsole::new(&dev.name, *input)
+            .map_err(|e| anyhow::anyhow!("bus config: uart-lite `{}`: {e:#}", dev.name))?;
+          Box::new(Uart::new(console))
+        }
+        DeviceKind::Uart16550 { input, stride } => {
+          if !matches!(stride, 1 | 4) {
+            anyhow::bail!(
+              "bus config: uart16550 `{}`: stride must be 1 or 4",
+              dev.name
+            );
+          }
+          if dev.size < 8 * stride {
+            anyhow::bail!(
+              "bus config: uart16550 `{}` needs at least {:#x} bytes",
+              dev.name,
+              8 * stride
+            );
+          }
+          let console = HostConsole::new(&dev.name, *input)
+            .map_err(|e| anyhow::anyhow!("bus config: uart16550 `{}`: {e:#}", dev.name))?;
+          Box::new(Uart16550::new(console, *stride))
+        }
+        DeviceKind::Mem => {
+          bus.mem_init_regions.push((dev.base, dev.size));
+          Box::new(MemDevice::new(dev.size, dev.base, mem_init, track_uninit))
+        }
+        DeviceKind::SparseMem { fill } => Box::new(SparseMemDevice::new(*fill)),
+        DeviceKind::Image { path } => {
+          let image = image_path(path, elf_file);
+          let device = ImageMemDevice::new(&image, elf_file, dev.base, dev.size)
+            .map_err(|e| anyhow::anyhow!("bus config: image `{}`: {e:#}", dev.name))?;
+          Box::new(device)
+        }
+        DeviceKind::Clint { freq, inc } => Box::new(Clint::new(*freq, *inc)),
+        DeviceKind::Plic { sources, contexts } => {
+          irq_sources = *sources;
+          Box::new(Plic::new(*sources, *contexts))
+        }
+        DeviceKind::Block { image, cow } => {
+          if dev.size < 0x38 {
+            anyhow::bail!("bus config: block `{}` needs at least 0x38 bytes", dev.name);
+          }
+          let disk = Disk::open(Path::new(image), *cow)
+            .map_err(|e| anyhow::anyhow!("bus config: block `{}`: {e:#}", dev.name))?;
+          Box::new(BlockDevice::new(&dev.name, disk))
+        }
+        DeviceKind::VirtioConsole { input } => {
+          if dev.size < 0x200 {
+            anyhow::bail!(
+              "bus config: virtio-console `{}` needs at least 0x200 bytes",
+              dev.name
+            );
+          }
+          let console = HostConsole::new(&dev.name, *input)
+            .map_err(|e| anyhow::anyhow!("bus config: virtio-console `{}`: {e:#}", dev.name))?;
+          Box::new(VirtioMmio::console(&dev.name, console))
+        }
+        DeviceKind::VirtioBlk { image, cow } => {
+          if dev.size < 0x200 {
+            anyhow::bail!(
+              "bus config: virtio-blk `{}` needs at least 0x200 bytes",
+              dev.name
+            );
+          }
+          let disk = Disk::open(Path::new(image), *cow)
+            .map_err(|e| anyhow::anyhow!("bus config: virtio-blk `{}`: {e:#}", dev.name))?;
+          Box::new(VirtioMmio::blk(&dev.name, disk))
+        }
+        DeviceKind::Rtc { clock, tick_ns } => {
+          if dev.size < 8 {
+            anyhow::bail!("bus config: rtc `{}` needs at least 8 bytes", dev.name);
+          }
+          Box::new(Rtc::new(*clock, *tick_ns, dev.size >= 0x20))
+        }
+        DeviceKind::Keyboard { script, clock } => {
+          let keyboard = Keyboard::new(Path::new(script), *clock)
+            .map_err(|e| anyhow::anyhow!("bus config: keyboard `{}`: {e:#}", dev.name))?;
+          Box::new(keyboard)
+        }
+        DeviceKind::Vga { fb, width, height, format, dump, dump_dir, keep } => {
+          if dev.size < 8 {
+            anyhow::bail!("bus config: vga `{}` needs at least 0x8 bytes", dev.name);
+          }
+          let frames = FrameDump {
+            dir: PathBuf::from(dump_dir),
+            format: *dump,
+            keep: *keep,
+          };
+          let (ctl, framebuffer) = vga(&dev.name, *width, *height, *format, frames)
+            .map_err(|e| anyhow::anyhow!("bus config: vga `{}`: {e:#}", dev.name))?;
+          let fb_name = format!("{}-fb", dev.name);
+          let fb_size = framebuffer_size(*width, *height, *format).next_multiple_of(REGION_ALIGN);
+          bus
+            .register_device(&fb_name, *fb, fb_size, Box::new(framebuffer))
+            .map_err(|e| anyhow::anyhow!("bus config: {e}"))?;
+          debug!(
+            "bus: vga framebuffer `{fb_name}` at [{fb:#x}, {:#x})",
+            fb + fb_size
+          );
+          Box::new(ctl)
+        }
+      };
+      let id = bus
+        .register_device(&dev.name, dev.base, dev.size, device)
+        .map_err(|e| anyhow::anyhow!("bus config: {e}"))?;
+      bus.devices[id].on_error = dev.on_error;
+      if let Some(timing) = &dev.timing {
+        let timing = Timing::new(timing)
+          .map_err(|e| anyhow::anyhow!("bus config: timing of `{}`: {e}", dev.name))?;
+        bus.devices[id].timing = Some(timing);
+      }
+      if let DeviceKind::Plic { .. } = dev.kind {
+        if bus.irq_controller.is_some() {
+          anyhow::bail!("bus config: more than one interrupt controller");
+        }
+        bus.set_irq_controller(id)?;
+      }
+      if let Some(source) = dev.irq {
+        irqs.push((id, &dev.name, source));
+      }
+      debug!(
+        "bus: {} `{}` at [{:#x}, {:#x})",
+        dev.kind.name(),
+        dev.name,
+        dev.base,
+        dev.base + dev.size
+      );
     }
 
-    let start = addr as usize;
-    let end = (addr + size) as usize;
+    for (id, name, source) in irqs {
+      if source == 0 || source as usize > irq_sources {
+        anyhow::bail!(
+          "bus config: device `{name}` uses irq {source}, but the PLIC has sources 1..={irq_sources}"
+        );
+      }
+      bus.connect_irq(id, source).map_err(|e| anyhow::anyhow!("bus config: {e}"))?;
+    }
+    Ok(bus)
+  }
 
-    let handler = self.devices.iter().find(|d| match d {
-      ShadowBusDevice { base, size, device: _ } => *base <= start && end <= (*base + *size),
+  /// Map `device` at [base, base + size), returns its device id.
+  /// Empty, unaligned and overlapping regions are rejected.
+  pub fn register_device(
+    &mut self,
+    name: &str,
+    base: usize,
+    size: usize,
+    device: Box<dyn ShadowDevice>,
+  ) -> anyhow::Result<usize> {
+    if size == 0 {
+      anyhow::bail!("device `{name}` has zero size");
+    }
+    if !base.is_multiple_of(REGION_ALIGN) || !size.is_multiple_of(REGION_ALIGN) {
+      anyhow::bail!(
+        "device `{name}` [{base:#x}, {:#x}) is not aligned to {REGION_ALIGN} bytes",
+        base.wrapping_add(size)
+      );
+    }
+    let Some(end) = base.checked_add(size) else {
+      anyhow::bail!("device `{name}` at {base:#x} with size {size:#x} exceeds the address space");
+    };
+
+    // first region that ends after `base`, the only one that may overlap
+    let pos = self.decode.partition_point(|&(_, e, _)| e <= base);
+    if let Some(&(b, e, id)) = self.decode.get(pos) {
+      if b < end {
+        anyhow::bail!(
+          "device `{name}` [{base:#x}, {end:#x}) overlaps device `{}` [{b:#x}, {e:#x})",
+          self.devices[id].name
+        );
+      }
+    }
+
+    let id = self.devices.len();
+    self.devices.push(ShadowBusDevice {
+      name: name.to_string(),
+      base,
+      device,
+      on_error: ErrorPolicy::Abort,
+      timing: None,
     });
+    self.decode.insert(pos, (base, end, id));
+    Ok(id)
+  }
+
+  /// Make device `id` the interrupt controller other devices raise their lines into
+  pub fn set_irq_controller(&mut self, id: usize) -> anyhow::Result<()> {
+    if id >= self.devices.len() {
+      anyhow::bail!("no device with id {id}");
+    }
+    self.irq_controller = Some(id);
+    Ok(())
+  }
+
+  /// Wire the interrupt output of device `id` to `source` of the interrupt controller
+  pub fn connect_irq(&mut self, id: usize, source: u32) -> anyhow::Result<()> {
+    if id >= self.devices.len() {
+      anyhow::bail!("no device with id {id}");
+    }
+    if self.irq_controller.is_none() {
+      anyhow::bail!(
+        "device `{}` raises irq {source}, but there is no interrupt controller",
+        self.devices[id].name
+      );
+    }
+    self.irq_routes.push((id, source));
+    Ok(())
+  }
+
+  /// Find the device containing the whole [start, end)
+  fn decode(&self, start: usize, end: usize) -> Option<usize> {
+    let pos = self.decode.partition_point(|&(b, _, _)| b <= start);
+    let &(_, e, id) = self.decode.get(pos.checked_sub(1)?)?;
+    (end <= e).then_some(id)
+  }
+
+  /// The devices mapped right below and above `addr`, for diagnostics
+  fn neighbours(&self, addr: usize) -> String {
+    let pos = self.decode.partition_point(|&(b, _, _)| b <= addr);
+    let below = match pos.checked_sub(1).map(|i| self.decode[i]) {
+      Some((b, e, id)) => format!(
+        "`{}` [{b:#x}, {e:#x}) ends {:#x} below",
+        self.devices[id].name,
+        addr.saturating_sub(e)
+      ),
+      None => "nothing".to_string(),
+    };
+    let above = match self.decode.get(pos) {
+      Some(&(b, e, id)) => {
+        format!(
+          "`{}` [{b:#x}, {e:#x}) starts {:#x} above",
+          self.devices[id].name,
+          b - addr
+        )
+      }
+      None => "nothing".to_string(),
+    };
+    format!("nearest devices: {below}, {above}")
+  }
+
+  /// Advance every device to `tick`, returns the pending interrupt lines in `mip` bit layout
+  pub fn tick(&mut self, tick: u64) -> u64 {
+    self.devices.iter_mut().for_each(|dev| dev.device.tick(tick));
+    self.run_dma();
+    if let Some(ctrl) = self.irq_controller {
+      for &(id, source) in &self.irq_routes {
+        let level = self.devices[id].device.irq_level();
+        self.devices[ctrl].device.set_irq_source(source, level);
+      }
+    }
+    self.devices.iter().fold(0, |mip, dev| mip | dev.device.interrupts())
+  }
+
+  pub fn retire(&mut self, instret: u64) {
+    self.devices.iter_mut().for_each(|dev| dev.device.retire(instret));
+  }
+
+  pub fn flush(&mut self) {
+    self.devices.iter_mut().for_each(|dev| dev.device.flush());
+  }
+
+  /// Ticks until the response to a burst of `bytes` at `addr` arriving at `tick` is ready
+  pub fn response_delay(&mut self, addr: u32, bytes: u32, tick: u64) -> u64 {
+    let start = addr as usize;
+    let Some(id) = self.decode(start, start + 1) else {
+      return 0;
+    };
+    let ShadowBusDevice { base, timing, .. } = &mut self.devices[id];
+    match timing {
+      Some(timing) => timing.access(start - *base, bytes as u64, tick),
+      None => 0,
+    }
+  }
+
+  /// Log the access statistics of the timed regions
+  pub fn timing_summary(&self) {
+    for dev in &self.devices {
+      if let Some(timing) = &dev.timing {
+        timing.summary(&dev.name);
+      }
+    }
+  }
+
+  /// Answer a failed access following `policy`, `Err` aborts the simulation
+  fn fault(policy: ErrorPolicy, resp: AxiResp, msg: String) -> anyhow::Result<AxiResp> {
+    match policy {
+      ErrorPolicy::Abort => Err(anyhow::anyhow!(msg)),
+      ErrorPolicy::Error => {
+        warn!("{msg}, responding {resp:?}");
+        Ok(resp)
+      }
+    }
+  }
+
+  /// Policy for a misaligned access to [start, end)
+  fn misaligned_policy(&self, start: usize, end: usize) -> ErrorPolicy {
+    match self.decode(start, end) {
+      Some(id) => self.devices[id].on_error,
+      None => self.on_unmapped,
+    }
+  }
+
+  /// Exclusive read of [addr, addr + size) by `id`, replaces the previous reservation of `id`
+  pub fn reserve(&mut self, id: ExclusiveId, addr: u32, size: u32) {
+    let start = addr as usize;
+    self.exclusive.reserve(id, start, start + size as usize);
+  }
+
+  /// Whether an exclusive write of [addr, addr + size) by `id` may proceed
+  pub fn exclusive_ok(&self, id: ExclusiveId, addr: u32, size: u32) -> bool {
+    let start = addr as usize;
+    self.exclusive.holds(id, start, start + size as usize)
+  }
 
-    match handler {
-      Some(ShadowBusDevice { base, size: _, device }) => {
+  /// Returns the response and a bus-width beat, zeroed on errors
+  pub fn read_mem_axi(
+    &mut self,
+    addr: u32,
+    size: u32,
+    bus_size: u32,
+  ) -> anyhow::Result<(AxiResp, Vec<u8>)> {
+    let start = addr as usize;
+    let end = start + size as usize;
+
+    if !addr.is_multiple_of(size) || !bus_size.is_multiple_of(size) {
+      let policy = self.misaligned_policy(start, end);
+      let msg = format!("read_mem_axi addr={addr:#x} size={size}B dlen={bus_size}B is misaligned");
+      return Ok((
+        Self::fault(policy, AxiResp::SlvErr, msg)?,
+        vec![0; bus_size as usize],
+      ));
+    }
+
+    match self.decode(start, end) {
+      Some(id) => {
+        let ShadowBusDevice { base, device, .. } = &mut self.devices[id];
         let offset = start - *base;
         let data = device.read_mem(offset, size as usize);
 
@@ -93,33 +532,68 @@ impl ShadowBus {
           let end = start + data.len();
           data_padded[start..end].copy_from_slice(&data);
 
-          Ok(data_padded)
+          Ok((AxiResp::Okay, data_padded))
         } else {
-          Ok(data)
+          Ok((AxiResp::Okay, data))
         }
       }
       None => {
-        anyhow::bail!("read addr={addr:#x} size={size}B dlen={bus_size}B leads to nowhere!");
+        let msg = format!(
+          "read addr={addr:#x} size={size}B dlen={bus_size}B leads to nowhere! ({})",
+          self.neighbours(start)
+        );
+        let resp = Self::fault(self.on_unmapped, AxiResp::DecErr, msg)?;
+        Ok((resp, vec![0; bus_size as usize]))
       }
     }
   }
 
-  pub fn read_mem_unaligned(&self, addr: u64, size: u64) -> anyhow::Result<Vec<u8>> {
-    let start = addr as usize;
-    let end = (addr + size) as usize;
+  /// Start logging the AXI accesses to a range, returns the watchpoint id
+  pub fn watch(&mut self, watchpoint: Watchpoint) -> usize {
+    self.watchpoints.push(Some(watchpoint));
+    self.watchpoints.len() - 1
+  }
 
-    let handler = self.devices.iter().find(|d| match d {
-      ShadowBusDevice { base, size, device: _ } => *base <= start && end <= (*base + *size),
-    });
+  /// Remove a watchpoint set by `watch`
+  #[allow(dead_code)]
+  pub fn unwatch(&mut self, id: usize) -> Option<Watchpoint> {
+    self.watchpoints.get_mut(id)?.take()
+  }
+
+  /// Watchpoints caught by an access to [start, end), with their ids
+  pub fn watch_hits(&self, start: usize, end: usize, write: bool) -> Vec<(usize, &Watchpoint)> {
+    self
+      .watchpoints
+      .iter()
+      .enumerate()
+      .filter_map(|(id, watch)| Some((id, watch.as_ref()?)))
+      .filter(|(_, watch)| watch.hit(start, end, write))
+      .collect()
+  }
+
+  /// Address of the first byte in [addr, addr + size) that was never loaded nor written
+  pub fn first_uninitialized(&self, addr: usize, size: usize) -> Option<usize> {
+    let id = self.decode(addr, addr + size)?;
+    let ShadowBusDevice { base, device, .. } = &self.devices[id];
+    device.uninitialized(addr - base, size).map(|offset| base + offset)
+  }
 
-    match handler {
-      Some(ShadowBusDevice { base, size: _, device }) => {
+  pub fn read_mem_unaligned(&mut self, addr: u64, size: u64) -> anyhow::Result<Vec<u8>> {
+    let start = addr as usize;
+    let end = start + size as usize;
+
+    match self.decode(start, end) {
+      Some(id) => {
+        let ShadowBusDevice { base, device, .. } = &mut self.devices[id];
         let offset = start - *base;
         let data = device.read_mem(offset, size as usize);
         Ok(data)
       }
       None => {
-        anyhow::bail!("read addr={addr:#x} size={size}B leads to nowhere!");
+        anyhow::bail!(
+          "read addr={addr:#x} size={size}B leads to nowhere! ({})",
+          self.neighbours(start)
+        );
       }
     }
   }
@@ -134,54 +608,360 @@ impl ShadowBus {
     bus_size: u32,
     masks: &[bool],
     data: &[u8],
-  ) -> anyhow::Result<()> {
-    if addr % size != 0 || bus_size % size != 0 {
-      anyhow::bail!("write_mem_axi addr={addr:#x} size={size}B dlen={bus_size}B");
+  ) -> anyhow::Result<AxiResp> {
+    if !addr.is_multiple_of(size) || !bus_size.is_multiple_of(size) {
+      let start = addr as usize;
+      let policy = self.misaligned_policy(start, start + size as usize);
+      let msg = format!("write_mem_axi addr={addr:#x} size={size}B dlen={bus_size}B is misaligned");
+      return Self::fault(policy, AxiResp::SlvErr, msg);
     }
 
     if !masks.iter().any(|x| *x) {
       trace!("Mask 0 write detected");
-      return Ok(());
+      return Ok(AxiResp::Okay);
     }
 
     let start = (addr & ((!bus_size) + 1)) as usize;
     let end = start + bus_size as usize;
 
-    let handler = self.devices.iter_mut().find(|d| match d {
-      ShadowBusDevice { base, size, device: _ } => *base <= start && end <= (*base + *size),
-    });
-
-    match handler {
-      Some(ShadowBusDevice { base, size: _, device }) => {
+    match self.decode(start, end) {
+      Some(id) => {
+        let ShadowBusDevice { base, device, .. } = &mut self.devices[id];
         let offset = start - *base;
         device.write_mem_chunk(offset, bus_size as usize, Option::from(masks), data);
+        // only the strobed bytes break reservations
+        let lo = masks.iter().position(|m| *m).unwrap_or(0);
+        let hi = masks.iter().rposition(|m| *m).map_or(0, |i| i + 1);
+        self.exclusive.clear(start + lo, start + hi);
       }
       None => {
-        anyhow::bail!("write addr={addr:#x} size={size}B dlen={bus_size}B leads to nowhere!");
+        let msg = format!(
+          "write addr={addr:#x} size={size}B dlen={bus_size}B leads to nowhere! ({})",
+          self.neighbours(start)
+        );
+        return Self::fault(self.on_unmapped, AxiResp::DecErr, msg);
+      }
+    }
+    Ok(AxiResp::Okay)
+  }
+
+  /// Hand the initial RAM contents to `load` in chunks, so the reference model
+  /// can start from the same memory. Zero-filled RAM is skipped.
+  #[cfg_attr(not(feature = "difftest"), allow(dead_code))]
+  pub fn mirror_mem_init(&self, mut load: impl FnMut(usize, &[u8])) {
+    const CHUNK: usize = 1 << 20;
+    if self.mem_init == MemInit::Zero {
+      return;
+    }
+    let mut buf = vec![0u8; CHUNK];
+    for &(base, size) in &self.mem_init_regions {
+      for start in (base..base + size).step_by(CHUNK) {
+        let chunk = &mut buf[..CHUNK.min(base + size - start)];
+        self.mem_init.fill(start, chunk);
+        load(start, chunk);
       }
     }
-    Ok(())
   }
 
   pub fn load_mem_seg(&mut self, vaddr: usize, data: &[u8]) -> anyhow::Result<()> {
-    let handler = self
-      .devices
-      .iter_mut()
-      .find(|d| match d {
-        ShadowBusDevice { base, size, device: _ } => {
-          *base <= vaddr as usize && (vaddr as usize + data.len()) <= (*base + *size)
-        }
-      })
-      .ok_or_else(|| {
-        anyhow::anyhow!(
-          "fail reading ELF into mem with vaddr={:#x}, len={}B: load memory to nowhere",
-          vaddr,
-          data.len()
-        )
-      })?;
+    let id = self.decode(vaddr, vaddr + data.len()).ok_or_else(|| {
+      anyhow::anyhow!(
+        "fail reading ELF into mem with vaddr={:#x}, len={}B: load memory to nowhere",
+        vaddr,
+        data.len()
+      )
+    })?;
 
+    let handler = &mut self.devices[id];
+    if handler.device.preloaded() {
+      return Ok(());
+    }
     let offset = vaddr - handler.base;
     handler.device.write_mem_chunk(offset, data.len(), None, data);
     Ok(())
   }
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn empty_bus() -> ShadowBus {
+    bus_on_unmapped(ErrorPolicy::Abort)
+  }
+
+  fn bus_on_unmapped(on_unmapped: ErrorPolicy) -> ShadowBus {
+    let config = BusConfig { devices: Vec::new(), on_unmapped };
+    ShadowBus::from_config(&config, Path::new(""), MemInit::Zero, false).unwrap()
+  }
+
+  fn register(bus: &mut ShadowBus, name: &str, base: usize, size: usize) -> anyhow::Result<usize> {
+    bus.register_device(name, base, size, Box::new(SparseMemDevice::new(0)))
+  }
+
+  /// A 32-bit register write through the 8-byte AXI data bus
+  fn write32(bus: &mut ShadowBus, addr: u32, value: u32) {
+    let shift = addr as usize % 8;
+    let mut data = [0; 8];
+    data[shift..shift + 4].copy_from_slice(&value.to_le_bytes());
+    let strobe: Vec<bool> = (0..8).map(|i| (shift..shift + 4).contains(&i)).collect();
+    bus.write_mem_axi(addr & !7, 8, 8, &strobe, &data).unwrap();
+  }
+
+  /// The TOML example in the doc comment of `BusConfig`
+  fn doc_example() -> String {
+    include_str!("config.rs")
+      .lines()
+      .map(str::trim_start)
+      .skip_while(|line| *line != "/// ```toml")
+      .skip(1)
+      .take_while(|line| *line != "/// ```")
+      .map(|line| line.strip_prefix("///").unwrap().trim_start())
+      .collect::<Vec<_>>()
+      .join("\n")
+  }
+
+  #[test]
+  fn decode_finds_the_containing_device() {
+    let mut bus = empty_bus();
+    let high = register(&mut bus, "high", 0x8000_0000, 0x1000).unwrap();
+    let low = register(&mut bus, "low", 0x1000, 0x100).unwrap();
+    assert_eq!(bus.decode(0x1000, 0x1008), Some(low));
+    assert_eq!(bus.decode(0x10f8, 0x1100), Some(low));
+    assert_eq!(bus.decode(0x8000_0ff8, 0x8000_1000), Some(high));
+    // past the end, in the gap and below everything
+    assert_eq!(bus.decode(0x10f8, 0x1108), None);
+    assert_eq!(bus.decode(0x2000, 0x2008), None);
+    assert_eq!(bus.decode(0x0, 0x8), None);
+    assert_eq!(bus.decode(0x8000_1000, 0x8000_1008), None);
+  }
+
+  #[test]
+  fn register_rejects_bad_regions() {
+    let mut bus = empty_bus();
+    register(&mut bus, "ram", 0x8000_0000, 0x1000).unwrap();
+    // right before and right after are fine
+    register(&mut bus, "below", 0x7fff_f000, 0x1000).unwrap();
+    register(&mut bus, "above", 0x8000_1000, 0x1000).unwrap();
+
+    for (base, size) in [
+      (0x8000_0000, 0x1000),
+      (0x7fff_fff8, 0x10),
+      (0x8000_0ff8, 0x10),
+      (0x8000_0100, 0x8),
+    ] {
+      let e = register(&mut bus, "new", base, size).unwrap_err();
+      assert!(e.to_string().contains("overlaps"), "{e}");
+    }
+    assert!(register(&mut bus, "empty", 0x1000, 0).is_err());
+    assert!(register(&mut bus, "unaligned", 0x1004, 0x8).is_err());
+    assert!(register(&mut bus, "odd", 0x1000, 0x4).is_err());
+    assert!(register(&mut bus, "wraps", usize::MAX - 7, 0x10).is_err());
+    assert_eq!(bus.devices.len(), 3);
+    assert!(bus.decode.windows(2).all(|w| w[0].1 <= w[1].0));
+  }
+
+  #[test]
+  fn unmapped_accesses_follow_on_unmapped() {
+    let mut bus = bus_on_unmapped(ErrorPolicy::Error);
+    register(&mut bus, "ram", 0x1000, 0x100).unwrap();
+    assert_eq!(
+      bus.read_mem_axi(0x2000, 8, 8).unwrap(),
+      (AxiResp::DecErr, vec![0; 8])
+    );
+    assert_eq!(
+      bus.write_mem_axi(0x2000, 8, 8, &[true; 8], &[1; 8]).unwrap(),
+      AxiResp::DecErr
+    );
+    // a misaligned access that hits nothing follows `on_unmapped` too
+    assert_eq!(
+      bus.read_mem_axi(0x2004, 8, 8).unwrap(),
+      (AxiResp::SlvErr, vec![0; 8])
+    );
+    assert_eq!(
+      bus.read_mem_axi(0x1000, 8, 8).unwrap(),
+      (AxiResp::Okay, vec![0; 8])
+    );
+
+    let mut bus = empty_bus();
+    register(&mut bus, "ram", 0x1000, 0x100).unwrap();
+    assert!(bus.read_mem_axi(0x2000, 8, 8).is_err());
+    assert!(bus.write_mem_axi(0x2000, 8, 8, &[true; 8], &[1; 8]).is_err());
+  }
+
+  #[test]
+  fn misaligned_accesses_follow_the_device_policy() {
+    let mut bus = empty_bus();
+    let ram = register(&mut bus, "ram", 0x1000, 0x100).unwrap();
+    bus.devices[ram].on_error = ErrorPolicy::Error;
+    assert_eq!(
+      bus.read_mem_axi(0x1004, 8, 8).unwrap(),
+      (AxiResp::SlvErr, vec![0; 8])
+    );
+    assert_eq!(
+      bus.write_mem_axi(0x1004, 8, 8, &[true; 8], &[1; 8]).unwrap(),
+      AxiResp::SlvErr
+    );
+    // the failed write left the memory alone
+    assert_eq!(
+      bus.read_mem_axi(0x1000, 8, 8).unwrap(),
+      (AxiResp::Okay, vec![0; 8])
+    );
+
+    bus.devices[ram].on_error = ErrorPolicy::Abort;
+    assert!(bus.read_mem_axi(0x1004, 8, 8).is_err());
+    assert!(bus.write_mem_axi(0x1004, 8, 8, &[true; 8], &[1; 8]).is_err());
+  }
+
+  /// One-beat exclusive write the way the driver serves it
+  fn exclusive_write(bus: &mut ShadowBus, id: ExclusiveId, addr: u32, data: u8) -> AxiResp {
+    if !bus.exclusive_ok(id, addr, 8) {
+      return AxiResp::Okay;
+    }
+    match bus.write_mem_axi(addr, 8, 8, &[true; 8], &[data; 8]).unwrap() {
+      AxiResp::Okay => AxiResp::ExOkay,
+      resp => resp,
+    }
+  }
+
+  #[test]
+  fn exclusive_write_after_reserve_succeeds_once() {
+    let mut bus = empty_bus();
+    register(&mut bus, "ram", 0x1000, 0x100).unwrap();
+    bus.reserve((0, 1), 0x1008, 8);
+    // a different ID or a different range does not hold the reservation
+    assert!(!bus.exclusive_ok((0, 2), 0x1008, 8));
+    assert!(!bus.exclusive_ok((1, 1), 0x1008, 8));
+    assert!(!bus.exclusive_ok((0, 1), 0x1008, 4));
+    assert_eq!(
+      exclusive_write(&mut bus, (0, 1), 0x1008, 0xaa),
+      AxiResp::ExOkay
+    );
+    assert_eq!(
+      bus.read_mem_axi(0x1008, 8, 8).unwrap(),
+      (AxiResp::Okay, vec![0xaa; 8])
+    );
+    // the write itself used the reservation up
+    assert_eq!(
+      exclusive_write(&mut bus, (0, 1), 0x1008, 0xbb),
+      AxiResp::Okay
+    );
+    assert_eq!(
+      bus.read_mem_axi(0x1008, 8, 8).unwrap(),
+      (AxiResp::Okay, vec![0xaa; 8])
+    );
+  }
+
+  #[test]
+  fn overlapping_writes_break_reservations() {
+    let mut bus = empty_bus();
+    register(&mut bus, "ram", 0x1000, 0x100).unwrap();
+    bus.reserve((0, 1), 0x1008, 8);
+    // another ID writes only the last byte of the reserved doubleword
+    let mut strobe = [false; 8];
+    strobe[7] = true;
+    assert_eq!(
+      bus.write_mem_axi(0x1008, 8, 8, &strobe, &[0x55; 8]).unwrap(),
+      AxiResp::Okay
+    );
+    assert_eq!(
+      exclusive_write(&mut bus, (0, 1), 0x1008, 0xaa),
+      AxiResp::Okay
+    );
+    let expected = [0, 0, 0, 0, 0, 0, 0, 0x55];
+    assert_eq!(
+      bus.read_mem_axi(0x1008, 8, 8).unwrap(),
+      (AxiResp::Okay, expected.to_vec())
+    );
+  }
+
+  #[test]
+  fn disjoint_writes_keep_reservations() {
+    let mut bus = empty_bus();
+    register(&mut bus, "ram", 0x1000, 0x100).unwrap();
+    bus.reserve((0, 1), 0x1008, 4);
+    bus.write_mem_axi(0x1000, 8, 8, &[true; 8], &[0x55; 8]).unwrap();
+    bus.write_mem_axi(0x1010, 8, 8, &[true; 8], &[0x55; 8]).unwrap();
+    // the other half of the same bus beat
+    let strobe = [false, false, false, false, true, true, true, true];
+    bus.write_mem_axi(0x100c, 4, 8, &strobe, &[0x55; 8]).unwrap();
+    assert!(bus.exclusive_ok((0, 1), 0x1008, 4));
+  }
+
+  #[test]
+  fn ram_is_uninitialized_until_loaded_or_written() {
+    let mut bus = empty_bus();
+    let ram = MemDevice::new(0x100, 0x1000, MemInit::Zero, true);
+    bus.register_device("ram", 0x1000, 0x100, Box::new(ram)).unwrap();
+    assert_eq!(bus.first_uninitialized(0x1000, 8), Some(0x1000));
+    // what the ELF loader puts there
+    bus.load_mem_seg(0x1004, &[1; 0x10]).unwrap();
+    assert_eq!(bus.first_uninitialized(0x1000, 8), Some(0x1000));
+    assert_eq!(bus.first_uninitialized(0x1004, 0x10), None);
+    assert_eq!(bus.first_uninitialized(0x1010, 8), Some(0x1014));
+    // a write only covers its strobed bytes
+    let strobe = [true, true, true, true, false, false, false, false];
+    bus.write_mem_axi(0x1000, 4, 8, &strobe, &[2; 8]).unwrap();
+    assert_eq!(bus.first_uninitialized(0x1000, 8), None);
+    assert_eq!(bus.first_uninitialized(0x1014, 4), Some(0x1014));
+    // devices without a bitmap never report, nor does the space between devices
+    register(&mut bus, "sparse", 0x2000, 0x100).unwrap();
+    assert_eq!(bus.first_uninitialized(0x2000, 8), None);
+    assert_eq!(bus.first_uninitialized(0x3000, 8), None);
+  }
+
+  #[test]
+  fn clint_lines_reach_the_dut() {
+    let config = BusConfig::default();
+    let mut bus = ShadowBus::from_config(&config, Path::new(""), MemInit::Zero, false).unwrap();
+    assert_eq!(bus.tick(2) & DUT_IRQ_LINES, 0);
+    // mtimecmp, then msip
+    bus.write_mem_axi(0x3800_4000, 8, 8, &[true; 8], &[0; 8]).unwrap();
+    assert_eq!(bus.tick(4) & DUT_IRQ_LINES, MIP_MTIP);
+    bus.write_mem_axi(0x3800_0000, 8, 8, &[true; 8], &[1, 0, 0, 0, 0, 0, 0, 0]).unwrap();
+    assert_eq!(bus.tick(6) & DUT_IRQ_LINES, MIP_MSIP | MIP_MTIP);
+    bus.write_mem_axi(0x3800_4000, 8, 8, &[true; 8], &[0xff; 8]).unwrap();
+    assert_eq!(bus.tick(8) & DUT_IRQ_LINES, MIP_MSIP);
+  }
+
+  #[test]
+  fn plic_lines_reach_the_dut() {
+    let config: BusConfig = toml::from_str(
+      r#"
+      [[devices]]
+      name = "plic"
+      kind = "plic"
+      base = 0x0c000000
+      size = 0x4000000
+      [[devices]]
+      name = "uart"
+      kind = "uart16550"
+      base = 0x10000000
+      size = 0x8
+      irq = 1
+      "#,
+    )
+    .unwrap();
+    let mut bus = ShadowBus::from_config(&config, Path::new(""), MemInit::Zero, false).unwrap();
+    // THR empty interrupt of the uart (IER, byte 1) on source 1, priority 1
+    let strobe = [false, true, false, false, false, false, false, false];
+    bus.write_mem_axi(0x1000_0000, 8, 8, &strobe, &[0, 1 << 1, 0, 0, 0, 0, 0, 0]).unwrap();
+    write32(&mut bus, 0x0c00_0004, 1);
+    assert_eq!(bus.tick(2) & DUT_IRQ_LINES, 0);
+    // context 0 drives MEIP, context 1 SEIP
+    write32(&mut bus, 0x0c00_2000, 1 << 1);
+    assert_eq!(bus.tick(4) & DUT_IRQ_LINES, MIP_MEIP);
+    write32(&mut bus, 0x0c00_2080, 1 << 1);
+    assert_eq!(bus.tick(6) & DUT_IRQ_LINES, MIP_MEIP | MIP_SEIP);
+  }
+
+  #[test]
+  fn doc_example_regions_fit() {
+    let config: BusConfig = toml::from_str(&doc_example()).unwrap();
+    assert!(!config.devices.is_empty());
+    let mut bus = empty_bus();
+    for dev in &config.devices {
+      register(&mut bus, &dev.name, dev.base, dev.size).unwrap();
+    }
+  }
+}
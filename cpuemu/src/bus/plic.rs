@@ -0,0 +1,253 @@
+use super::{regs::*, ShadowDevice, MIP_MEIP, MIP_SEIP};
+
+// SiFive PLIC layout
+const PRIORITY: usize = 0x0;
+const PENDING: usize = 0x1000;
+const ENABLE: usize = 0x2000;
+const ENABLE_STRIDE: usize = 0x80;
+const CONTEXT: usize = 0x20_0000;
+const CONTEXT_STRIDE: usize = 0x1000;
+
+const PRIORITY_MASK: u32 = 0x7;
+
+/// Platform-level interrupt controller with level-triggered gateways.
+/// Context 0 drives MEIP and context 1 drives SEIP of the (single) hart.
+pub(super) struct Plic {
+  /// indexed by source id, source 0 is reserved
+  priority: Vec<u32>,
+  level: Vec<bool>,
+  pending: Vec<bool>,
+  /// claimed but not completed yet, the gateway holds further requests
+  in_service: Vec<bool>,
+
+  /// indexed by context, then by source id
+  enable: Vec<Vec<bool>>,
+  threshold: Vec<u32>,
+}
+
+impl Plic {
+  pub fn new(sources: usize, contexts: usize) -> Self {
+    let n = sources + 1;
+    Self {
+      priority: vec![0; n],
+      level: vec![false; n],
+      pending: vec![false; n],
+      in_service: vec![false; n],
+      enable: vec![vec![false; n]; contexts],
+      threshold: vec![0; contexts],
+    }
+  }
+
+  /// Highest priority pending source enabled for `ctx`, ties go to the lowest id
+  fn best(&self, ctx: usize) -> Option<usize> {
+    (1..self.priority.len())
+      .filter(|&src| self.pending[src] && self.enable[ctx][src])
+      .filter(|&src| self.priority[src] > self.threshold[ctx])
+      .max_by_key(|&src| (self.priority[src], std::cmp::Reverse(src)))
+  }
+
+  fn claim(&mut self, ctx: usize) -> u32 {
+    match self.best(ctx) {
+      Some(src) => {
+        self.pending[src] = false;
+        self.in_service[src] = true;
+        src as u32
+      }
+      None => 0,
+    }
+  }
+
+  fn complete(&mut self, src: usize) {
+    if src == 0 || src >= self.in_service.len() {
+      return;
+    }
+    self.in_service[src] = false;
+    if self.level[src] {
+      self.pending[src] = true;
+    }
+  }
+
+  /// 32 enable/pending bits starting at source `word * 32`
+  fn bits(bits: &[bool], word: usize) -> u32 {
+    (0..32)
+      .filter(|i| bits.get(word * 32 + i).copied().unwrap_or(false))
+      .fold(0, |acc, i| acc | (1 << i))
+  }
+}
+
+impl ShadowDevice for Plic {
+  fn read_mem(&mut self, addr: usize, size: usize) -> Vec<u8> {
+    let contexts = self.threshold.len();
+    reg_read(4, addr, size, |offset| {
+      let value = match offset {
+        o if o < PENDING => self.priority.get((o - PRIORITY) / 4).copied().unwrap_or(0),
+        o if o < ENABLE => Self::bits(&self.pending, (o - PENDING) / 4),
+        o if o < CONTEXT => {
+          let ctx = (o - ENABLE) / ENABLE_STRIDE;
+          match self.enable.get(ctx) {
+            Some(enable) => Self::bits(enable, (o - ENABLE) % ENABLE_STRIDE / 4),
+            None => 0,
+          }
+        }
+        o => match (
+          (o - CONTEXT) / CONTEXT_STRIDE,
+          (o - CONTEXT) % CONTEXT_STRIDE,
+        ) {
+          (ctx, 0) if ctx < contexts => self.threshold[ctx],
+          (ctx, 4) if ctx < contexts => self.claim(ctx),
+          _ => 0,
+        },
+      };
+      value as u64
+    })
+  }
+
+  fn write_mem_chunk(&mut self, addr: usize, size: usize, strobe: Option<&[bool]>, data: &[u8]) {
+    let contexts = self.threshold.len();
+    for reg in reg_writes(4, addr, size, strobe, data) {
+      match reg.offset {
+        o if o < PENDING => {
+          let src = (o - PRIORITY) / 4;
+          if src != 0 && src < self.priority.len() {
+            self.priority[src] = reg.merge(self.priority[src] as u64) as u32 & PRIORITY_MASK;
+          }
+        }
+        // pending bits are read-only
+        o if o < ENABLE => {}
+        o if o < CONTEXT => {
+          let ctx = (o - ENABLE) / ENABLE_STRIDE;
+          let word = (o - ENABLE) % ENABLE_STRIDE / 4;
+          if let Some(enable) = self.enable.get_mut(ctx) {
+            let value = reg.merge(Self::bits(enable, word) as u64);
+            for i in 0..32 {
+              if let Some(bit) = enable.get_mut(word * 32 + i) {
+                *bit = (value >> i) & 1 != 0;
+              }
+            }
+            // source 0 does not exist
+            enable[0] = false;
+          }
+        }
+        o => match (
+          (o - CONTEXT) / CONTEXT_STRIDE,
+          (o - CONTEXT) % CONTEXT_STRIDE,
+        ) {
+          (ctx, 0) if ctx < contexts => {
+            self.threshold[ctx] = reg.merge(self.threshold[ctx] as u64) as u32 & PRIORITY_MASK
+          }
+          (ctx, 4) if ctx < contexts => self.complete(reg.merge(0) as usize),
+          _ => {}
+        },
+      }
+    }
+  }
+
+  fn interrupts(&self) -> u64 {
+    [MIP_MEIP, MIP_SEIP]
+      .iter()
+      .enumerate()
+      .filter(|(ctx, _)| *ctx < self.threshold.len() && self.best(*ctx).is_some())
+      .fold(0, |mip, (_, line)| mip | line)
+  }
+
+  fn set_irq_source(&mut self, source: u32, level: bool) {
+    let src = source as usize;
+    if src == 0 || src >= self.level.len() {
+      return;
+    }
+    self.level[src] = level;
+    if level && !self.in_service[src] {
+      self.pending[src] = true;
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn write(plic: &mut Plic, addr: usize, value: u32) {
+    plic.write_mem_chunk(addr, 4, None, &value.to_le_bytes());
+  }
+
+  fn read(plic: &mut Plic, addr: usize) -> u32 {
+    u32::from_le_bytes(plic.read_mem(addr, 4).try_into().unwrap())
+  }
+
+  fn claim(plic: &mut Plic, ctx: usize) -> u32 {
+    read(plic, CONTEXT + ctx * CONTEXT_STRIDE + 4)
+  }
+
+  fn complete(plic: &mut Plic, ctx: usize, src: u32) {
+    write(plic, CONTEXT + ctx * CONTEXT_STRIDE + 4, src);
+  }
+
+  /// Sources 1..=3 with priorities 1, 3, 3, all enabled for context 0
+  fn plic() -> Plic {
+    let mut plic = Plic::new(3, 2);
+    for (src, priority) in [(1, 1), (2, 3), (3, 3)] {
+      write(&mut plic, PRIORITY + src * 4, priority);
+    }
+    write(&mut plic, ENABLE, 0b1110);
+    plic
+  }
+
+  #[test]
+  fn claims_by_priority_then_lowest_id() {
+    let mut plic = plic();
+    (1..=3).for_each(|src| plic.set_irq_source(src, true));
+    assert_eq!(read(&mut plic, PENDING), 0b1110);
+    assert_eq!(plic.interrupts(), MIP_MEIP);
+    assert_eq!(claim(&mut plic, 0), 2);
+    assert_eq!(claim(&mut plic, 0), 3);
+    assert_eq!(claim(&mut plic, 0), 1);
+    assert_eq!(claim(&mut plic, 0), 0);
+    assert_eq!(plic.interrupts(), 0);
+  }
+
+  #[test]
+  fn level_source_comes_back_only_after_complete() {
+    let mut plic = plic();
+    plic.set_irq_source(2, true);
+    assert_eq!(claim(&mut plic, 0), 2);
+    // the gateway holds the still raised line while in service
+    plic.set_irq_source(2, true);
+    assert_eq!(claim(&mut plic, 0), 0);
+    complete(&mut plic, 0, 2);
+    assert_eq!(claim(&mut plic, 0), 2);
+    // lowered before completion, nothing left
+    plic.set_irq_source(2, false);
+    complete(&mut plic, 0, 2);
+    assert_eq!(claim(&mut plic, 0), 0);
+  }
+
+  #[test]
+  fn threshold_and_enable_mask_sources() {
+    let mut plic = plic();
+    plic.set_irq_source(1, true);
+    write(&mut plic, CONTEXT, 1);
+    assert_eq!(read(&mut plic, CONTEXT), 1);
+    assert_eq!(plic.interrupts(), 0);
+    assert_eq!(claim(&mut plic, 0), 0);
+    // context 1 (SEIP) has nothing enabled until now
+    write(&mut plic, ENABLE + ENABLE_STRIDE, 0b0011);
+    assert_eq!(read(&mut plic, ENABLE + ENABLE_STRIDE), 0b0010);
+    assert_eq!(plic.interrupts(), MIP_SEIP);
+    assert_eq!(claim(&mut plic, 1), 1);
+  }
+
+  #[test]
+  fn out_of_range_accesses_are_ignored() {
+    let mut plic = plic();
+    plic.set_irq_source(0, true);
+    plic.set_irq_source(9, true);
+    write(&mut plic, PRIORITY, 7);
+    assert_eq!(read(&mut plic, PRIORITY), 0);
+    assert_eq!(read(&mut plic, PENDING), 0);
+    complete(&mut plic, 0, 9);
+    assert_eq!(claim(&mut plic, 5), 0);
+    // priorities are 3 bits wide
+    write(&mut plic, PRIORITY + 4, 0xff);
+    assert_eq!(read(&mut plic, PRIORITY + 4), 7);
+  }
+}
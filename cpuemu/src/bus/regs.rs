@@ -0,0 +1,61 @@
+//! Helpers for devices made of little-endian registers of `width` bytes
+
+/// One register touched by a write, `mask` has ones over the written bytes
+pub(super) struct RegWrite {
+  /// offset of the register, aligned to the register width
+  pub offset: usize,
+  pub value: u64,
+  pub mask: u64,
+}
+
+impl RegWrite {
+  /// Merge the written bytes into the old register value
+  pub fn merge(&self, old: u64) -> u64 {
+    (old & !self.mask) | (self.value & self.mask)
+  }
+}
+
+/// Split a `write_mem_chunk` into per-register writes, skipping registers without any enabled byte
+pub(super) fn reg_writes(
+  width: usize,
+  addr: usize,
+  size: usize,
+  strobe: Option<&[bool]>,
+  data: &[u8],
+) -> Vec<RegWrite> {
+  let mut writes: Vec<RegWrite> = Vec::new();
+  for i in 0..size {
+    if strobe.is_some_and(|masks| !masks[i]) {
+      continue;
+    }
+    let offset = (addr + i) / width * width;
+    let shift = (addr + i - offset) * 8;
+    match writes.last_mut() {
+      Some(last) if last.offset == offset => {}
+      _ => writes.push(RegWrite { offset, value: 0, mask: 0 }),
+    }
+    let reg = writes.last_mut().unwrap();
+    reg.value |= (data[i] as u64) << shift;
+    reg.mask |= 0xff << shift;
+  }
+  writes
+}
+
+/// Serve a `read_mem` from registers, `read` returns the register at an aligned offset
+pub(super) fn reg_read(
+  width: usize,
+  addr: usize,
+  size: usize,
+  mut read: impl FnMut(usize) -> u64,
+) -> Vec<u8> {
+  let mut data = Vec::with_capacity(size);
+  let mut offset = addr / width * width;
+  while offset < addr + size {
+    let bytes = read(offset).to_le_bytes();
+    let lo = addr.max(offset) - offset;
+    let hi = (addr + size).min(offset + width) - offset;
+    data.extend_from_slice(&bytes[lo..hi]);
+    offset += width;
+  }
+  data
+}
@@ -0,0 +1,174 @@
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use serde::Deserialize;
+
+use super::{regs::*, ShadowDevice};
+
+// same layout as the RTC of NEMU/nexus-am, extended with the date
+const UPTIME_LO: usize = 0x0;
+const UPTIME_HI: usize = 0x4;
+const SECOND: usize = 0x8;
+const MINUTE: usize = 0xc;
+const HOUR: usize = 0x10;
+const DAY: usize = 0x14;
+const MONTH: usize = 0x18;
+const YEAR: usize = 0x1c;
+
+/// Where the RTC takes its time from
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum RtcClock {
+  /// simulation ticks, so the program sees the speed of the simulated hardware
+  #[default]
+  Sim,
+  /// the host wall clock
+  Host,
+}
+
+/// Uptime in microseconds (`AM_TIMER_UPTIME`) and the UTC date (`AM_TIMER_RTC`).
+/// Reading the low half of the uptime latches it, so the high half read next is consistent.
+/// An 8-byte region, like the one of NEMU, only holds the uptime.
+pub(super) struct Rtc {
+  clock: RtcClock,
+  /// the date registers are mapped
+  calendar: bool,
+  /// length of a simulation tick
+  tick_ns: u64,
+  tick: u64,
+  /// host time the simulation started at
+  boot: Instant,
+  boot_unix_us: u64,
+  latched_us: u64,
+}
+
+impl Rtc {
+  pub fn new(clock: RtcClock, tick_ns: u64, calendar: bool) -> Self {
+    let boot_unix_us =
+      SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_micros() as u64);
+    Self {
+      clock,
+      calendar,
+      tick_ns,
+      tick: 0,
+      boot: Instant::now(),
+      boot_unix_us,
+      latched_us: 0,
+    }
+  }
+
+  fn uptime_us(&self) -> u64 {
+    match self.clock {
+      RtcClock::Sim => self.tick * self.tick_ns / 1000,
+      RtcClock::Host => self.boot.elapsed().as_micros() as u64,
+    }
+  }
+
+  /// (year, month, day, hour, minute, second) in UTC, the date starts at the host time of boot
+  fn date(&self) -> (u64, u64, u64, u64, u64, u64) {
+    let secs = (self.boot_unix_us + self.uptime_us()) / 1_000_000;
+    let (days, rem) = (secs / 86400, secs % 86400);
+    let (year, month, day) = civil_from_days(days);
+    (year, month, day, rem / 3600, rem % 3600 / 60, rem % 60)
+  }
+}
+
+/// Gregorian date of a day count since 1970-01-01 (Howard Hinnant's algorithm)
+fn civil_from_days(days: u64) -> (u64, u64, u64) {
+  let z = days + 719468;
+  let era = z / 146097;
+  let doe = z % 146097;
+  let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+  let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+  let mp = (5 * doy + 2) / 153;
+  let day = doy - (153 * mp + 2) / 5 + 1;
+  let month = if mp < 10 { mp + 3 } else { mp - 9 };
+  let year = yoe + era * 400 + (month <= 2) as u64;
+  (year, month, day)
+}
+
+impl ShadowDevice for Rtc {
+  fn read_mem(&mut self, addr: usize, size: usize) -> Vec<u8> {
+    // any read covering the low half starts there
+    if addr == UPTIME_LO {
+      self.latched_us = self.uptime_us();
+    }
+    reg_read(4, addr, size, |offset| match offset {
+      UPTIME_LO => self.latched_us & 0xffff_ffff,
+      UPTIME_HI => self.latched_us >> 32,
+      SECOND..=YEAR if self.calendar => {
+        let (year, month, day, hour, minute, second) = self.date();
+        match offset {
+          SECOND => second,
+          MINUTE => minute,
+          HOUR => hour,
+          DAY => day,
+          MONTH => month,
+          _ => year,
+        }
+      }
+      _ => 0,
+    })
+  }
+
+  fn write_mem_chunk(
+    &mut self,
+    _addr: usize,
+    _size: usize,
+    _strobe: Option<&[bool]>,
+    _data: &[u8],
+  ) {
+    // read-only
+  }
+
+  fn tick(&mut self, tick: u64) {
+    self.tick = tick;
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn civil_from_days_handles_leap_years() {
+    assert_eq!(civil_from_days(0), (1970, 1, 1));
+    assert_eq!(civil_from_days(59), (1970, 3, 1));
+    assert_eq!(civil_from_days(789), (1972, 2, 29));
+    assert_eq!(civil_from_days(790), (1972, 3, 1));
+    // divisible by 400 is a leap year, by 100 only is not
+    assert_eq!(civil_from_days(11016), (2000, 2, 29));
+    assert_eq!(civil_from_days(11017), (2000, 3, 1));
+    assert_eq!(civil_from_days(20088), (2024, 12, 31));
+    assert_eq!(civil_from_days(47540), (2100, 2, 28));
+    assert_eq!(civil_from_days(47541), (2100, 3, 1));
+  }
+
+  fn read32(rtc: &mut Rtc, offset: usize) -> u64 {
+    u32::from_le_bytes(rtc.read_mem(offset, 4).try_into().unwrap()) as u64
+  }
+
+  #[test]
+  fn uptime_is_latched_by_the_low_half() {
+    // a microsecond per tick
+    let mut rtc = Rtc::new(RtcClock::Sim, 1000, false);
+    rtc.tick(0xffff_fff0);
+    assert_eq!(read32(&mut rtc, UPTIME_LO), 0xffff_fff0);
+    rtc.tick(0x1_0000_0010);
+    assert_eq!(read32(&mut rtc, UPTIME_HI), 0);
+    assert_eq!(read32(&mut rtc, UPTIME_LO), 0x10);
+    assert_eq!(read32(&mut rtc, UPTIME_HI), 1);
+    // both halves at once
+    rtc.tick(0x2_0000_0020);
+    assert_eq!(rtc.read_mem(UPTIME_LO, 8), 0x2_0000_0020u64.to_le_bytes());
+  }
+
+  #[test]
+  fn date_advances_with_the_simulation() {
+    let mut rtc = Rtc::new(RtcClock::Sim, 1_000_000, true);
+    // 2000-02-28 23:59:58
+    rtc.boot_unix_us = (11015 * 86400 + 86398) * 1_000_000;
+    assert_eq!(rtc.date(), (2000, 2, 28, 23, 59, 58));
+    rtc.tick(2000);
+    assert_eq!(rtc.date(), (2000, 2, 29, 0, 0, 0));
+  }
+}
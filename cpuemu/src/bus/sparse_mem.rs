@@ -0,0 +1,106 @@
+use std::collections::HashMap;
+
+use super::ShadowDevice;
+
+const PAGE_SIZE: usize = 4096;
+
+/// RAM whose 4 KiB pages are only allocated on first write,
+/// so GiB-sized DRAM regions cost nothing until the program touches them.
+pub(super) struct SparseMemDevice {
+  /// value returned for bytes of pages never written
+  fill: u8,
+  pages: HashMap<usize, Box<[u8; PAGE_SIZE]>>,
+}
+
+impl SparseMemDevice {
+  pub fn new(fill: u8) -> Self {
+    Self { fill, pages: HashMap::new() }
+  }
+
+  fn page_mut(&mut self, page: usize) -> &mut [u8; PAGE_SIZE] {
+    let fill = self.fill;
+    self.pages.entry(page).or_insert_with(|| Box::new([fill; PAGE_SIZE]))
+  }
+}
+
+impl ShadowDevice for SparseMemDevice {
+  fn read_mem(&mut self, addr: usize, size: usize) -> Vec<u8> {
+    let mut data = Vec::with_capacity(size);
+    let mut addr = addr;
+    let end = addr + size;
+    while addr < end {
+      let (page, offset) = (addr / PAGE_SIZE, addr % PAGE_SIZE);
+      let len = (PAGE_SIZE - offset).min(end - addr);
+      match self.pages.get(&page) {
+        Some(mem) => data.extend_from_slice(&mem[offset..offset + len]),
+        None => data.resize(data.len() + len, self.fill),
+      }
+      addr += len;
+    }
+    data
+  }
+
+  fn write_mem_chunk(&mut self, addr: usize, size: usize, strobe: Option<&[bool]>, data: &[u8]) {
+    if let Some(masks) = strobe {
+      masks.iter().enumerate().filter(|(_, mask)| **mask).for_each(|(i, _)| {
+        let addr = addr + i;
+        self.page_mut(addr / PAGE_SIZE)[addr % PAGE_SIZE] = data[i];
+      })
+    } else {
+      let mut done = 0;
+      while done < size {
+        let addr = addr + done;
+        let (page, offset) = (addr / PAGE_SIZE, addr % PAGE_SIZE);
+        let len = (PAGE_SIZE - offset).min(size - done);
+        self.page_mut(page)[offset..offset + len].copy_from_slice(&data[done..done + len]);
+        done += len;
+      }
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn untouched_pages_read_the_fill_value() {
+    let dev = &mut SparseMemDevice::new(0xa5);
+    assert_eq!(dev.read_mem(0x1234, 8), [0xa5; 8]);
+    assert_eq!(dev.read_mem(PAGE_SIZE - 4, 8), [0xa5; 8]);
+    assert!(dev.pages.is_empty());
+  }
+
+  #[test]
+  fn write_across_a_page_boundary() {
+    let dev = &mut SparseMemDevice::new(0);
+    let data: Vec<u8> = (1..=8).collect();
+    dev.write_mem_chunk(PAGE_SIZE - 3, 8, None, &data);
+    assert_eq!(dev.pages.len(), 2);
+    assert_eq!(dev.read_mem(PAGE_SIZE - 3, 8), data);
+    // the rest of both pages still holds the fill value
+    assert_eq!(dev.read_mem(PAGE_SIZE - 8, 5), [0; 5]);
+    assert_eq!(dev.read_mem(PAGE_SIZE + 5, 8), [0; 8]);
+  }
+
+  #[test]
+  fn pages_are_allocated_on_first_write_only() {
+    let dev = &mut SparseMemDevice::new(0xff);
+    dev.read_mem(0x10_0000, PAGE_SIZE);
+    assert!(dev.pages.is_empty());
+    dev.write_mem_chunk(0x10_0008, 8, None, &[0; 8]);
+    let strobe = [true, false, false, false, false, false, false, true];
+    dev.write_mem_chunk(0x10_0010, 8, Some(&strobe), &[1; 8]);
+    assert_eq!(dev.pages.len(), 1);
+    // no byte enabled, nothing to allocate
+    dev.write_mem_chunk(0x10_1000, 8, Some(&[false; 8]), &[1; 8]);
+    assert_eq!(dev.pages.len(), 1);
+    dev.write_mem_chunk(0x10_1000, 8, None, &[2; 8]);
+    assert_eq!(dev.pages.len(), 2);
+    assert_eq!(dev.read_mem(0x10_0008, 8), [0; 8]);
+    assert_eq!(
+      dev.read_mem(0x10_0010, 8),
+      [1, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 1]
+    );
+  }
+}
@@ -0,0 +1,146 @@
+use tracing::info;
+
+use super::{DramConfig, TimingConfig};
+
+/// Response timing of one region: fixed latency, optional DRAM row buffers,
+/// and a bandwidth limit that makes back-to-back bursts queue up.
+pub(super) struct Timing {
+  latency: u64,
+  bandwidth: Option<u64>,
+  dram: Option<DramConfig>,
+  /// open row of each bank
+  open_rows: Vec<Option<usize>>,
+  /// first tick the region is free to move data again
+  busy_until: u64,
+
+  accesses: u64,
+  row_hits: u64,
+  total_delay: u64,
+}
+
+impl Timing {
+  pub fn new(config: &TimingConfig) -> anyhow::Result<Self> {
+    if config.bandwidth == Some(0) {
+      anyhow::bail!("bandwidth must be at least 1 byte per tick");
+    }
+    if let Some(dram) = &config.dram {
+      if dram.row_size == 0 || dram.banks == 0 {
+        anyhow::bail!("dram needs a non-zero row_size and banks");
+      }
+    }
+    Ok(Self {
+      latency: config.latency,
+      bandwidth: config.bandwidth,
+      dram: config.dram.clone(),
+      open_rows: vec![None; config.dram.as_ref().map_or(0, |dram| dram.banks)],
+      busy_until: 0,
+      accesses: 0,
+      row_hits: 0,
+      total_delay: 0,
+    })
+  }
+
+  /// A burst of `bytes` at `offset` arrives at `tick`, returns the ticks until its response
+  pub fn access(&mut self, offset: usize, bytes: u64, tick: u64) -> u64 {
+    let mut latency = self.latency;
+    if let Some(dram) = &self.dram {
+      let row = offset / dram.row_size;
+      let bank = row % dram.banks;
+      if self.open_rows[bank] == Some(row) {
+        self.row_hits += 1;
+        latency += dram.row_hit;
+      } else {
+        self.open_rows[bank] = Some(row);
+        latency += dram.row_miss;
+      }
+    }
+
+    let mut start = tick;
+    if let Some(bandwidth) = self.bandwidth {
+      // wait for the bursts in flight, then take the data path for our transfer
+      start = tick.max(self.busy_until);
+      self.busy_until = start + bytes.div_ceil(bandwidth);
+      latency += bytes.div_ceil(bandwidth);
+    }
+
+    let delay = start - tick + latency;
+    self.accesses += 1;
+    self.total_delay += delay;
+    delay
+  }
+
+  pub fn summary(&self, name: &str) {
+    if self.accesses == 0 {
+      return;
+    }
+    let avg = self.total_delay as f64 / self.accesses as f64;
+    if self.dram.is_some() {
+      let hit_rate = self.row_hits as f64 / self.accesses as f64;
+      info!(
+        "{name}: {} accesses, {avg:.2} ticks average latency, {:.1}% row hits",
+        self.accesses,
+        hit_rate * 100.0
+      );
+    } else {
+      info!(
+        "{name}: {} accesses, {avg:.2} ticks average latency",
+        self.accesses
+      );
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn dram() -> DramConfig {
+    DramConfig {
+      row_size: 2048,
+      banks: 8,
+      row_hit: 10,
+      row_miss: 30,
+    }
+  }
+
+  #[test]
+  fn row_hits_are_faster_than_misses() {
+    let config = TimingConfig { latency: 4, bandwidth: None, dram: Some(dram()) };
+    let mut timing = Timing::new(&config).unwrap();
+    assert_eq!(timing.access(0x0, 8, 100), 4 + 30);
+    assert_eq!(timing.access(0x8, 8, 200), 4 + 10);
+    assert_eq!(timing.access(0x7f8, 8, 300), 4 + 10);
+    // the next row lives in the next bank, bank 0 keeps row 0 open
+    assert_eq!(timing.access(0x800, 8, 400), 4 + 30);
+    assert_eq!(timing.access(0x0, 8, 500), 4 + 10);
+    // row 8 wraps around to bank 0 and closes row 0
+    assert_eq!(timing.access(0x4000, 8, 600), 4 + 30);
+    assert_eq!(timing.access(0x0, 8, 700), 4 + 30);
+    assert_eq!((timing.accesses, timing.row_hits), (7, 3));
+  }
+
+  #[test]
+  fn back_to_back_bursts_queue_up_on_the_bandwidth() {
+    let config = TimingConfig { latency: 2, bandwidth: Some(8), dram: None };
+    let mut timing = Timing::new(&config).unwrap();
+    // 64 bytes at 8 bytes per tick take 8 ticks on the data path
+    assert_eq!(timing.access(0x0, 64, 10), 2 + 8);
+    assert_eq!(timing.access(0x40, 64, 10), 8 + 2 + 8);
+    assert_eq!(timing.access(0x80, 64, 12), 14 + 2 + 8);
+    // partial beats round up, an idle region does not queue
+    assert_eq!(timing.access(0x0, 4, 100), 2 + 1);
+    assert_eq!(timing.access(0x0, 12, 200), 2 + 2);
+  }
+
+  #[test]
+  fn bad_configs_are_rejected() {
+    let config = TimingConfig { latency: 0, bandwidth: Some(0), dram: None };
+    assert!(Timing::new(&config).is_err());
+    let config = TimingConfig {
+      latency: 0,
+      bandwidth: None,
+      dram: Some(DramConfig { banks: 0, ..dram() }),
+    };
+    assert!(Timing::new(&config).is_err());
+  }
+}
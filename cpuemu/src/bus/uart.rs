@@ -1,50 +1,226 @@
-use super::ShadowDevice;
-
-pub(super) struct Uart<const SIZE: usize> {
-  regs: Box<[u8; SIZE]>,
-  // 0x0: rxfifo
-  // 0x4: txfifo
-  // 0x8: stat
-  // 0xc: ctrl
+use std::collections::VecDeque;
+
+use super::{
+  console::{push_lossy, HostConsole},
+  regs::*,
+  ShadowDevice,
+};
+
+// Xilinx AXI UART-Lite (PG142)
+const RX_FIFO: usize = 0x0;
+const TX_FIFO: usize = 0x4;
+const STAT: usize = 0x8;
+const CTRL: usize = 0xc;
+
+const STAT_RX_VALID: u64 = 1 << 0;
+const STAT_RX_FULL: u64 = 1 << 1;
+const STAT_TX_EMPTY: u64 = 1 << 2;
+const STAT_TX_FULL: u64 = 1 << 3;
+const STAT_INTR_ENABLED: u64 = 1 << 4;
+
+const CTRL_RST_TX_FIFO: u64 = 1 << 0;
+const CTRL_RST_RX_FIFO: u64 = 1 << 1;
+const CTRL_ENABLE_INTR: u64 = 1 << 4;
+
+const FIFO_DEPTH: usize = 16;
+
+pub(super) struct Uart {
+  console: HostConsole,
+  rx: VecDeque<u8>,
+  tx: VecDeque<u8>,
+  intr_enabled: bool,
+
+  /// rx became non-empty or tx became empty since the last tick
+  intr_event: bool,
+  /// the interrupt pulse, one tick long
+  intr: bool,
 }
 
-impl<const SIZE: usize> ShadowDevice for Uart<SIZE> {
-  fn new() -> Box<dyn ShadowDevice>
-  where
-    Self: Sized,
-  {
-    Box::new(Self { regs: vec![0u8; SIZE].try_into().unwrap() })
+impl Uart {
+  pub fn new(console: HostConsole) -> Self {
+    Self {
+      console,
+      rx: VecDeque::with_capacity(FIFO_DEPTH),
+      tx: VecDeque::with_capacity(FIFO_DEPTH),
+      intr_enabled: false,
+      intr_event: false,
+      intr: false,
+    }
+  }
+
+  /// Move what the host has typed into rxfifo
+  fn poll_rx(&mut self) {
+    if self.console.poll_into(&mut self.rx, FIFO_DEPTH) {
+      self.intr_event = true;
+    }
   }
 
-  fn read_mem(&self, addr: usize, size: usize) -> Vec<u8> {
-    //TODO: 从键盘读取rxfifo
-    let start = addr;
-    let end = addr + size;
-    self.regs[start..end].to_vec()
+  fn stat(&self) -> u64 {
+    let mut stat = 0;
+    if !self.rx.is_empty() {
+      stat |= STAT_RX_VALID;
+    }
+    if self.rx.len() == FIFO_DEPTH {
+      stat |= STAT_RX_FULL;
+    }
+    if self.tx.is_empty() {
+      stat |= STAT_TX_EMPTY;
+    }
+    if self.tx.len() == FIFO_DEPTH {
+      stat |= STAT_TX_FULL;
+    }
+    if self.intr_enabled {
+      stat |= STAT_INTR_ENABLED;
+    }
+    stat
   }
+}
 
-  //fn write_mem(&mut self, addr: usize, data: u8) {
-  //  self.mem[addr] = data;
-  //}
+impl ShadowDevice for Uart {
+  fn read_mem(&mut self, addr: usize, size: usize) -> Vec<u8> {
+    self.poll_rx();
+    reg_read(4, addr, size, |offset| match offset {
+      RX_FIFO => self.rx.pop_front().unwrap_or(0) as u64,
+      STAT => self.stat(),
+      // txfifo and ctrl are write-only
+      _ => 0,
+    })
+  }
 
   fn write_mem_chunk(&mut self, addr: usize, size: usize, strobe: Option<&[bool]>, data: &[u8]) {
     // NOTE: addr & size alignment check already done in ShadowBus, and ELF load can be unaligned anyway.
-    if let Some(masks) = strobe {
-      masks.iter().enumerate().for_each(|(i, mask)| {
-        if *mask {
-          self.regs[addr + i] = data[i];
+    for reg in reg_writes(4, addr, size, strobe, data) {
+      let value = reg.merge(0);
+      match reg.offset {
+        TX_FIFO => push_lossy(&mut self.tx, value as u8, FIFO_DEPTH),
+        CTRL => {
+          if value & CTRL_RST_TX_FIFO != 0 {
+            self.tx.clear();
+          }
+          if value & CTRL_RST_RX_FIFO != 0 {
+            self.rx.clear();
+          }
+          self.intr_enabled = value & CTRL_ENABLE_INTR != 0;
         }
-      })
-    } else {
-      let start = addr;
-      let end = addr + size;
-      self.regs[start..end].copy_from_slice(data);
+        _ => {}
+      }
     }
+  }
 
-    //printf txfifo
-    if self.regs[0x4] != 0x0 {
-      print!("{}", self.regs[0x4] as char);
+  fn tick(&mut self, tick: u64) {
+    // send one byte per tick, the interrupt fires when txfifo drains
+    if let Some(byte) = self.tx.pop_front() {
+      self.console.write_byte(byte);
+      if self.tx.is_empty() {
+        self.intr_event = true;
+      }
+    }
+    if self.console.poll_on_tick(tick, &mut self.rx, FIFO_DEPTH) {
+      self.intr_event = true;
     }
-    self.regs[0x4] = 0x0;
+
+    self.intr = self.intr_enabled && std::mem::take(&mut self.intr_event);
+  }
+
+  fn irq_level(&self) -> bool {
+    self.intr
+  }
+
+  fn flush(&mut self) {
+    self.tx.drain(..).for_each(|byte| self.console.write_byte(byte));
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::io::Write;
+
+  use super::*;
+  use crate::bus::console::RX_POLL_INTERVAL;
+
+  fn read(uart: &mut Uart, offset: usize) -> u64 {
+    u32::from_le_bytes(uart.read_mem(offset, 4).try_into().unwrap()) as u64
+  }
+
+  fn write(uart: &mut Uart, offset: usize, value: u64) {
+    uart.write_mem_chunk(offset, 4, None, &(value as u32).to_le_bytes());
+  }
+
+  #[test]
+  fn rx_valid_follows_the_host_input() {
+    let (console, mut host) = HostConsole::piped();
+    let mut uart = Uart::new(console);
+    assert_eq!(read(&mut uart, STAT) & STAT_RX_VALID, 0);
+    host.write_all(b"ok").unwrap();
+    assert_ne!(read(&mut uart, STAT) & STAT_RX_VALID, 0);
+    assert_eq!(read(&mut uart, RX_FIFO), b'o' as u64);
+    assert_eq!(read(&mut uart, RX_FIFO), b'k' as u64);
+    assert_eq!(read(&mut uart, STAT) & STAT_RX_VALID, 0);
+    // an empty rxfifo reads as zero
+    assert_eq!(read(&mut uart, RX_FIFO), 0);
+  }
+
+  #[test]
+  fn rx_fifo_holds_16_bytes() {
+    let (console, mut host) = HostConsole::piped();
+    let mut uart = Uart::new(console);
+    host.write_all(&[b'x'; FIFO_DEPTH + 4]).unwrap();
+    assert_ne!(read(&mut uart, STAT) & STAT_RX_FULL, 0);
+    assert_eq!(uart.rx.len(), FIFO_DEPTH);
+    // the rest stays with the host until the next access finds room
+    read(&mut uart, RX_FIFO);
+    assert_eq!(uart.rx.len(), FIFO_DEPTH - 1);
+    read(&mut uart, STAT);
+    assert_eq!(uart.rx.len(), FIFO_DEPTH);
+  }
+
+  #[test]
+  fn tx_full_drops_further_writes() {
+    let (console, _host) = HostConsole::piped();
+    let mut uart = Uart::new(console);
+    assert_eq!(
+      read(&mut uart, STAT) & (STAT_TX_EMPTY | STAT_TX_FULL),
+      STAT_TX_EMPTY
+    );
+    for _ in 0..=FIFO_DEPTH {
+      write(&mut uart, TX_FIFO, b'.' as u64);
+    }
+    assert_eq!(uart.tx.len(), FIFO_DEPTH);
+    assert_eq!(
+      read(&mut uart, STAT) & (STAT_TX_EMPTY | STAT_TX_FULL),
+      STAT_TX_FULL
+    );
+    // one byte leaves per tick
+    uart.tick(1);
+    assert_eq!(read(&mut uart, STAT) & (STAT_TX_EMPTY | STAT_TX_FULL), 0);
+  }
+
+  #[test]
+  fn ctrl_resets_the_fifos() {
+    let (console, mut host) = HostConsole::piped();
+    let mut uart = Uart::new(console);
+    host.write_all(b"abc").unwrap();
+    write(&mut uart, TX_FIFO, b'.' as u64);
+    assert_eq!(
+      read(&mut uart, STAT) & (STAT_RX_VALID | STAT_TX_EMPTY),
+      STAT_RX_VALID
+    );
+    write(&mut uart, CTRL, CTRL_RST_RX_FIFO);
+    assert!(uart.rx.is_empty());
+    assert_eq!(uart.tx.len(), 1);
+    write(&mut uart, CTRL, CTRL_RST_TX_FIFO | CTRL_ENABLE_INTR);
+    assert_eq!(read(&mut uart, STAT), STAT_TX_EMPTY | STAT_INTR_ENABLED);
+  }
+
+  #[test]
+  fn interrupt_pulses_on_rx_arrival() {
+    let (console, mut host) = HostConsole::piped();
+    let mut uart = Uart::new(console);
+    write(&mut uart, CTRL, CTRL_ENABLE_INTR);
+    host.write_all(b"a").unwrap();
+    uart.tick(RX_POLL_INTERVAL);
+    assert!(uart.irq_level());
+    uart.tick(RX_POLL_INTERVAL + 1);
+    assert!(!uart.irq_level());
   }
 }
@@ -0,0 +1,345 @@
+use std::collections::VecDeque;
+
+use super::{
+  console::{push_lossy, HostConsole},
+  regs::*,
+  ShadowDevice,
+};
+
+// NS16550A register indexes, the byte offset is `index * stride`
+const RBR_THR_DLL: usize = 0;
+const IER_DLM: usize = 1;
+const IIR_FCR: usize = 2;
+const LCR: usize = 3;
+const MCR: usize = 4;
+const LSR: usize = 5;
+const MSR: usize = 6;
+const SCR: usize = 7;
+
+const IER_RDA: u8 = 1 << 0;
+const IER_THRE: u8 = 1 << 1;
+const IER_MASK: u8 = 0x0f;
+
+const IIR_NO_INTR: u8 = 0x01;
+const IIR_THRE: u8 = 0x02;
+const IIR_RDA: u8 = 0x04;
+const IIR_FIFO_ENABLED: u8 = 0xc0;
+
+const FCR_FIFO_ENABLE: u8 = 1 << 0;
+const FCR_RX_RESET: u8 = 1 << 1;
+const FCR_TX_RESET: u8 = 1 << 2;
+
+const LCR_DLAB: u8 = 1 << 7;
+
+const LSR_DR: u8 = 1 << 0;
+const LSR_THRE: u8 = 1 << 5;
+const LSR_TEMT: u8 = 1 << 6;
+
+/// modem lines always report carrier, data set ready and clear to send
+const MSR_LINES_UP: u8 = 0xb0;
+
+const FIFO_DEPTH: usize = 16;
+
+/// 16550-compatible UART, as found on most RISC-V boards and expected by Linux/OpenSBI.
+/// Line settings and the divisor are stored but otherwise ignored.
+pub(super) struct Uart16550 {
+  console: HostConsole,
+  /// byte distance between two registers (the device tree `reg-shift`)
+  stride: usize,
+  rx: VecDeque<u8>,
+  tx: VecDeque<u8>,
+
+  ier: u8,
+  fcr: u8,
+  lcr: u8,
+  mcr: u8,
+  scr: u8,
+  divisor: u16,
+  /// the THR empty interrupt, cleared by reading IIR or writing THR
+  thre_pending: bool,
+}
+
+impl Uart16550 {
+  pub fn new(console: HostConsole, stride: usize) -> Self {
+    Self {
+      console,
+      stride,
+      rx: VecDeque::with_capacity(FIFO_DEPTH),
+      tx: VecDeque::with_capacity(FIFO_DEPTH),
+      ier: 0,
+      fcr: 0,
+      lcr: 0,
+      mcr: 0,
+      scr: 0,
+      divisor: 0,
+      thre_pending: false,
+    }
+  }
+
+  fn depth(&self) -> usize {
+    if self.fcr & FCR_FIFO_ENABLE != 0 {
+      FIFO_DEPTH
+    } else {
+      1
+    }
+  }
+
+  fn poll_rx(&mut self) {
+    let depth = self.depth();
+    self.console.poll_into(&mut self.rx, depth);
+  }
+
+  fn dlab(&self) -> bool {
+    self.lcr & LCR_DLAB != 0
+  }
+
+  /// Highest priority interrupt identification, without the FIFO bits
+  fn iir(&self) -> u8 {
+    if self.ier & IER_RDA != 0 && !self.rx.is_empty() {
+      IIR_RDA
+    } else if self.ier & IER_THRE != 0 && self.thre_pending {
+      IIR_THRE
+    } else {
+      IIR_NO_INTR
+    }
+  }
+
+  fn lsr(&self) -> u8 {
+    let mut lsr = 0;
+    if !self.rx.is_empty() {
+      lsr |= LSR_DR;
+    }
+    if self.tx.is_empty() {
+      lsr |= LSR_THRE | LSR_TEMT;
+    }
+    lsr
+  }
+
+  fn read_reg(&mut self, index: usize) -> u8 {
+    match index {
+      RBR_THR_DLL if self.dlab() => self.divisor as u8,
+      RBR_THR_DLL => self.rx.pop_front().unwrap_or(0),
+      IER_DLM if self.dlab() => (self.divisor >> 8) as u8,
+      IER_DLM => self.ier,
+      IIR_FCR => {
+        let iir = self.iir();
+        if iir == IIR_THRE {
+          self.thre_pending = false;
+        }
+        if self.fcr & FCR_FIFO_ENABLE != 0 {
+          iir | IIR_FIFO_ENABLED
+        } else {
+          iir
+        }
+      }
+      LCR => self.lcr,
+      MCR => self.mcr,
+      LSR => self.lsr(),
+      MSR => MSR_LINES_UP,
+      SCR => self.scr,
+      _ => 0,
+    }
+  }
+
+  fn write_reg(&mut self, index: usize, value: u8) {
+    match index {
+      RBR_THR_DLL if self.dlab() => self.divisor = self.divisor & 0xff00 | value as u16,
+      RBR_THR_DLL => {
+        let depth = self.depth();
+        push_lossy(&mut self.tx, value, depth);
+        self.thre_pending = false;
+      }
+      IER_DLM if self.dlab() => self.divisor = self.divisor & 0x00ff | (value as u16) << 8,
+      IER_DLM => {
+        // enabling the THR empty interrupt while THR is empty raises it right away
+        if value & IER_THRE != 0 && self.ier & IER_THRE == 0 && self.tx.is_empty() {
+          self.thre_pending = true;
+        }
+        self.ier = value & IER_MASK;
+      }
+      IIR_FCR => {
+        if value & FCR_RX_RESET != 0 {
+          self.rx.clear();
+        }
+        if value & FCR_TX_RESET != 0 {
+          self.tx.clear();
+        }
+        self.fcr = value & !(FCR_RX_RESET | FCR_TX_RESET);
+        let depth = self.depth();
+        self.rx.truncate(depth);
+        self.tx.truncate(depth);
+      }
+      LCR => self.lcr = value,
+      MCR => self.mcr = value,
+      SCR => self.scr = value,
+      // LSR and MSR are read-only
+      _ => {}
+    }
+  }
+}
+
+impl ShadowDevice for Uart16550 {
+  fn read_mem(&mut self, addr: usize, size: usize) -> Vec<u8> {
+    self.poll_rx();
+    let stride = self.stride;
+    reg_read(stride, addr, size, |offset| {
+      self.read_reg(offset / stride) as u64
+    })
+  }
+
+  fn write_mem_chunk(&mut self, addr: usize, size: usize, strobe: Option<&[bool]>, data: &[u8]) {
+    for reg in reg_writes(self.stride, addr, size, strobe, data) {
+      // only the low byte of a wide register is implemented
+      if reg.mask & 0xff != 0 {
+        self.write_reg(reg.offset / self.stride, reg.value as u8);
+      }
+    }
+  }
+
+  fn tick(&mut self, tick: u64) {
+    // send one byte per tick
+    if let Some(byte) = self.tx.pop_front() {
+      self.console.write_byte(byte);
+      if self.tx.is_empty() {
+        self.thre_pending = true;
+      }
+    }
+    let depth = self.depth();
+    self.console.poll_on_tick(tick, &mut self.rx, depth);
+  }
+
+  fn irq_level(&self) -> bool {
+    self.iir() != IIR_NO_INTR
+  }
+
+  fn flush(&mut self) {
+    self.tx.drain(..).for_each(|byte| self.console.write_byte(byte));
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::io::Write;
+
+  use super::*;
+
+  fn read(uart: &mut Uart16550, index: usize) -> u8 {
+    uart.read_mem(index, 1)[0]
+  }
+
+  fn write(uart: &mut Uart16550, index: usize, value: u8) {
+    uart.write_mem_chunk(index, 1, None, &[value]);
+  }
+
+  #[test]
+  fn iir_reports_the_highest_priority_interrupt() {
+    let (console, mut host) = HostConsole::piped();
+    let mut uart = Uart16550::new(console, 1);
+    assert_eq!(read(&mut uart, IIR_FCR), IIR_NO_INTR);
+    // enabling THRE while THR is empty raises it at once, reading IIR clears it
+    write(&mut uart, IER_DLM, IER_THRE);
+    assert_eq!(read(&mut uart, IIR_FCR), IIR_THRE);
+    assert_eq!(read(&mut uart, IIR_FCR), IIR_NO_INTR);
+
+    write(&mut uart, IER_DLM, IER_RDA | IER_THRE);
+    write(&mut uart, RBR_THR_DLL, b'a');
+    uart.tick(1);
+    host.write_all(b"z").unwrap();
+    // received data goes before THR empty
+    assert_eq!(read(&mut uart, IIR_FCR), IIR_RDA);
+    assert!(uart.irq_level());
+    assert_eq!(read(&mut uart, RBR_THR_DLL), b'z');
+    assert_eq!(read(&mut uart, IIR_FCR), IIR_THRE);
+    assert_eq!(read(&mut uart, IIR_FCR), IIR_NO_INTR);
+    assert!(!uart.irq_level());
+  }
+
+  #[test]
+  fn writing_thr_clears_thre() {
+    let (console, _host) = HostConsole::piped();
+    let mut uart = Uart16550::new(console, 1);
+    write(&mut uart, IER_DLM, IER_THRE);
+    write(&mut uart, RBR_THR_DLL, b'a');
+    assert_eq!(read(&mut uart, IIR_FCR), IIR_NO_INTR);
+    uart.tick(1);
+    assert_eq!(read(&mut uart, IIR_FCR), IIR_THRE);
+  }
+
+  #[test]
+  fn dlab_switches_to_the_divisor_latches() {
+    let (console, _host) = HostConsole::piped();
+    let mut uart = Uart16550::new(console, 1);
+    write(&mut uart, LCR, LCR_DLAB | 0x03);
+    write(&mut uart, RBR_THR_DLL, 0x34);
+    write(&mut uart, IER_DLM, 0x12);
+    assert_eq!(uart.divisor, 0x1234);
+    assert_eq!(read(&mut uart, RBR_THR_DLL), 0x34);
+    assert_eq!(read(&mut uart, IER_DLM), 0x12);
+    // neither THR nor IER saw the writes
+    assert!(uart.tx.is_empty());
+    assert_eq!(uart.ier, 0);
+
+    write(&mut uart, LCR, 0x03);
+    write(&mut uart, IER_DLM, IER_RDA);
+    write(&mut uart, RBR_THR_DLL, b'x');
+    assert_eq!(read(&mut uart, IER_DLM), IER_RDA);
+    assert_eq!(uart.tx, [b'x']);
+    assert_eq!(uart.divisor, 0x1234);
+  }
+
+  #[test]
+  fn fcr_resets_the_fifos() {
+    let (console, mut host) = HostConsole::piped();
+    let mut uart = Uart16550::new(console, 1);
+    write(&mut uart, IIR_FCR, FCR_FIFO_ENABLE);
+    assert_eq!(read(&mut uart, IIR_FCR), IIR_NO_INTR | IIR_FIFO_ENABLED);
+    host.write_all(b"abcd").unwrap();
+    write(&mut uart, RBR_THR_DLL, 1);
+    write(&mut uart, RBR_THR_DLL, 2);
+    assert_eq!(read(&mut uart, LSR) & LSR_DR, LSR_DR);
+    assert_eq!((uart.rx.len(), uart.tx.len()), (4, 2));
+
+    write(&mut uart, IIR_FCR, FCR_FIFO_ENABLE | FCR_RX_RESET);
+    assert_eq!((uart.rx.len(), uart.tx.len()), (0, 2));
+    write(&mut uart, IIR_FCR, FCR_FIFO_ENABLE | FCR_TX_RESET);
+    assert!(uart.tx.is_empty());
+    // the reset bits clear themselves
+    assert_eq!(uart.fcr, FCR_FIFO_ENABLE);
+  }
+
+  #[test]
+  fn without_fifo_only_one_byte_is_held() {
+    let (console, mut host) = HostConsole::piped();
+    let mut uart = Uart16550::new(console, 1);
+    host.write_all(b"ab").unwrap();
+    write(&mut uart, RBR_THR_DLL, 1);
+    write(&mut uart, RBR_THR_DLL, 2);
+    assert_eq!(read(&mut uart, RBR_THR_DLL), b'a');
+    assert_eq!(uart.tx, [1]);
+  }
+
+  #[test]
+  fn lsr_tracks_data_ready_and_thr_empty() {
+    let (console, mut host) = HostConsole::piped();
+    let mut uart = Uart16550::new(console, 1);
+    assert_eq!(read(&mut uart, LSR), LSR_THRE | LSR_TEMT);
+    write(&mut uart, RBR_THR_DLL, b'.');
+    assert_eq!(read(&mut uart, LSR), 0);
+    uart.tick(1);
+    assert_eq!(read(&mut uart, LSR), LSR_THRE | LSR_TEMT);
+    host.write_all(b"x").unwrap();
+    assert_eq!(read(&mut uart, LSR), LSR_DR | LSR_THRE | LSR_TEMT);
+    read(&mut uart, RBR_THR_DLL);
+    assert_eq!(read(&mut uart, LSR), LSR_THRE | LSR_TEMT);
+  }
+
+  #[test]
+  fn stride_spreads_the_registers() {
+    let (console, _host) = HostConsole::piped();
+    let mut uart = Uart16550::new(console, 4);
+    uart.write_mem_chunk(SCR * 4, 4, None, &[0x5a, 0xff, 0xff, 0xff]);
+    assert_eq!(uart.scr, 0x5a);
+    assert_eq!(uart.read_mem(SCR * 4, 4), [0x5a, 0, 0, 0]);
+    assert_eq!(uart.read_mem(MSR * 4, 1), [MSR_LINES_UP]);
+  }
+}
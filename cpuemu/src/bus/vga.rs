@@ -0,0 +1,338 @@
+use std::{
+  collections::VecDeque,
+  fs,
+  path::PathBuf,
+  sync::{Arc, Mutex},
+};
+
+use anyhow::Context;
+use serde::Deserialize;
+use tracing::{debug, warn};
+
+use super::{regs::*, ShadowDevice};
+
+// same layout as the VGA controller of NEMU/nexus-am
+const SIZE: usize = 0x0;
+const SYNC: usize = 0x4;
+
+/// Layout of one pixel in the framebuffer
+#[derive(Debug, Default, Clone, Copy, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum PixelFormat {
+  /// 32-bit `0x00RRGGBB`, as nexus-am draws
+  #[default]
+  Xrgb8888,
+  /// 16-bit `RRRRRGGGGGGBBBBB`
+  Rgb565,
+}
+
+impl PixelFormat {
+  fn bytes(self) -> usize {
+    match self {
+      PixelFormat::Xrgb8888 => 4,
+      PixelFormat::Rgb565 => 2,
+    }
+  }
+
+  fn rgb(self, pixel: &[u8]) -> [u8; 3] {
+    match self {
+      PixelFormat::Xrgb8888 => [pixel[2], pixel[1], pixel[0]],
+      PixelFormat::Rgb565 => {
+        let v = u16::from_le_bytes([pixel[0], pixel[1]]);
+        let (r, g, b) = ((v >> 11) as u8, (v >> 5) as u8 & 0x3f, v as u8 & 0x1f);
+        [r << 3 | r >> 2, g << 2 | g >> 4, b << 3 | b >> 2]
+      }
+    }
+  }
+}
+
+/// File format of the dumped frames
+#[derive(Debug, Default, Clone, Copy, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum FrameFormat {
+  #[default]
+  Ppm,
+  Png,
+}
+
+/// Where and how the frames are saved
+pub(super) struct FrameDump {
+  pub dir: PathBuf,
+  pub format: FrameFormat,
+  /// keep only the files of the last `keep` frames, 0 = keep all
+  pub keep: usize,
+}
+
+struct Screen {
+  name: String,
+  width: usize,
+  height: usize,
+  format: PixelFormat,
+  pixels: Box<[u8]>,
+
+  dump: FrameDump,
+  frames: u64,
+  /// files written, oldest first
+  written: VecDeque<PathBuf>,
+}
+
+impl Screen {
+  fn save_frame(&mut self) -> anyhow::Result<()> {
+    let rgb: Vec<u8> =
+      self.pixels.chunks(self.format.bytes()).flat_map(|pixel| self.format.rgb(pixel)).collect();
+    let (ext, bytes) = match self.dump.format {
+      FrameFormat::Ppm => ("ppm", encode_ppm(self.width, self.height, &rgb)),
+      FrameFormat::Png => ("png", encode_png(self.width, self.height, &rgb)),
+    };
+    let path = self.dump.dir.join(format!("{}-{:06}.{ext}", self.name, self.frames));
+    fs::write(&path, bytes).with_context(|| format!("writing {}", path.display()))?;
+    debug!(
+      "{}: frame {} saved to {}",
+      self.name,
+      self.frames,
+      path.display()
+    );
+    self.frames += 1;
+
+    self.written.push_back(path);
+    if self.dump.keep != 0 && self.written.len() > self.dump.keep {
+      let old = self.written.pop_front().unwrap();
+      fs::remove_file(&old).with_context(|| format!("removing {}", old.display()))?;
+    }
+    Ok(())
+  }
+}
+
+/// Control registers of the VGA: screen size (`width << 16 | height`) and sync.
+/// Writing a non-zero value to sync saves the framebuffer as a frame.
+pub(super) struct VgaCtl {
+  screen: Arc<Mutex<Screen>>,
+}
+
+/// Pixels of the VGA, mapped separately from its control registers
+pub(super) struct VgaFramebuffer {
+  screen: Arc<Mutex<Screen>>,
+}
+
+/// The two halves of a VGA, the framebuffer takes `width * height` pixels
+pub(super) fn vga(
+  name: &str,
+  width: usize,
+  height: usize,
+  format: PixelFormat,
+  dump: FrameDump,
+) -> anyhow::Result<(VgaCtl, VgaFramebuffer)> {
+  if width == 0 || width > 0xffff || height == 0 || height > 0xffff {
+    anyhow::bail!("screen size {width}x{height} out of range");
+  }
+  fs::create_dir_all(&dump.dir).with_context(|| format!("creating {}", dump.dir.display()))?;
+  let screen = Arc::new(Mutex::new(Screen {
+    name: name.to_string(),
+    width,
+    height,
+    format,
+    pixels: vec![0u8; framebuffer_size(width, height, format)].into_boxed_slice(),
+    dump,
+    frames: 0,
+    written: VecDeque::new(),
+  }));
+  Ok((VgaCtl { screen: screen.clone() }, VgaFramebuffer { screen }))
+}
+
+/// Bytes the framebuffer of a `width` x `height` screen takes
+pub(super) fn framebuffer_size(width: usize, height: usize, format: PixelFormat) -> usize {
+  width * height * format.bytes()
+}
+
+impl ShadowDevice for VgaCtl {
+  fn read_mem(&mut self, addr: usize, size: usize) -> Vec<u8> {
+    let screen = self.screen.lock().unwrap();
+    reg_read(4, addr, size, |offset| match offset {
+      SIZE => (screen.width << 16 | screen.height) as u64,
+      _ => 0,
+    })
+  }
+
+  fn write_mem_chunk(&mut self, addr: usize, size: usize, strobe: Option<&[bool]>, data: &[u8]) {
+    for reg in reg_writes(4, addr, size, strobe, data) {
+      if reg.offset == SYNC && reg.merge(0) != 0 {
+        let mut screen = self.screen.lock().unwrap();
+        if let Err(e) = screen.save_frame() {
+          warn!("{}: fail saving frame: {e:#}", screen.name);
+        }
+      }
+    }
+  }
+}
+
+impl ShadowDevice for VgaFramebuffer {
+  fn read_mem(&mut self, addr: usize, size: usize) -> Vec<u8> {
+    let screen = self.screen.lock().unwrap();
+    // the region is rounded up to the bus width, the tail reads as zero
+    let mut data = vec![0u8; size];
+    let end = (addr + size).min(screen.pixels.len());
+    if addr < end {
+      data[..end - addr].copy_from_slice(&screen.pixels[addr..end]);
+    }
+    data
+  }
+
+  fn write_mem_chunk(&mut self, addr: usize, size: usize, strobe: Option<&[bool]>, data: &[u8]) {
+    let mut screen = self.screen.lock().unwrap();
+    let len = screen.pixels.len();
+    for i in (0..size).filter(|&i| addr + i < len) {
+      if strobe.is_some_and(|masks| !masks[i]) {
+        continue;
+      }
+      screen.pixels[addr + i] = data[i];
+    }
+  }
+}
+
+fn encode_ppm(width: usize, height: usize, rgb: &[u8]) -> Vec<u8> {
+  let mut out = format!("P6\n{width} {height}\n255\n").into_bytes();
+  out.extend_from_slice(rgb);
+  out
+}
+
+/// 8-bit RGB PNG, the zlib stream uses stored (uncompressed) blocks
+fn encode_png(width: usize, height: usize, rgb: &[u8]) -> Vec<u8> {
+  // every scanline starts with filter type 0
+  let mut raw = Vec::with_capacity((width * 3 + 1) * height);
+  for row in rgb.chunks(width * 3) {
+    raw.push(0);
+    raw.extend_from_slice(row);
+  }
+
+  let mut zlib = vec![0x78, 0x01];
+  let blocks = raw.chunks(0xffff);
+  let count = blocks.len();
+  for (i, block) in blocks.enumerate() {
+    zlib.push((i + 1 == count) as u8);
+    let len = block.len() as u16;
+    zlib.extend_from_slice(&len.to_le_bytes());
+    zlib.extend_from_slice(&(!len).to_le_bytes());
+    zlib.extend_from_slice(block);
+  }
+  zlib.extend_from_slice(&adler32(&raw).to_be_bytes());
+
+  let mut ihdr = Vec::with_capacity(13);
+  ihdr.extend_from_slice(&(width as u32).to_be_bytes());
+  ihdr.extend_from_slice(&(height as u32).to_be_bytes());
+  // bit depth 8, color type 2 (RGB), deflate, no filter, no interlace
+  ihdr.extend_from_slice(&[8, 2, 0, 0, 0]);
+
+  let mut out = b"\x89PNG\r\n\x1a\n".to_vec();
+  png_chunk(&mut out, b"IHDR", &ihdr);
+  png_chunk(&mut out, b"IDAT", &zlib);
+  png_chunk(&mut out, b"IEND", &[]);
+  out
+}
+
+fn png_chunk(out: &mut Vec<u8>, kind: &[u8; 4], data: &[u8]) {
+  out.extend_from_slice(&(data.len() as u32).to_be_bytes());
+  let start = out.len();
+  out.extend_from_slice(kind);
+  out.extend_from_slice(data);
+  let crc = crc32(&out[start..]);
+  out.extend_from_slice(&crc.to_be_bytes());
+}
+
+fn crc32(data: &[u8]) -> u32 {
+  let mut crc = !0u32;
+  for &byte in data {
+    crc ^= byte as u32;
+    for _ in 0..8 {
+      crc = if crc & 1 != 0 {
+        crc >> 1 ^ 0xedb8_8320
+      } else {
+        crc >> 1
+      };
+    }
+  }
+  !crc
+}
+
+fn adler32(data: &[u8]) -> u32 {
+  let (mut a, mut b) = (1u32, 0u32);
+  for &byte in data {
+    a = (a + byte as u32) % 65521;
+    b = (b + a) % 65521;
+  }
+  b << 16 | a
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn checksums_match_the_reference_values() {
+    assert_eq!(crc32(b""), 0);
+    assert_eq!(crc32(b"123456789"), 0xcbf4_3926);
+    assert_eq!(crc32(b"IEND"), 0xae42_6082);
+    assert_eq!(adler32(b""), 1);
+    assert_eq!(adler32(b"Wikipedia"), 0x11e6_0398);
+    // sums wrap at 65521, as computed by zlib
+    assert_eq!(adler32(&[0xff; 6000]), 0xa497_59ea);
+  }
+
+  /// (kind, data) of every chunk, checking the CRCs
+  fn chunks(png: &[u8]) -> Vec<([u8; 4], Vec<u8>)> {
+    assert_eq!(&png[..8], b"\x89PNG\r\n\x1a\n");
+    let mut rest = &png[8..];
+    let mut chunks = Vec::new();
+    while !rest.is_empty() {
+      let len = u32::from_be_bytes(rest[..4].try_into().unwrap()) as usize;
+      let body = &rest[4..8 + len];
+      let crc = u32::from_be_bytes(rest[8 + len..12 + len].try_into().unwrap());
+      assert_eq!(crc32(body), crc);
+      chunks.push((body[..4].try_into().unwrap(), body[4..].to_vec()));
+      rest = &rest[12 + len..];
+    }
+    chunks
+  }
+
+  /// Scanlines out of a zlib stream of stored blocks
+  fn inflate_stored(zlib: &[u8]) -> Vec<u8> {
+    assert_eq!(u16::from_be_bytes([zlib[0], zlib[1]]) % 31, 0);
+    let mut rest = &zlib[2..];
+    let mut raw = Vec::new();
+    loop {
+      let last = rest[0] == 1;
+      let len = u16::from_le_bytes([rest[1], rest[2]]);
+      assert_eq!(!len, u16::from_le_bytes([rest[3], rest[4]]));
+      raw.extend_from_slice(&rest[5..5 + len as usize]);
+      rest = &rest[5 + len as usize..];
+      if last {
+        break;
+      }
+    }
+    assert_eq!(rest, adler32(&raw).to_be_bytes());
+    raw
+  }
+
+  #[test]
+  fn png_holds_the_pixels() {
+    // big enough to need two stored blocks
+    let (width, height) = (200, 120);
+    let rgb: Vec<u8> = (0..width * height * 3).map(|i| (i * 7) as u8).collect();
+    let chunks = chunks(&encode_png(width, height, &rgb));
+    let kinds: Vec<&[u8; 4]> = chunks.iter().map(|(kind, _)| kind).collect();
+    assert_eq!(kinds, [b"IHDR", b"IDAT", b"IEND"]);
+    assert_eq!(chunks[0].1[..8], [0, 0, 0, 200, 0, 0, 0, 120]);
+
+    let raw = inflate_stored(&chunks[1].1);
+    assert_eq!(raw.len(), (width * 3 + 1) * height);
+    for (row, line) in raw.chunks(width * 3 + 1).enumerate() {
+      assert_eq!(line[0], 0);
+      assert_eq!(line[1..], rgb[row * width * 3..(row + 1) * width * 3]);
+    }
+  }
+
+  #[test]
+  fn ppm_header() {
+    let ppm = encode_ppm(2, 1, &[1, 2, 3, 4, 5, 6]);
+    assert_eq!(ppm, b"P6\n2 1\n255\n\x01\x02\x03\x04\x05\x06");
+  }
+}
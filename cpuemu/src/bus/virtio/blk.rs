@@ -0,0 +1,121 @@
+//! virtio-blk (virtio 1.1 spec section 5.2)
+
+use tracing::warn;
+
+use super::{Chain, Disk, DmaMemory, VirtioBackend, Virtqueue};
+
+const VIRTIO_ID_BLOCK: u32 = 2;
+
+const VIRTIO_BLK_F_FLUSH: u64 = 1 << 9;
+
+const VIRTIO_BLK_T_IN: u32 = 0;
+const VIRTIO_BLK_T_OUT: u32 = 1;
+const VIRTIO_BLK_T_FLUSH: u32 = 4;
+const VIRTIO_BLK_T_GET_ID: u32 = 8;
+
+const VIRTIO_BLK_S_OK: u8 = 0;
+const VIRTIO_BLK_S_IOERR: u8 = 1;
+const VIRTIO_BLK_S_UNSUPP: u8 = 2;
+
+/// type, reserved, sector
+const HEADER_SIZE: usize = 16;
+const ID_SIZE: usize = 20;
+
+/// One request queue over a disk image, requests complete as soon as they are seen
+pub(super) struct VirtioBlk {
+  name: String,
+  disk: Disk,
+}
+
+impl VirtioBlk {
+  pub fn new(name: &str, disk: Disk) -> Self {
+    Self { name: name.to_string(), disk }
+  }
+
+  /// Serve one request, returns the bytes written into the driver's buffers.
+  /// The status goes into the last writable byte, whatever the data before it.
+  fn serve(&mut self, chain: &Chain, mem: &mut dyn DmaMemory) -> anyhow::Result<u32> {
+    let readable = chain.read_all(mem)?;
+    if readable.len() < HEADER_SIZE {
+      anyhow::bail!("request header of {} bytes", readable.len());
+    }
+    let Some(data_len) = chain.writable_len().checked_sub(1) else {
+      anyhow::bail!("request without a status byte");
+    };
+    let kind = u32::from_le_bytes(readable[0..4].try_into().unwrap());
+    let sector = u64::from_le_bytes(readable[8..16].try_into().unwrap());
+
+    let mut response = Vec::new();
+    let result = match kind {
+      // don't allocate more than the disk holds
+      VIRTIO_BLK_T_IN => self.disk.range(sector, data_len).and_then(|_| {
+        response.resize(data_len, 0);
+        self.disk.read(sector, &mut response)
+      }),
+      VIRTIO_BLK_T_OUT => self.disk.write(sector, &readable[HEADER_SIZE..]),
+      VIRTIO_BLK_T_FLUSH => self.disk.flush(),
+      VIRTIO_BLK_T_GET_ID => {
+        response = self.name.as_bytes().to_vec();
+        response.resize(ID_SIZE.min(data_len), 0);
+        Ok(())
+      }
+      _ => {
+        chain.write_at(mem, data_len, &[VIRTIO_BLK_S_UNSUPP])?;
+        return Ok(1);
+      }
+    };
+    let status = match result {
+      Ok(()) => VIRTIO_BLK_S_OK,
+      Err(e) => {
+        warn!(
+          "{}: request {kind} at sector {sector} failed: {e:#}",
+          self.name
+        );
+        VIRTIO_BLK_S_IOERR
+      }
+    };
+    chain.write_at(mem, 0, &response)?;
+    chain.write_at(mem, data_len, &[status])?;
+    Ok(response.len() as u32 + 1)
+  }
+}
+
+impl VirtioBackend for VirtioBlk {
+  fn device_id(&self) -> u32 {
+    VIRTIO_ID_BLOCK
+  }
+
+  fn features(&self) -> u64 {
+    VIRTIO_BLK_F_FLUSH
+  }
+
+  fn num_queues(&self) -> usize {
+    1
+  }
+
+  fn config(&self) -> Vec<u8> {
+    // capacity, in 512-byte sectors
+    self.disk.sectors().to_le_bytes().to_vec()
+  }
+
+  fn process(
+    &mut self,
+    _index: usize,
+    queue: &mut Virtqueue,
+    mem: &mut dyn DmaMemory,
+  ) -> anyhow::Result<bool> {
+    let mut used = false;
+    while let Some(chain) = queue.pop(mem)? {
+      let len = self.serve(&chain, mem)?;
+      queue.push_used(mem, &chain, len)?;
+      used = true;
+    }
+    Ok(used)
+  }
+
+  fn flush(&mut self) {
+    if let Err(e) = self.disk.flush() {
+      warn!("{}: fail flushing disk: {e:#}", self.name);
+    }
+  }
+}
@@ -0,0 +1,91 @@
+//! virtio-console (virtio 1.1 spec section 5.3), a single port without MULTIPORT
+
+use std::collections::VecDeque;
+
+use super::{DmaMemory, HostConsole, VirtioBackend, Virtqueue};
+
+const VIRTIO_ID_CONSOLE: u32 = 3;
+
+const RECEIVEQ: usize = 0;
+const TRANSMITQ: usize = 1;
+
+/// host input buffered while the driver has no receive buffer
+const RX_DEPTH: usize = 4096;
+
+pub(super) struct VirtioConsole {
+  console: HostConsole,
+  rx: VecDeque<u8>,
+}
+
+impl VirtioConsole {
+  pub fn new(console: HostConsole) -> Self {
+    Self { console, rx: VecDeque::new() }
+  }
+
+  /// Fill receive buffers with host input while there is some
+  fn receive(&mut self, queue: &mut Virtqueue, mem: &mut dyn DmaMemory) -> anyhow::Result<bool> {
+    let mut used = false;
+    while !self.rx.is_empty() {
+      let Some(chain) = queue.pop(mem)? else {
+        break;
+      };
+      let n = self.rx.len().min(chain.writable_len());
+      let data: Vec<u8> = self.rx.drain(..n).collect();
+      let len = chain.write_all(mem, &data)?;
+      queue.push_used(mem, &chain, len)?;
+      used = true;
+    }
+    Ok(used)
+  }
+
+  fn transmit(&mut self, queue: &mut Virtqueue, mem: &mut dyn DmaMemory) -> anyhow::Result<bool> {
+    let mut used = false;
+    while let Some(chain) = queue.pop(mem)? {
+      chain.read_all(mem)?.into_iter().for_each(|byte| self.console.write_byte(byte));
+      queue.push_used(mem, &chain, 0)?;
+      used = true;
+    }
+    Ok(used)
+  }
+}
+
+impl VirtioBackend for VirtioConsole {
+  fn device_id(&self) -> u32 {
+    VIRTIO_ID_CONSOLE
+  }
+
+  fn features(&self) -> u64 {
+    0
+  }
+
+  fn num_queues(&self) -> usize {
+    2
+  }
+
+  fn config(&self) -> Vec<u8> {
+    // cols, rows and max_nr_ports are only valid with features we do not offer
+    Vec::new()
+  }
+
+  fn process(
+    &mut self,
+    index: usize,
+    queue: &mut Virtqueue,
+    mem: &mut dyn DmaMemory,
+  ) -> anyhow::Result<bool> {
+    match index {
+      RECEIVEQ => self.receive(queue, mem),
+      TRANSMITQ => self.transmit(queue, mem),
+      _ => Ok(false),
+    }
+  }
+
+  fn pending(&self, index: usize, queue: &Virtqueue) -> bool {
+    // without receive buffers, wait for the driver to add some instead of rereading the ring
+    index == RECEIVEQ && !self.rx.is_empty() && queue.may_have_avail()
+  }
+
+  fn tick(&mut self, tick: u64) {
+    self.console.poll_on_tick(tick, &mut self.rx, RX_DEPTH);
+  }
+}
@@ -0,0 +1,273 @@
+//! virtio-mmio transport (virtio 1.1 spec section 4.2, version 2 only)
+
+mod blk;
+use blk::VirtioBlk;
+
+mod console;
+use console::VirtioConsole;
+
+mod queue;
+use queue::*;
+
+use tracing::warn;
+
+use super::{regs::*, Disk, DmaMemory, HostConsole, ShadowDevice};
+
+const MAGIC_VALUE: usize = 0x000;
+const VERSION: usize = 0x004;
+const DEVICE_ID: usize = 0x008;
+const VENDOR_ID: usize = 0x00c;
+const DEVICE_FEATURES: usize = 0x010;
+const DEVICE_FEATURES_SEL: usize = 0x014;
+const DRIVER_FEATURES: usize = 0x020;
+const DRIVER_FEATURES_SEL: usize = 0x024;
+const QUEUE_SEL: usize = 0x030;
+const QUEUE_NUM_MAX: usize = 0x034;
+const QUEUE_NUM: usize = 0x038;
+const QUEUE_READY: usize = 0x044;
+const QUEUE_NOTIFY: usize = 0x050;
+const INTERRUPT_STATUS: usize = 0x060;
+const INTERRUPT_ACK: usize = 0x064;
+const STATUS: usize = 0x070;
+const QUEUE_DESC_LOW: usize = 0x080;
+const QUEUE_DESC_HIGH: usize = 0x084;
+const QUEUE_DRIVER_LOW: usize = 0x090;
+const QUEUE_DRIVER_HIGH: usize = 0x094;
+const QUEUE_DEVICE_LOW: usize = 0x0a0;
+const QUEUE_DEVICE_HIGH: usize = 0x0a4;
+const CONFIG_GENERATION: usize = 0x0fc;
+const CONFIG: usize = 0x100;
+
+/// "virt"
+const MAGIC: u32 = 0x7472_6976;
+/// "QEMU"
+const VENDOR: u32 = 0x554d_4551;
+
+const VIRTIO_F_VERSION_1: u64 = 1 << 32;
+
+const STATUS_DRIVER_OK: u32 = 4;
+const STATUS_DEVICE_NEEDS_RESET: u32 = 64;
+
+const INTERRUPT_USED_BUFFER: u32 = 1;
+const INTERRUPT_CONFIG_CHANGE: u32 = 2;
+
+/// Device type behind the transport
+trait VirtioBackend: Send + Sync {
+  /// virtio device ID
+  fn device_id(&self) -> u32;
+  /// device-specific feature bits, the transport adds VIRTIO_F_VERSION_1
+  fn features(&self) -> u64;
+  fn num_queues(&self) -> usize;
+  /// device configuration space
+  fn config(&self) -> Vec<u8>;
+  /// Serve what queue `index` holds, returns whether any buffer was used
+  fn process(
+    &mut self,
+    index: usize,
+    queue: &mut Virtqueue,
+    mem: &mut dyn DmaMemory,
+  ) -> anyhow::Result<bool>;
+  /// Queue `index` has work without being notified, e.g. host input for a receive queue
+  fn pending(&self, _index: usize, _queue: &Virtqueue) -> bool {
+    false
+  }
+  fn tick(&mut self, _tick: u64) {}
+  fn flush(&mut self) {}
+}
+
+pub(super) struct VirtioMmio {
+  name: String,
+  backend: Box<dyn VirtioBackend>,
+  queues: Vec<Virtqueue>,
+
+  device_features_sel: u32,
+  driver_features: u64,
+  driver_features_sel: u32,
+  queue_sel: u32,
+  /// queues notified by the driver, one bit each
+  notified: u64,
+  interrupt_status: u32,
+  status: u32,
+}
+
+impl VirtioMmio {
+  /// virtio-blk over `disk`
+  pub fn blk(name: &str, disk: Disk) -> Self {
+    Self::new(name, Box::new(VirtioBlk::new(name, disk)))
+  }
+
+  /// virtio-console with a single port on the host console
+  pub fn console(name: &str, console: HostConsole) -> Self {
+    Self::new(name, Box::new(VirtioConsole::new(console)))
+  }
+
+  fn new(name: &str, backend: Box<dyn VirtioBackend>) -> Self {
+    let queues = (0..backend.num_queues()).map(|_| Virtqueue::default()).collect();
+    Self {
+      name: name.to_string(),
+      backend,
+      queues,
+      device_features_sel: 0,
+      driver_features: 0,
+      driver_features_sel: 0,
+      queue_sel: 0,
+      notified: 0,
+      interrupt_status: 0,
+      status: 0,
+    }
+  }
+
+  fn reset(&mut self) {
+    self.queues.iter_mut().for_each(Virtqueue::reset);
+    self.driver_features = 0;
+    self.notified = 0;
+    self.interrupt_status = 0;
+    self.status = 0;
+  }
+
+  fn device_features(&self) -> u64 {
+    self.backend.features() | VIRTIO_F_VERSION_1
+  }
+
+  fn queue(&mut self) -> Option<&mut Virtqueue> {
+    self.queues.get_mut(self.queue_sel as usize)
+  }
+
+  fn driver_ok(&self) -> bool {
+    self.status & STATUS_DRIVER_OK != 0 && self.status & STATUS_DEVICE_NEEDS_RESET == 0
+  }
+
+  fn read_reg(&mut self, offset: usize) -> u32 {
+    let half = |value: u64, sel: u32| match sel {
+      0 => value as u32,
+      1 => (value >> 32) as u32,
+      _ => 0,
+    };
+    match offset {
+      MAGIC_VALUE => MAGIC,
+      VERSION => 2,
+      DEVICE_ID => self.backend.device_id(),
+      VENDOR_ID => VENDOR,
+      DEVICE_FEATURES => half(self.device_features(), self.device_features_sel),
+      QUEUE_NUM_MAX => self.queue().map_or(0, |_| MAX_QUEUE_SIZE as u32),
+      QUEUE_READY => self.queue().map_or(0, |q| q.ready as u32),
+      INTERRUPT_STATUS => self.interrupt_status,
+      STATUS => self.status,
+      // the configuration never changes
+      CONFIG_GENERATION => 0,
+      o if o >= CONFIG => {
+        let config = self.backend.config();
+        let mut bytes = [0u8; 4];
+        for (i, byte) in bytes.iter_mut().enumerate() {
+          *byte = config.get(o - CONFIG + i).copied().unwrap_or(0);
+        }
+        u32::from_le_bytes(bytes)
+      }
+      _ => 0,
+    }
+  }
+
+  fn write_reg(&mut self, offset: usize, value: u32) {
+    let set_low = |old: u64| (old & !0xffff_ffff) | value as u64;
+    let set_high = |old: u64| (old & 0xffff_ffff) | ((value as u64) << 32);
+    match offset {
+      DEVICE_FEATURES_SEL => self.device_features_sel = value,
+      DRIVER_FEATURES => match self.driver_features_sel {
+        0 => self.driver_features = set_low(self.driver_features),
+        1 => self.driver_features = set_high(self.driver_features),
+        _ => {}
+      },
+      DRIVER_FEATURES_SEL => self.driver_features_sel = value,
+      QUEUE_SEL => self.queue_sel = value,
+      QUEUE_NUM => {
+        if let Some(q) = self.queue() {
+          q.num = (value as u16).min(MAX_QUEUE_SIZE);
+        }
+      }
+      QUEUE_READY => {
+        if let Some(q) = self.queue() {
+          q.ready = value & 1 != 0;
+        }
+      }
+      QUEUE_NOTIFY if (value as usize) < self.queues.len() => {
+        self.queues[value as usize].notify();
+        self.notified |= 1 << value;
+      }
+      INTERRUPT_ACK => self.interrupt_status &= !value,
+      STATUS if value == 0 => self.reset(),
+      STATUS => self.status = value,
+      QUEUE_DESC_LOW | QUEUE_DESC_HIGH | QUEUE_DRIVER_LOW | QUEUE_DRIVER_HIGH
+      | QUEUE_DEVICE_LOW | QUEUE_DEVICE_HIGH => {
+        let Some(q) = self.queue() else {
+          return;
+        };
+        let field = match offset {
+          QUEUE_DESC_LOW | QUEUE_DESC_HIGH => &mut q.desc,
+          QUEUE_DRIVER_LOW | QUEUE_DRIVER_HIGH => &mut q.avail,
+          _ => &mut q.used,
+        };
+        *field = if offset & 4 == 0 {
+          set_low(*field)
+        } else {
+          set_high(*field)
+        };
+      }
+      _ => {}
+    }
+  }
+
+  /// Queues with work to do, one bit each
+  fn busy_queues(&self) -> u64 {
+    (0..self.queues.len())
+      .filter(|&i| self.backend.pending(i, &self.queues[i]))
+      .fold(self.notified, |busy, i| busy | 1 << i)
+  }
+}
+
+impl ShadowDevice for VirtioMmio {
+  fn read_mem(&mut self, addr: usize, size: usize) -> Vec<u8> {
+    reg_read(4, addr, size, |offset| self.read_reg(offset) as u64)
+  }
+
+  fn write_mem_chunk(&mut self, addr: usize, size: usize, strobe: Option<&[bool]>, data: &[u8]) {
+    for reg in reg_writes(4, addr, size, strobe, data) {
+      let old = self.read_reg(reg.offset) as u64;
+      self.write_reg(reg.offset, reg.merge(old) as u32);
+    }
+  }
+
+  fn tick(&mut self, tick: u64) {
+    self.backend.tick(tick);
+  }
+
+  fn wants_dma(&self) -> bool {
+    self.driver_ok() && self.busy_queues() != 0
+  }
+
+  fn run_dma(&mut self, mem: &mut dyn DmaMemory) {
+    let busy = self.busy_queues();
+    self.notified = 0;
+    for index in (0..self.queues.len()).filter(|i| busy & 1 << i != 0) {
+      match self.backend.process(index, &mut self.queues[index], mem) {
+        Ok(true) => self.interrupt_status |= INTERRUPT_USED_BUFFER,
+        Ok(false) => {}
+        Err(e) => {
+          warn!(
+            "{}: queue {index}: {e:#}, the device needs a reset",
+            self.name
+          );
+          self.status |= STATUS_DEVICE_NEEDS_RESET;
+          self.interrupt_status |= INTERRUPT_CONFIG_CHANGE;
+        }
+      }
+    }
+  }
+
+  fn irq_level(&self) -> bool {
+    self.interrupt_status != 0
+  }
+
+  fn flush(&mut self) {
+    self.backend.flush();
+  }
+}
@@ -0,0 +1,324 @@
+//! Split virtqueues, as in the virtio 1.1 spec section 2.6
+
+use super::DmaMemory;
+
+const VIRTQ_DESC_F_NEXT: u16 = 1;
+const VIRTQ_DESC_F_WRITE: u16 = 2;
+const VIRTQ_DESC_F_INDIRECT: u16 = 4;
+
+/// Largest queue the driver may set up
+pub(super) const MAX_QUEUE_SIZE: u16 = 256;
+
+fn read_u16(mem: &mut dyn DmaMemory, addr: u64) -> anyhow::Result<u16> {
+  let bytes = mem.read(addr as usize, 2)?;
+  Ok(u16::from_le_bytes([bytes[0], bytes[1]]))
+}
+
+/// One queue as configured by the driver, addresses are guest physical
+#[derive(Default)]
+pub(super) struct Virtqueue {
+  pub num: u16,
+  pub ready: bool,
+  pub desc: u64,
+  pub avail: u64,
+  pub used: u64,
+  /// next avail ring entry to take
+  last_avail: u16,
+  /// next used ring entry to fill
+  used_idx: u16,
+  /// the last `pop` found avail.idx where we left it, and the driver did not notify since
+  drained: bool,
+}
+
+/// The buffers of one request: device-readable ones first, then device-writable ones
+pub(super) struct Chain {
+  head: u16,
+  /// (addr, len)
+  readable: Vec<(u64, u32)>,
+  writable: Vec<(u64, u32)>,
+}
+
+impl Chain {
+  /// All device-readable bytes, concatenated
+  pub fn read_all(&self, mem: &mut dyn DmaMemory) -> anyhow::Result<Vec<u8>> {
+    let mut data = Vec::new();
+    for &(addr, len) in &self.readable {
+      data.extend(mem.read(addr as usize, len as usize)?);
+    }
+    Ok(data)
+  }
+
+  pub fn writable_len(&self) -> usize {
+    self.writable.iter().map(|&(_, len)| len as usize).sum()
+  }
+
+  /// Scatter `data` over the device-writable buffers, returns the bytes written
+  pub fn write_all(&self, mem: &mut dyn DmaMemory, data: &[u8]) -> anyhow::Result<u32> {
+    self.write_at(mem, 0, data)?;
+    Ok(data.len() as u32)
+  }
+
+  /// Scatter `data` over the device-writable buffers, from byte `offset` of them on
+  pub fn write_at(
+    &self,
+    mem: &mut dyn DmaMemory,
+    offset: usize,
+    data: &[u8],
+  ) -> anyhow::Result<()> {
+    let mut skip = offset;
+    let mut rest = data;
+    for &(addr, len) in &self.writable {
+      let len = len as usize;
+      if rest.is_empty() {
+        break;
+      }
+      if skip >= len {
+        skip -= len;
+        continue;
+      }
+      let n = rest.len().min(len - skip);
+      mem.write(addr as usize + skip, &rest[..n])?;
+      rest = &rest[n..];
+      skip = 0;
+    }
+    if !rest.is_empty() {
+      anyhow::bail!(
+        "{} bytes at {offset:#x} do not fit in the writable buffers",
+        data.len()
+      );
+    }
+    Ok(())
+  }
+}
+
+impl Virtqueue {
+  /// Back to the state after a device reset
+  pub fn reset(&mut self) {
+    *self = Self::default();
+  }
+
+  /// The driver notified the queue, avail.idx may have moved
+  pub fn notify(&mut self) {
+    self.drained = false;
+  }
+
+  /// Whether avail.idx may have moved since `pop` last found nothing, without reading it
+  pub fn may_have_avail(&self) -> bool {
+    !self.drained
+  }
+
+  /// Take the next request the driver made available, None when there is none
+  pub fn pop(&mut self, mem: &mut dyn DmaMemory) -> anyhow::Result<Option<Chain>> {
+    if !self.ready || self.num == 0 {
+      return Ok(None);
+    }
+    let avail_idx = read_u16(mem, self.avail + 2)?;
+    self.drained = avail_idx == self.last_avail;
+    if self.drained {
+      return Ok(None);
+    }
+    let slot = (self.last_avail % self.num) as u64;
+    let head = read_u16(mem, self.avail + 4 + slot * 2)?;
+    self.last_avail = self.last_avail.wrapping_add(1);
+
+    let mut chain = Chain { head, readable: Vec::new(), writable: Vec::new() };
+    let mut index = head;
+    // a chain never has more descriptors than the table, more means a loop
+    for _ in 0..self.num {
+      if index >= self.num {
+        anyhow::bail!("descriptor {index} out of a {}-entry table", self.num);
+      }
+      let desc = mem.read((self.desc + index as u64 * 16) as usize, 16)?;
+      let addr = u64::from_le_bytes(desc[0..8].try_into().unwrap());
+      let len = u32::from_le_bytes(desc[8..12].try_into().unwrap());
+      let flags = u16::from_le_bytes([desc[12], desc[13]]);
+      let next = u16::from_le_bytes([desc[14], desc[15]]);
+      if flags & VIRTQ_DESC_F_INDIRECT != 0 {
+        anyhow::bail!("indirect descriptors were not negotiated");
+      }
+      if flags & VIRTQ_DESC_F_WRITE != 0 {
+        chain.writable.push((addr, len));
+      } else if chain.writable.is_empty() {
+        chain.readable.push((addr, len));
+      } else {
+        anyhow::bail!("device-readable descriptor after a device-writable one");
+      }
+      if flags & VIRTQ_DESC_F_NEXT == 0 {
+        return Ok(Some(chain));
+      }
+      index = next;
+    }
+    anyhow::bail!("descriptor chain from {head} loops")
+  }
+
+  /// Hand a request back to the driver, `len` bytes were written into its buffers
+  pub fn push_used(
+    &mut self,
+    mem: &mut dyn DmaMemory,
+    chain: &Chain,
+    len: u32,
+  ) -> anyhow::Result<()> {
+    let slot = (self.used_idx % self.num) as u64;
+    let mut elem = (chain.head as u32).to_le_bytes().to_vec();
+    elem.extend_from_slice(&len.to_le_bytes());
+    mem.write((self.used + 4 + slot * 8) as usize, &elem)?;
+    // the element must be visible before the index moves
+    self.used_idx = self.used_idx.wrapping_add(1);
+    mem.write((self.used + 2) as usize, &self.used_idx.to_le_bytes())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  const DESC: u64 = 0x0;
+  const AVAIL: u64 = 0x1000;
+  const USED: u64 = 0x2000;
+
+  /// Flat guest memory from address 0
+  struct Ram(Vec<u8>);
+
+  impl DmaMemory for Ram {
+    fn read(&mut self, addr: usize, len: usize) -> anyhow::Result<Vec<u8>> {
+      match self.0.get(addr..addr + len) {
+        Some(bytes) => Ok(bytes.to_vec()),
+        None => anyhow::bail!("read [{addr:#x}, +{len:#x}) out of the RAM"),
+      }
+    }
+
+    fn write(&mut self, addr: usize, data: &[u8]) -> anyhow::Result<()> {
+      match self.0.get_mut(addr..addr + data.len()) {
+        Some(bytes) => {
+          bytes.copy_from_slice(data);
+          Ok(())
+        }
+        None => anyhow::bail!("write [{addr:#x}, +{:#x}) out of the RAM", data.len()),
+      }
+    }
+  }
+
+  impl Ram {
+    fn desc(&mut self, index: u16, addr: u64, len: u32, flags: u16, next: u16) {
+      let mut desc = addr.to_le_bytes().to_vec();
+      desc.extend_from_slice(&len.to_le_bytes());
+      desc.extend_from_slice(&flags.to_le_bytes());
+      desc.extend_from_slice(&next.to_le_bytes());
+      self.write((DESC + index as u64 * 16) as usize, &desc).unwrap();
+    }
+
+    /// Make the chain starting at `head` the `idx`th available one, of an 8-entry ring
+    fn offer(&mut self, idx: u16, head: u16) {
+      let slot = (idx % 8) as u64;
+      self.write((AVAIL + 4 + slot * 2) as usize, &head.to_le_bytes()).unwrap();
+      self.write((AVAIL + 2) as usize, &(idx + 1).to_le_bytes()).unwrap();
+    }
+
+    fn u32_at(&mut self, addr: u64) -> u32 {
+      u32::from_le_bytes(self.read(addr as usize, 4).unwrap().try_into().unwrap())
+    }
+  }
+
+  fn setup() -> (Virtqueue, Ram) {
+    let queue = Virtqueue {
+      num: 8,
+      ready: true,
+      desc: DESC,
+      avail: AVAIL,
+      used: USED,
+      ..Default::default()
+    };
+    (queue, Ram(vec![0; 0x4000]))
+  }
+
+  #[test]
+  fn pop_splits_readable_and_writable() {
+    let (mut queue, mut mem) = setup();
+    assert!(queue.pop(&mut mem).unwrap().is_none());
+    mem.write(0x3000, b"header").unwrap();
+    mem.desc(5, 0x3000, 6, VIRTQ_DESC_F_NEXT, 2);
+    mem.desc(2, 0x3100, 3, VIRTQ_DESC_F_NEXT | VIRTQ_DESC_F_WRITE, 7);
+    mem.desc(7, 0x3200, 2, VIRTQ_DESC_F_WRITE, 0);
+    mem.offer(0, 5);
+
+    let chain = queue.pop(&mut mem).unwrap().unwrap();
+    assert_eq!(chain.head, 5);
+    assert_eq!(chain.read_all(&mut mem).unwrap(), b"header");
+    assert_eq!(chain.writable_len(), 5);
+    // scattered over both writable buffers
+    chain.write_at(&mut mem, 2, b"xyz").unwrap();
+    assert_eq!(mem.read(0x3102, 1).unwrap(), b"x");
+    assert_eq!(mem.read(0x3200, 2).unwrap(), b"yz");
+    assert!(chain.write_at(&mut mem, 4, b"ab").is_err());
+    assert_eq!(chain.write_all(&mut mem, b"abcde").unwrap(), 5);
+    assert_eq!(mem.read(0x3100, 3).unwrap(), b"abc");
+    assert!(queue.pop(&mut mem).unwrap().is_none());
+  }
+
+  #[test]
+  fn push_used_fills_the_ring_and_moves_the_index() {
+    let (mut queue, mut mem) = setup();
+    mem.desc(3, 0x3000, 4, 0, 0);
+    for idx in 0..10 {
+      // the index keeps counting past the ring size
+      mem.offer(idx, 3);
+      let chain = queue.pop(&mut mem).unwrap().unwrap();
+      queue.push_used(&mut mem, &chain, idx as u32).unwrap();
+    }
+    assert_eq!(
+      mem.read((USED + 2) as usize, 2).unwrap(),
+      10u16.to_le_bytes()
+    );
+    // slot 1 holds the 10th element
+    assert_eq!(mem.u32_at(USED + 4 + 8), 3);
+    assert_eq!(mem.u32_at(USED + 4 + 8 + 4), 9);
+  }
+
+  #[test]
+  fn bad_chains() {
+    let (mut queue, mut mem) = setup();
+    // loop
+    mem.desc(0, 0x3000, 1, VIRTQ_DESC_F_NEXT, 1);
+    mem.desc(1, 0x3000, 1, VIRTQ_DESC_F_NEXT, 0);
+    mem.offer(0, 0);
+    assert!(queue.pop(&mut mem).is_err());
+    // past the table
+    mem.desc(2, 0x3000, 1, VIRTQ_DESC_F_NEXT, 8);
+    mem.offer(1, 2);
+    assert!(queue.pop(&mut mem).is_err());
+    // readable after writable
+    mem.desc(3, 0x3000, 1, VIRTQ_DESC_F_NEXT | VIRTQ_DESC_F_WRITE, 4);
+    mem.desc(4, 0x3000, 1, 0, 0);
+    mem.offer(2, 3);
+    assert!(queue.pop(&mut mem).is_err());
+    // indirect
+    mem.desc(5, 0x3000, 16, VIRTQ_DESC_F_INDIRECT, 0);
+    mem.offer(3, 5);
+    assert!(queue.pop(&mut mem).is_err());
+  }
+
+  #[test]
+  fn drained_until_notified() {
+    let (mut queue, mut mem) = setup();
+    assert!(queue.may_have_avail());
+    assert!(queue.pop(&mut mem).unwrap().is_none());
+    assert!(!queue.may_have_avail());
+    mem.desc(0, 0x3000, 1, VIRTQ_DESC_F_WRITE, 0);
+    mem.offer(0, 0);
+    queue.notify();
+    assert!(queue.may_have_avail());
+    assert!(queue.pop(&mut mem).unwrap().is_some());
+    assert!(queue.may_have_avail());
+  }
+
+  #[test]
+  fn not_ready_queue_is_empty() {
+    let (mut queue, mut mem) = setup();
+    mem.desc(0, 0x3000, 1, 0, 0);
+    mem.offer(0, 0);
+    queue.ready = false;
+    assert!(queue.pop(&mut mem).unwrap().is_none());
+    queue.reset();
+    assert_eq!(queue.num, 0);
+  }
+}
@@ -0,0 +1,142 @@
+use std::str::FromStr;
+
+use super::parse_int;
+
+/// Which accesses a watchpoint catches
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum WatchAccess {
+  Read,
+  Write,
+  ReadWrite,
+}
+
+impl WatchAccess {
+  fn catches(self, write: bool) -> bool {
+    match self {
+      WatchAccess::Read => !write,
+      WatchAccess::Write => write,
+      WatchAccess::ReadWrite => true,
+    }
+  }
+}
+
+/// An address range whose AXI accesses get logged
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Watchpoint {
+  pub start: usize,
+  pub end: usize,
+  pub access: WatchAccess,
+  /// end the simulation on the first hit
+  pub stop: bool,
+}
+
+impl Watchpoint {
+  /// Whether an access to [start, end) is caught
+  pub fn hit(&self, start: usize, end: usize, write: bool) -> bool {
+    self.access.catches(write) && start < self.end && self.start < end
+  }
+}
+
+/// `addr[:len][:r|w|rw][:stop]`, len defaults to a doubleword and the access to both
+impl FromStr for Watchpoint {
+  type Err = anyhow::Error;
+
+  fn from_str(s: &str) -> anyhow::Result<Self> {
+    let mut fields = s.split(':');
+    let start = parse_int(fields.next().unwrap_or_default())? as usize;
+    let end_at = |len: usize| {
+      start.checked_add(len).ok_or_else(|| anyhow::anyhow!("watchpoint `{s}` wraps around"))
+    };
+    let mut watch = Watchpoint {
+      start,
+      end: end_at(8)?,
+      access: WatchAccess::ReadWrite,
+      stop: false,
+    };
+    for field in fields {
+      match field {
+        "r" => watch.access = WatchAccess::Read,
+        "w" => watch.access = WatchAccess::Write,
+        "rw" => watch.access = WatchAccess::ReadWrite,
+        "stop" => watch.stop = true,
+        len => match parse_int(len)? as usize {
+          0 => anyhow::bail!("watchpoint `{s}` is empty"),
+          len => watch.end = end_at(len)?,
+        },
+      }
+    }
+    Ok(watch)
+  }
+}
+
+/// Comma separated watchpoints, as given to `+watch=`
+pub(crate) fn parse_watchpoints(s: &str) -> anyhow::Result<Vec<Watchpoint>> {
+  s.split(',')
+    .filter(|spec| !spec.is_empty())
+    .map(|spec| spec.parse().map_err(|e| anyhow::anyhow!("bad watchpoint `{spec}`: {e}")))
+    .collect()
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn defaults_to_a_doubleword_read_and_written() {
+    let watch: Watchpoint = "0x8000_1000".parse().unwrap();
+    assert_eq!(
+      watch,
+      Watchpoint {
+        start: 0x8000_1000,
+        end: 0x8000_1008,
+        access: WatchAccess::ReadWrite,
+        stop: false
+      }
+    );
+  }
+
+  #[test]
+  fn fields_in_any_order() {
+    let watch: Watchpoint = "0x100:stop:w:0x40".parse().unwrap();
+    assert_eq!((watch.start, watch.end), (0x100, 0x140));
+    assert_eq!(watch.access, WatchAccess::Write);
+    assert!(watch.stop);
+    assert_eq!(
+      "256:r".parse::<Watchpoint>().unwrap().access,
+      WatchAccess::Read
+    );
+  }
+
+  #[test]
+  fn bad_specs() {
+    for spec in ["", "zz", "0x100:0", "0x100:x"] {
+      assert!(spec.parse::<Watchpoint>().is_err(), "{spec}");
+    }
+  }
+
+  #[test]
+  fn ranges_that_wrap_around() {
+    assert!("0xfffffffffffffff0".parse::<Watchpoint>().is_ok());
+    assert!("0xfffffffffffffff8".parse::<Watchpoint>().is_err());
+    assert!("0xffffffffffffffff:2".parse::<Watchpoint>().is_err());
+  }
+
+  #[test]
+  fn list() {
+    let watches = parse_watchpoints("0x10:r,,0x20:4:stop").unwrap();
+    assert_eq!(watches.len(), 2);
+    assert_eq!(watches[1].end, 0x24);
+    assert!(parse_watchpoints("0x10,oops").is_err());
+    assert!(parse_watchpoints("").unwrap().is_empty());
+  }
+
+  #[test]
+  fn hits_overlapping_accesses_of_its_kind() {
+    let watch: Watchpoint = "0x100:8:w".parse().unwrap();
+    assert!(watch.hit(0x104, 0x108, true));
+    assert!(watch.hit(0xf8, 0x101, true));
+    assert!(!watch.hit(0x108, 0x110, true));
+    assert!(!watch.hit(0xf8, 0x100, true));
+    assert!(!watch.hit(0x100, 0x108, false));
+  }
+}
@@ -5,9 +5,12 @@ use std::ffi::{c_char, c_longlong};
 //use std::cmp::max;
 use std::fs::File;
 use std::io::Write;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Mutex;
 use tracing::{debug, trace};
 
+use crate::axi::{AxiRequest, AxiResp, AXI_PAYLOAD_BEATS};
+use crate::bus::build_images;
 use crate::drive::Driver;
 use crate::drive::SimState;
 use crate::plusarg::PlusArgMatcher;
@@ -25,6 +28,27 @@ static DPI_TARGET: Mutex<Option<Box<Driver>>> = Mutex::new(None);
 
 pub(crate) struct AxiReadPayload {
   pub(crate) data: Vec<u8>,
+  /// one per beat
+  pub(crate) resp: Vec<AxiResp>,
+}
+
+impl AxiReadPayload {
+  /// SLVERR on every beat, for a read that could not be served
+  pub(crate) fn error(beats: usize, bus_size: u32) -> Self {
+    Self {
+      data: vec![0; beats * bus_size as usize],
+      resp: vec![AxiResp::SlvErr; beats],
+    }
+  }
+
+  /// Keep the first `beats` beats, returns the others
+  pub(crate) fn split_off(&mut self, beats: usize) -> Self {
+    let bus_size = self.data.len() / self.resp.len();
+    Self {
+      data: self.data.split_off(beats * bus_size),
+      resp: self.resp.split_off(beats),
+    }
+  }
 }
 
 unsafe fn write_to_pointer(dst: *mut u8, data: &[u8]) {
@@ -32,28 +56,61 @@ unsafe fn write_to_pointer(dst: *mut u8, data: &[u8]) {
   dst.copy_from_slice(data);
 }
 
-unsafe fn fill_axi_read_payload(dst: *mut SvBitVecVal, dlen: u32, payload: &AxiReadPayload) {
-  let data_len = 256 * (dlen / 8) as usize;
+/// ReadResponse is packed as valid (u8), len (u8), id (u16), then the ReadPayload:
+/// AXI_PAYLOAD_BEATS resp bytes followed by AXI_PAYLOAD_BEATS data slots.
+/// Bit 1 of valid tells that more chunks of the burst follow, so the last beat is not RLAST.
+unsafe fn fill_axi_read_response(
+  dst: *mut SvBitVecVal,
+  dlen: u32,
+  response: Option<(u64, AxiReadPayload, bool)>,
+) {
+  let dst = dst as *mut u8;
+  let Some((id, payload, more)) = response else {
+    write_to_pointer(dst, &[0]);
+    return;
+  };
+  let data_len = AXI_PAYLOAD_BEATS * (dlen / 8) as usize;
   assert!(payload.data.len() <= data_len);
-  write_to_pointer(dst as *mut u8, &payload.data);
+  assert!(payload.resp.len() <= AXI_PAYLOAD_BEATS);
+  let len = payload.resp.len() as u8 - 1;
+  let id = (id as u16).to_le_bytes();
+  write_to_pointer(dst, &[1 | (more as u8) << 1, len, id[0], id[1]]);
+  let resp: Vec<u8> = payload.resp.iter().map(|resp| *resp as u8).collect();
+  write_to_pointer(dst.add(4), &resp);
+  write_to_pointer(dst.add(4 + AXI_PAYLOAD_BEATS), &payload.data);
+}
+
+/// WriteResponse is packed as valid (u8), resp (u8), id (u16)
+unsafe fn fill_axi_write_response(dst: *mut SvBitVecVal, response: Option<(u64, AxiResp)>) {
+  let bytes = match response {
+    Some((id, resp)) => {
+      let id = (id as u16).to_le_bytes();
+      [1, resp as u8, id[0], id[1]]
+    }
+    None => [0; 4],
+  };
+  write_to_pointer(dst as *mut u8, &bytes);
 }
 
+/// WritePayload is packed as AXI_PAYLOAD_BEATS strobe slots followed by AXI_PAYLOAD_BEATS data slots,
+/// returns the strobes and data of the first `beats` beats
 unsafe fn load_from_payload<'a>(
   payload: *const SvBitVecVal,
   data_width: u32,
+  beats: usize,
 ) -> (Vec<bool>, &'a [u8]) {
   let src = payload as *mut u8;
-  let src_slice = unsafe { std::slice::from_raw_parts(src, 64) };
   let data_width_in_byte = (data_width / 8) as usize;
   let strb_width_in_byte = data_width_in_byte.div_ceil(8); // ceil divide by 8 to get byte width
-  let payload_size_in_byte = strb_width_in_byte + data_width_in_byte; // data width in byte
+  let strb_size_in_byte = AXI_PAYLOAD_BEATS * strb_width_in_byte;
+  let payload_size_in_byte = strb_size_in_byte + AXI_PAYLOAD_BEATS * data_width_in_byte;
   let byte_vec = std::slice::from_raw_parts(src, payload_size_in_byte);
-  let strobe = &byte_vec[0..strb_width_in_byte];
-  let data = &byte_vec[strb_width_in_byte..];
+  let strobe = &byte_vec[0..beats * strb_width_in_byte];
+  let data = &byte_vec[strb_size_in_byte..strb_size_in_byte + beats * data_width_in_byte];
 
   let strb_width_in_bit = std::cmp::min(8, data_width_in_byte);
   let masks: Vec<bool> = strobe
-    .into_iter()
+    .iter()
     .flat_map(|strb| {
       let mask: Vec<bool> = (0..strb_width_in_bit).map(|i| (strb & (1 << i)) != 0).collect();
       mask
@@ -65,8 +122,7 @@ unsafe fn load_from_payload<'a>(
   );
 
   debug!(
-    "load {payload_size_in_byte} byte from payload: raw_data={} strb={} data={}",
-    hex::encode(byte_vec),
+    "load {beats} beats from payload: strb={} data={}",
     hex::encode(strobe),
     hex::encode(data),
   );
@@ -116,8 +172,32 @@ unsafe extern "C" fn axi_write(
   }
   let mut driver = DPI_TARGET.lock().unwrap();
   if let Some(driver) = driver.as_mut() {
-    let (strobe, data) = load_from_payload(payload, driver.dlen);
-    if let Err(e) = driver.axi_write(awaddr as u32, awsize as u64, &strobe, data) {
+    // the beats past the first AXI_PAYLOAD_BEATS ones came with axi_write_chunk
+    let total = awlen as usize + 1;
+    let buffered = driver.buffered_write_beats(channel_id as u64);
+    let beats = total.saturating_sub(buffered).min(AXI_PAYLOAD_BEATS);
+    let (strobe, data) = load_from_payload(payload, driver.dlen, beats);
+    driver.axi_write_chunk(channel_id as u64, &strobe, data);
+    let (strobe, data) = driver.take_write_burst(channel_id as u64);
+    let result = if buffered + beats != total {
+      Err(anyhow::anyhow!(
+        "axi_write addr={awaddr:#x} awlen={awlen} came with {} W beats",
+        buffered + beats
+      ))
+    } else {
+      let req = AxiRequest {
+        channel_id: channel_id as u64,
+        id: awid as u64,
+        addr: awaddr as u32,
+        len: awlen as u64,
+        size: awsize as u64,
+        burst: awburst as u64,
+        lock: awlock as u64,
+        prot: awprot as u64,
+      };
+      driver.axi_write(&req, &strobe, &data)
+    };
+    if let Err(e) = result {
       if awaddr as u64 != LAST_WRITE_PC {
         error!("{}", e);
       }
@@ -128,6 +208,25 @@ unsafe extern "C" fn axi_write(
   LAST_WRITE_PC = awaddr as u64;
 }
 
+/// AXI_PAYLOAD_BEATS W beats of a burst longer than the payload, the rest comes with axi_write
+#[no_mangle]
+unsafe extern "C" fn axi_write_chunk(channel_id: c_longlong, payload: *const SvBitVecVal) {
+  let mut driver = DPI_TARGET.lock().unwrap();
+  if let Some(driver) = driver.as_mut() {
+    let (strobe, data) = load_from_payload(payload, driver.dlen, AXI_PAYLOAD_BEATS);
+    driver.axi_write_chunk(channel_id as u64, &strobe, data);
+  }
+}
+
+/// Hand the next B of `channel_id` to the testbench, if one is ready
+#[no_mangle]
+unsafe extern "C" fn axi_write_poll(channel_id: c_longlong, response: *mut SvBitVecVal) {
+  let mut driver = DPI_TARGET.lock().unwrap();
+  if let Some(driver) = driver.as_mut() {
+    fill_axi_write_response(response, driver.axi_write_poll(channel_id as u64));
+  }
+}
+
 pub(crate) static mut LAST_READ_PC: u64 = 0;
 #[no_mangle]
 unsafe extern "C" fn axi_read(
@@ -142,7 +241,6 @@ unsafe extern "C" fn axi_read(
   arprot: c_longlong,
   arqos: c_longlong,
   arregion: c_longlong,
-  payload: *mut SvBitVecVal,
 ) {
   // 防止重复打印,但dirty且不靠谱
   if LAST_READ_PC != araddr as u64 {
@@ -154,27 +252,81 @@ unsafe extern "C" fn axi_read(
   }
   let mut driver = DPI_TARGET.lock().unwrap();
   if let Some(driver) = driver.as_mut() {
-    let response = match driver.axi_read(araddr as u32, arsize as u64) {
-      Ok(response) => response,
-      Err(e) => {
-        if araddr as u64 != LAST_READ_PC {
-          error!("{}", e);
-        }
-        driver.state = SimState::BadTrap;
-        LAST_READ_PC = araddr as u64;
-        return;
-      }
+    let req = AxiRequest {
+      channel_id: channel_id as u64,
+      id: arid as u64,
+      addr: araddr as u32,
+      len: arlen as u64,
+      size: arsize as u64,
+      burst: arburst as u64,
+      lock: arlock as u64,
+      prot: arprot as u64,
     };
-    fill_axi_read_payload(payload, driver.dlen, &response);
+    if let Err(e) = driver.axi_read(&req) {
+      if araddr as u64 != LAST_READ_PC {
+        error!("{}", e);
+      }
+      driver.state = SimState::BadTrap;
+      LAST_READ_PC = araddr as u64;
+      return;
+    }
   }
   LAST_READ_PC = araddr as u64;
 }
 
+/// Hand the next R burst of `channel_id` to the testbench, if one is ready,
+/// AXI_PAYLOAD_BEATS beats at a time
+#[no_mangle]
+unsafe extern "C" fn axi_read_poll(channel_id: c_longlong, response: *mut SvBitVecVal) {
+  let mut driver = DPI_TARGET.lock().unwrap();
+  if let Some(driver) = driver.as_mut() {
+    let dlen = driver.dlen;
+    fill_axi_read_response(response, dlen, driver.axi_read_poll(channel_id as u64));
+  }
+}
+
+/// Whether `+axi-fuzz` is on, checked before taking the driver lock every cycle
+static AXI_FUZZ: AtomicBool = AtomicBool::new(false);
+
+/// Which handshakes of `channel_id` the VIP stalls in the coming cycle
+#[no_mangle]
+unsafe extern "C" fn axi_stall(channel_id: c_longlong, stall: *mut SvBitVecVal) {
+  if !AXI_FUZZ.load(Ordering::Relaxed) {
+    *stall = 0;
+    return;
+  }
+  let mut driver = DPI_TARGET.lock().unwrap();
+  *stall = match driver.as_mut() {
+    Some(driver) => driver.axi_stall(channel_id as u64) as SvBitVecVal,
+    None => 0,
+  };
+}
+
+/// Interrupt lines of the devices, sampled by the DUT every cycle
+#[no_mangle]
+unsafe extern "C" fn interrupt_lines(lines: *mut SvBitVecVal) {
+  let driver = DPI_TARGET.lock().unwrap();
+  *lines = driver.as_ref().map_or(0, |driver| driver.interrupt_lines() as SvBitVecVal);
+}
+
 #[no_mangle]
 unsafe extern "C" fn sim_init() {
   let plusargs = PlusArgMatcher::from_args();
   let args = SimArgs::from_plusargs(&plusargs);
   args.setup_logger().unwrap();
+  if args.mem_image_build {
+    let exit_code = match args.load_bus_config().and_then(|c| build_images(&c, &args.elf_file)) {
+      Ok(()) => 0,
+      Err(e) => {
+        error!("fail building memory images: {e:#}");
+        1
+      }
+    };
+    let mut file = File::create("exit_code.txt").unwrap();
+    file.write_all(format!("{}", exit_code).as_bytes()).unwrap();
+    // nothing was simulated nor dumped yet
+    std::process::exit(exit_code);
+  }
   let scope = SvScope::get_current().expect("failed to get scope in sim_init");
 
   let mut dpi_target = DPI_TARGET.lock().unwrap();
@@ -182,12 +334,14 @@ unsafe extern "C" fn sim_init() {
 
   let driver = Box::new(Driver::new(scope, &args));
   *dpi_target = Some(driver);
+  AXI_FUZZ.store(args.axi_fuzz.is_some(), Ordering::Relaxed);
 }
 
 #[no_mangle]
 unsafe extern "C" fn sim_final() {
   let mut driver = DPI_TARGET.lock().unwrap();
   if let Some(driver) = driver.as_mut() {
+    driver.finish();
     match driver.state {
       SimState::GoodTrap => info!("sim_final: GoodTrap"),
       SimState::BadTrap => error!("sim_final: BadTrap"),
@@ -235,8 +389,8 @@ unsafe extern "C" fn calculate_ipc(instruction_count: u64, simulation_time: u64)
 }
 
 // 被内存布局搞晕了，先这样吧
+/// Called for every instruction the DUT retires, with or without difftest
 #[no_mangle]
-#[cfg(feature = "difftest")]
 unsafe extern "C" fn retire_instruction(
   inst: u32,
   pc: u64,
@@ -1,22 +1,26 @@
 use anyhow::Context;
 use elf::{
-  abi::{EM_RISCV, ET_EXEC, PT_LOAD, STT_FUNC},
+  abi::{EM_RISCV, ET_EXEC, PT_LOAD, STT_FUNC, STT_OBJECT},
   endian::LittleEndian,
   ElfStream,
 };
 use regex::Captures;
 use riscv_isa::{decode_full, decode_compressed, Target};
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
 use std::os::unix::fs::FileExt;
 use std::str::FromStr;
 use std::{fs, path::Path};
 use svdpi::{get_time, SvScope};
-use tracing::{debug, error, info, trace};
+use tracing::{debug, error, info, trace, warn};
 
 #[cfg(feature = "trace")]
 use crate::dpi::dump_wave;
 use crate::{
-  bus::ShadowBus,
+  axi::{
+    burst_addrs, AxiBurst, AxiFuzz, AxiMonitor, AxiRequest, AxiResp, AxiScheduler, CheckLevel,
+    AXI_PAYLOAD_BEATS,
+  },
+  bus::{MemInit, ShadowBus, DUT_IRQ_LINES},
   dpi::{AxiReadPayload, RetireData},
   ref_module::{RefModule, nemu::NemuEvent},
   SimArgs,
@@ -29,8 +33,11 @@ pub struct FunctionSym {
   pub(crate) name: String,
   #[allow(dead_code)]
   pub(crate) info: u8,
+  /// `st_size`, the symbol covers `[start, start + size)`
+  pub(crate) size: u64,
 }
-pub type FunctionSymTab = HashMap<u64, FunctionSym>;
+/// Function and data symbols by start address, for looking up the nearest one
+pub type FunctionSymTab = BTreeMap<u64, FunctionSym>;
 
 #[repr(u8)]
 #[derive(Copy, Clone, PartialEq)]
@@ -49,6 +56,7 @@ const EXIT_CODE: u32 = 0xdead_beef;
 struct Itrace {
   pc: u64,
   inst: u32,
+  #[cfg_attr(not(feature = "difftest"), allow(dead_code))]
   disasm: String
 }
 
@@ -57,6 +65,19 @@ pub(crate) struct Driver {
   refmodule: RefModule,
 
   bus: ShadowBus,
+  axi_monitor: AxiMonitor,
+  read_sched: AxiScheduler<AxiReadPayload>,
+  write_sched: AxiScheduler<AxiResp>,
+  /// (id, beats left) of the R burst being handed out, by channel
+  read_rest: HashMap<u64, (u64, AxiReadPayload)>,
+  /// (strobe, data) of the W beats received ahead of their AW, by channel
+  write_chunks: HashMap<u64, (Vec<bool>, Vec<u8>)>,
+  axi_fuzz: Option<AxiFuzz>,
+
+  uninit_check: CheckLevel,
+  /// doublewords already reported as read before written
+  uninit_reported: HashSet<usize>,
+  fn_sym_tab: FunctionSymTab,
 
   #[cfg(feature = "trace")]
   dump_control: DumpControl,
@@ -68,18 +89,25 @@ pub(crate) struct Driver {
   last_commit_cycle: u64,
 
   pub(crate) state: SimState,
+  /// interrupt lines raised by the devices, in `mip` bit layout
+  pending_irq: u64,
 
   pub(crate) dlen: u32,
 
   pub(crate) pc: u64,
+  /// instructions the DUT retired so far
+  instret: u64,
   pub(crate) gpr: [u64; 32],
   pub(crate) a0: u64, //check return
   skip: bool,
   target: Target,
-  itrace_stack: Vec<Itrace>,
+  /// the last ITRACE_DEPTH instructions the DUT retired, oldest first
+  itrace_stack: VecDeque<Itrace>,
 }
 
 static MAX_TIME: u64 = 10000000;
+/// retired instructions kept for diagnostics
+const ITRACE_DEPTH: usize = 16;
 
 impl Driver {
   fn get_tick(&self) -> u64 {
@@ -87,15 +115,50 @@ impl Driver {
   }
 
   pub(crate) fn new(scope: SvScope, args: &SimArgs) -> Self {
-    let (e_entry, shadow_bus, _fn_sym_tab, refmodule) =
-      Self::load_elf(&args.elf_file).expect("fail creating simulator");
+    let bus_config = args.load_bus_config().expect("fail loading bus config");
+    match args.mem_init {
+      MemInit::Random(seed) => info!("mem init: seed {seed}, replay with +mem-init=random:{seed}"),
+      MemInit::Pattern(word) => info!("mem init: pattern {word:#018x}"),
+      MemInit::Zero => {}
+    }
+    let mut shadow_bus = ShadowBus::from_config(
+      &bus_config,
+      &args.elf_file,
+      args.mem_init,
+      args.uninit_check != CheckLevel::Off,
+    )
+    .expect("fail creating shadow bus");
+    for watch in &args.watch {
+      let id = shadow_bus.watch(watch.clone());
+      info!(
+        "watch #{id}: [{:#x}, {:#x}) {:?}",
+        watch.start, watch.end, watch.access
+      );
+    }
+    let (e_entry, shadow_bus, fn_sym_tab, refmodule) =
+      Self::load_elf(&args.elf_file, shadow_bus).expect("fail creating simulator");
 
     //refmodule.display();
 
+    let axi_fuzz = args.axi_fuzz.map(|seed| {
+      info!("axi fuzz: seed {seed}, replay with +axi-fuzz={seed}");
+      AxiFuzz::new(seed, args.axi_fuzz_delay, args.axi_fuzz_stall)
+    });
+
+    let dlen = 64;
     let self_ = Self {
       #[cfg(feature = "difftest")]
       refmodule,
       bus: shadow_bus,
+      axi_monitor: AxiMonitor::new(args.axi_check, dlen / 8),
+      read_sched: AxiScheduler::new(args.axi_order, args.axi_hold),
+      write_sched: AxiScheduler::new(args.axi_order, args.axi_hold),
+      read_rest: HashMap::new(),
+      write_chunks: HashMap::new(),
+      axi_fuzz,
+      uninit_check: args.uninit_check,
+      uninit_reported: HashSet::new(),
+      fn_sym_tab,
       #[cfg(feature = "trace")]
       dump_control: DumpControl::new(scope, &args.wave_path, args.dump_start, args.dump_end),
       e_entry,
@@ -104,17 +167,22 @@ impl Driver {
       clock_flip_time: env!("CLOCK_FLIP_TIME").parse().unwrap(),
       last_commit_cycle: 0,
       state: SimState::Running,
-      dlen: 64,
+      pending_irq: 0,
+      dlen,
       pc: 0x8000_0000,
+      instret: 0,
       gpr: [0; 32],
       a0: 0,
       skip: false,
       target: Target::from_str("RV64IMACZifencei_Zicsr").unwrap(),
-      itrace_stack: Vec::new(),
+      itrace_stack: VecDeque::with_capacity(ITRACE_DEPTH),
     };
     self_
   }
-  pub fn load_elf(path: &Path) -> anyhow::Result<(u64, ShadowBus, FunctionSymTab, RefModule)> {
+  pub fn load_elf(
+    path: &Path,
+    mut mem: ShadowBus,
+  ) -> anyhow::Result<(u64, ShadowBus, FunctionSymTab, RefModule)> {
     let file = fs::File::open(path).with_context(|| "reading ELF file")?;
     let mut elf: ElfStream<LittleEndian, _> =
       ElfStream::open_stream(&file).with_context(|| "parsing ELF file")?;
@@ -132,14 +200,17 @@ impl Driver {
     }
 
     debug!("ELF entry: 0x{:x}", elf.ehdr.e_entry);
-    let mut mem = ShadowBus::new();
     let mut load_buffer = Vec::new();
     //#[cfg(feature = "difftest")]
     let mut refmodule = RefModule::new();
+    // same initial contents as the shadow memory, before the segments go on top
+    #[cfg(feature = "difftest")]
+    mem.mirror_mem_init(|addr, bytes| refmodule.load_mem_seg(addr, bytes));
 
     elf.segments().iter().filter(|phdr| phdr.p_type == PT_LOAD).for_each(|phdr| {
       let vaddr: usize = phdr.p_vaddr.try_into().expect("fail converting vaddr(u64) to usize");
       let filesz: usize = phdr.p_filesz.try_into().expect("fail converting p_filesz(u64) to usize");
+      let memsz: usize = phdr.p_memsz.try_into().expect("fail converting p_memsz(u64) to usize");
       debug!(
         "Read loadable segments 0x{:x}..0x{:x} to memory 0x{:x}",
         phdr.p_offset,
@@ -156,6 +227,8 @@ impl Driver {
           vaddr, filesz, phdr.p_offset, err
         )
       });
+      // the part past the file contents (.bss) is zero
+      load_buffer.resize(memsz.max(filesz), 0u8);
       mem.load_mem_seg(vaddr, load_buffer.as_mut_slice());
       #[cfg(feature = "difftest")]
       refmodule.load_mem_seg(vaddr, load_buffer.as_mut_slice());
@@ -169,14 +242,14 @@ impl Driver {
       parsed_table
         .iter()
         // st_symtype = symbol.st_info & 0xf (But why masking here?)
-        .filter(|sym| sym.st_symtype() == STT_FUNC)
+        .filter(|sym| matches!(sym.st_symtype(), STT_FUNC | STT_OBJECT))
         .for_each(|sym| {
           let name = string_table
             .get(sym.st_name as usize)
             .unwrap_or_else(|_| panic!("fail to get name at st_name={}", sym.st_name));
           fn_sym_tab.insert(
             sym.st_value,
-            FunctionSym { name: name.to_string(), info: sym.st_symtype() },
+            FunctionSym { name: name.to_string(), info: sym.st_symtype(), size: sym.st_size },
           );
         });
     } else {
@@ -186,42 +259,292 @@ impl Driver {
     Ok((elf.ehdr.e_entry, mem, fn_sym_tab, refmodule))
   }
 
-  pub(crate) fn axi_read(&mut self, addr: u32, arsize: u64) -> anyhow::Result<AxiReadPayload> {
+  /// Serve the read right away, its response waits in the scheduler until polled
+  pub(crate) fn axi_read(&mut self, req: &AxiRequest) -> anyhow::Result<()> {
+    let tick = self.get_tick();
+    let (payload, result) = match self.serve_read(req) {
+      Ok(payload) => (payload, Ok(())),
+      // still answer, so the channel does not hang before the simulation stops
+      Err(e) => (
+        AxiReadPayload::error(req.len as usize + 1, self.dlen / 8),
+        Err(self.diagnose(req, "read", e)),
+      ),
+    };
+    let delay = self.bus.response_delay(req.addr, req.bytes(), tick) + self.fuzz_delay();
+    self.read_sched.push(req.channel_id, req.id, tick, delay, payload);
+    result
+  }
+
+  /// The next AXI_PAYLOAD_BEATS beats at most of the read responses of `channel_id`,
+  /// with their ID and whether more beats of the burst follow
+  pub(crate) fn axi_read_poll(&mut self, channel_id: u64) -> Option<(u64, AxiReadPayload, bool)> {
+    let tick = self.get_tick();
+    let (id, mut payload) = match self.read_rest.remove(&channel_id) {
+      Some(rest) => rest,
+      None => self.read_sched.pop(channel_id, tick)?,
+    };
+    let more = payload.resp.len() > AXI_PAYLOAD_BEATS;
+    if more {
+      let rest = payload.split_off(AXI_PAYLOAD_BEATS);
+      self.read_rest.insert(channel_id, (id, rest));
+    }
+    Some((id, payload, more))
+  }
+
+  /// W beats of `channel_id` received so far for the next AW
+  pub(crate) fn buffered_write_beats(&self, channel_id: u64) -> usize {
+    let bus_size = (self.dlen / 8) as usize;
+    self.write_chunks.get(&channel_id).map_or(0, |(_, data)| data.len() / bus_size)
+  }
+
+  /// Keep W beats of `channel_id` until the rest of the burst arrives
+  pub(crate) fn axi_write_chunk(&mut self, channel_id: u64, strobe: &[bool], data: &[u8]) {
+    let (strobes, beats) = self.write_chunks.entry(channel_id).or_default();
+    strobes.extend_from_slice(strobe);
+    beats.extend_from_slice(data);
+  }
+
+  /// (strobe, data) of every W beat of `channel_id` received so far
+  pub(crate) fn take_write_burst(&mut self, channel_id: u64) -> (Vec<bool>, Vec<u8>) {
+    self.write_chunks.remove(&channel_id).unwrap_or_default()
+  }
+
+  /// Serve the write right away, its response waits in the scheduler until polled
+  pub(crate) fn axi_write(
+    &mut self,
+    req: &AxiRequest,
+    strobe: &[bool],
+    data: &[u8],
+  ) -> anyhow::Result<()> {
+    let tick = self.get_tick();
+    let (resp, result) = match self.serve_write(req, strobe, data) {
+      Ok(resp) => (resp, Ok(())),
+      Err(e) => (AxiResp::SlvErr, Err(self.diagnose(req, "write", e))),
+    };
+    let delay = self.bus.response_delay(req.addr, req.bytes(), tick) + self.fuzz_delay();
+    self.write_sched.push(req.channel_id, req.id, tick, delay, resp);
+    result
+  }
+
+  /// The next write response of `channel_id`, with its ID
+  pub(crate) fn axi_write_poll(&mut self, channel_id: u64) -> Option<(u64, AxiResp)> {
+    let tick = self.get_tick();
+    self.write_sched.pop(channel_id, tick)
+  }
+
+  /// Random extra response delay of the stress mode
+  fn fuzz_delay(&mut self) -> u64 {
+    self.axi_fuzz.as_mut().map_or(0, |fuzz| fuzz.delay())
+  }
+
+  /// Ready/valid stall bits of `channel_id` for the coming cycle
+  pub(crate) fn axi_stall(&mut self, channel_id: u64) -> u8 {
+    self.axi_fuzz.as_mut().map_or(0, |fuzz| fuzz.stall(channel_id))
+  }
+
+  fn serve_read(&mut self, req: &AxiRequest) -> anyhow::Result<AxiReadPayload> {
+    self.axi_monitor.check_read(self.get_tick(), req)?;
+    let AxiRequest { addr, len: arlen, size: arsize, burst: arburst, .. } = *req;
     let size = 1 << arsize;
-    let data = self.bus.read_mem_axi(addr, size, self.dlen / 8)?;
+    let bus_size = self.dlen / 8;
+    let burst = AxiBurst::from_bits(arburst)?;
+    let mut data = Vec::with_capacity((arlen as usize + 1) * bus_size as usize);
+    let mut resp = Vec::with_capacity(arlen as usize + 1);
+    for beat_addr in burst_addrs(addr, arlen, arsize, burst)? {
+      let (beat_resp, beat) = self.bus.read_mem_axi(beat_addr, size, bus_size)?;
+      if beat_resp == AxiResp::Okay {
+        self.check_uninit(beat_addr as usize, size as usize)?;
+      }
+      let start = beat_addr as usize;
+      self.check_watch(start, start + size as usize, &beat, None);
+      data.extend_from_slice(&beat);
+      resp.push(beat_resp);
+    }
+    if req.exclusive() && resp.iter().all(|r| *r == AxiResp::Okay) {
+      self.bus.reserve((req.channel_id, req.id), addr, req.bytes());
+      resp.fill(AxiResp::ExOkay);
+    }
     let data_hex = hex::encode(&data);
     unsafe {
       use crate::dpi::LAST_READ_PC;
       if addr as u64 != LAST_READ_PC {
         trace!(
-          "\x1b[34m[{}]\x1b[0m \x1b[35maxi_read\x1b[0m   (addr=\x1b[36m{addr:#x}\x1b[0m, size=\x1b[35m{size}\x1b[0m, data=\x1b[33m{data_hex}\x1b[0m)", 
+          "\x1b[34m[{}]\x1b[0m \x1b[35maxi_read\x1b[0m   (addr=\x1b[36m{addr:#x}\x1b[0m, size=\x1b[35m{size}\x1b[0m, len={arlen}, burst={burst:?}, data=\x1b[33m{data_hex}\x1b[0m)", 
           //"[{}] axi_read  (addr={addr:#x}, size={size}, data={data_hex})",
           self.get_tick()
         );
       }
     }
-    Ok(AxiReadPayload { data })
+    Ok(AxiReadPayload { data, resp })
   }
 
-  pub(crate) fn axi_write(
+  /// Report a read of memory that was never loaded nor written, once per doubleword
+  fn check_uninit(&mut self, addr: usize, size: usize) -> anyhow::Result<()> {
+    if self.uninit_check == CheckLevel::Off {
+      return Ok(());
+    }
+    let Some(first) = self.bus.first_uninitialized(addr, size) else {
+      return Ok(());
+    };
+    if !self.uninit_reported.insert(first & !7) {
+      return Ok(());
+    }
+    let (pc, _) = self.last_retired();
+    let msg = format!(
+      "[{}] read of uninitialized memory at {first:#x} (access {addr:#x}, size={size}B), \
+      last retired pc={pc:#x} <{}>, near <{}>",
+      self.get_tick(),
+      self.symbol_at(pc),
+      self.symbol_at(first as u64),
+    );
+    match self.uninit_check {
+      CheckLevel::Fatal => {
+        error!("{msg}");
+        anyhow::bail!("read of uninitialized memory")
+      }
+      _ => {
+        warn!("{msg}");
+        Ok(())
+      }
+    }
+  }
+
+  /// Log the beat if it hits a watchpoint, `strobe` is None for reads
+  fn check_watch(&mut self, start: usize, end: usize, data: &[u8], strobe: Option<&[bool]>) {
+    let write = strobe.is_some();
+    let hits: Vec<(usize, bool)> = self
+      .bus
+      .watch_hits(start, end, write)
+      .into_iter()
+      .map(|(id, watch)| (id, watch.stop))
+      .collect();
+    if hits.is_empty() {
+      return;
+    }
+    let tick = self.get_tick();
+    let (pc, inst) = self.last_retired();
+    let strobe = match strobe {
+      Some(strobe) => {
+        let mask = strobe.iter().rev().fold(0u64, |mask, s| mask << 1 | *s as u64);
+        format!(", strobe={mask:#x}")
+      }
+      None => String::new(),
+    };
+    for (id, stop) in hits {
+      info!(
+        "[{tick}] watch #{id}: {} [{start:#x}, {end:#x}) data={}{strobe}, \
+        last retired pc={pc:#x} <{}> inst={inst:#010x}",
+        if write { "write" } else { "read" },
+        hex::encode(data),
+        self.symbol_at(pc),
+      );
+      if stop {
+        info!("[{tick}] watch #{id} stops the simulation");
+        self.state = SimState::Finished;
+      }
+    }
+  }
+
+  fn last_retired(&self) -> (u64, u32) {
+    self.itrace_stack.back().map_or((0, 0), |t| (t.pc, t.inst))
+  }
+
+  /// Add what the program was doing to the error of a failed access
+  fn diagnose(&self, req: &AxiRequest, dir: &str, e: anyhow::Error) -> anyhow::Error {
+    let kind = if req.fetch() {
+      "instruction fetch"
+    } else {
+      "data"
+    };
+    let (pc, _) = self.last_retired();
+    let history = self
+      .itrace_stack
+      .iter()
+      .map(|t| {
+        format!(
+          "\t{:#018x} | {:#010x} | {:<28} <{}>",
+          t.pc,
+          t.inst,
+          self.disasm_raw(t.inst),
+          self.symbol_at(t.pc)
+        )
+      })
+      .collect::<Vec<_>>()
+      .join("\n");
+    anyhow::anyhow!(
+      "{e:#}\n\
+      \t{kind} {dir} (channel_id={}, id={}, addr={:#x}, len={}, size={}, prot={:#x})\n\
+      \tlast retired pc={pc:#x} <{}>\n\
+      Last {} retired instructions:\n{history}",
+      req.channel_id,
+      req.id,
+      req.addr,
+      req.len,
+      req.size,
+      req.prot,
+      self.symbol_at(pc),
+      self.itrace_stack.len(),
+    )
+  }
+
+  fn symbol_at(&self, addr: u64) -> String {
+    symbol_at(&self.fn_sym_tab, addr)
+  }
+
+  /// `strobe` and `data` hold `awlen + 1` bus-width beats,
+  /// returns the worst response among the beats
+  fn serve_write(
     &mut self,
-    addr: u32,
-    awsize: u64,
+    req: &AxiRequest,
     strobe: &[bool],
     data: &[u8],
-  ) -> anyhow::Result<()> {
+  ) -> anyhow::Result<AxiResp> {
+    self.axi_monitor.check_write(self.get_tick(), req, strobe)?;
+    let AxiRequest { addr, len: awlen, size: awsize, burst: awburst, .. } = *req;
     let size = 1 << awsize;
+    let bus_size = self.dlen / 8;
     // check exit with code
     if addr == EXIT_POS {
       let exit_data_slice = data[..4].try_into().expect("slice with incorrect length");
       if u32::from_le_bytes(exit_data_slice) == EXIT_CODE {
         info!("driver is ready to quit");
         self.state = SimState::Finished;
-        return Ok(());
+        return Ok(AxiResp::Okay);
       }
     }
 
-    self.bus.write_mem_axi(addr, size, self.dlen / 8, strobe, data)?;
+    let burst = AxiBurst::from_bits(awburst)?;
+    // a failed exclusive write leaves the memory alone and gets OKAY
+    let exclusive = req.exclusive();
+    if exclusive && !self.bus.exclusive_ok((req.channel_id, req.id), addr, req.bytes()) {
+      debug!(
+        "[{}] exclusive write to {addr:#x} (id={}) failed",
+        self.get_tick(),
+        req.id
+      );
+      return Ok(AxiResp::Okay);
+    }
+    let beats = burst_addrs(addr, awlen, awsize, burst)?;
+    let chunks = strobe.chunks(bus_size as usize).zip(data.chunks(bus_size as usize));
+    let mut resp = AxiResp::Okay;
+    for (beat_addr, (beat_strobe, beat_data)) in beats.into_iter().zip(chunks) {
+      // the strobed lanes of the beat
+      let lane_base = beat_addr as usize & !(bus_size as usize - 1);
+      let first = beat_strobe.iter().position(|s| *s);
+      let last = beat_strobe.iter().rposition(|s| *s);
+      if let (Some(first), Some(last)) = (first, last) {
+        self.check_watch(
+          lane_base + first,
+          lane_base + last + 1,
+          beat_data,
+          Some(beat_strobe),
+        );
+      }
+      resp = resp.max(self.bus.write_mem_axi(beat_addr, size, bus_size, beat_strobe, beat_data)?);
+    }
+    if exclusive && resp == AxiResp::Okay {
+      resp = AxiResp::ExOkay;
+    }
     let data_hex = hex::encode(data);
     self.last_commit_cycle = self.get_tick();
 
@@ -229,21 +552,50 @@ impl Driver {
       use crate::dpi::LAST_WRITE_PC;
       if addr as u64 != LAST_WRITE_PC {
         trace!(
-          "\x1b[34m[{}]\x1b[0m \x1b[33maxi_write\x1b[0m (addr=\x1b[36m{addr:#x}\x1b[0m, size={size}, data=\x1b[32m{data_hex}\x1b[0m)", 
+          "\x1b[34m[{}]\x1b[0m \x1b[33maxi_write\x1b[0m (addr=\x1b[36m{addr:#x}\x1b[0m, size={size}, len={awlen}, burst={burst:?}, data=\x1b[32m{data_hex}\x1b[0m)", 
           //"[{}] axi_write (addr={addr:#x}, size={size}, data={data_hex})",
           self.get_tick()
         );
       }
     }
 
-    Ok(())
+    Ok(resp)
+  }
+
+  /// Advance the devices and record the interrupt lines they raise
+  fn update_interrupts(&mut self, tick: u64) {
+    let mip = self.bus.tick(tick);
+    // devices may have written memory behind the DUT's back
+    #[cfg(feature = "difftest")]
+    for (addr, data) in self.bus.take_dma_writes() {
+      self.refmodule.load_mem_seg(addr, &data);
+    }
+    let raised = mip & !self.pending_irq;
+    if raised != 0 {
+      debug!("[{tick}] interrupt pending (mip={mip:#x}, raised={raised:#x})");
+    }
+    self.pending_irq = mip;
+  }
+
+  /// Interrupt lines the DUT samples in the coming cycle, in `mip` bit layout
+  pub(crate) fn interrupt_lines(&self) -> u64 {
+    self.pending_irq & DUT_IRQ_LINES
+  }
+
+  /// The simulation is over, let the devices flush their output
+  pub(crate) fn finish(&mut self) {
+    self.bus.flush();
+    self.axi_monitor.summary();
+    self.bus.timing_summary();
   }
 
   pub(crate) fn watchdog(&mut self) -> u8 {
     self.state = match self.state {
       SimState::Running => {
-        //check timeout
         let tick = self.get_tick();
+        self.update_interrupts(tick);
+
+        //check timeout
         if tick - self.last_commit_cycle > self.timeout {
           error!(
             "[{tick}] watchdog timeout (last_commit_cycle={})",
@@ -278,12 +630,17 @@ impl Driver {
     self.state as u8
   }
 
-  pub(crate) fn disasm(&mut self, inst: u32, gpr: [u64; 32]) -> String {
-    let raw = if inst % 4 == 3 {
+  /// Disassembly of `inst`, without register values
+  fn disasm_raw(&self, inst: u32) -> String {
+    if inst % 4 == 3 {
       decode_full(inst, &self.target).to_string()
     } else {
       decode_compressed(inst as u16, &self.target).to_string()
-    };
+    }
+  }
+
+  pub(crate) fn disasm(&mut self, inst: u32, gpr: [u64; 32]) -> String {
+    let raw = self.disasm_raw(inst);
     let re = regex::Regex::new(
       r"(?x)
         (\b\d+\b)       # 匹配纯数字offset(第1捕获组)
@@ -331,6 +688,13 @@ impl Driver {
 
   pub(crate) fn retire_instruction(&mut self, dut: &RetireData) {
     self.last_commit_cycle = self.get_tick();
+    if self.itrace_stack.len() == ITRACE_DEPTH {
+      self.itrace_stack.pop_front();
+    }
+    // the disassembly is only filled in by difftest, which knows the register values
+    self.itrace_stack.push_back(Itrace { pc: dut.pc, inst: dut.inst, disasm: String::new() });
+    self.instret += 1;
+    self.bus.retire(self.instret);
 
     // 避免输出多次
     if self.state != SimState::Running {
@@ -355,6 +719,22 @@ impl Driver {
         self.refmodule.override_event(event);
       }
 
+      // the DUT took an interrupt before this instruction, make the ref take it as well
+      let mcause = dut.csr[9];
+      if !self.skip
+        && self.pc != dut.pc
+        && mcause >> 63 != 0
+        && self.pending_irq & (1 << (mcause & 0x3f)) != 0
+      {
+        debug!(
+          "[{}] interrupt {mcause:#x} taken at pc={:#x}",
+          self.get_tick(),
+          self.pc
+        );
+        self.refmodule.raise_intr(mcause);
+        self.pc = dut.pc;
+      }
+
       let ref_event = self.refmodule.step();
       let mut error_msg = String::new();
 
@@ -387,8 +767,12 @@ impl Driver {
 
       //check csr
       for i in 0..18 {
-        let ref_csr = ref_csr[i];
-        let dut_csr = dut_csr[i];
+        let (mut ref_csr, mut dut_csr) = (ref_csr[i], dut_csr[i]);
+        // the device lines only show up in the DUT's mip, the ref is told when an interrupt is taken
+        if csr_name(i) == "mip" {
+          ref_csr &= !DUT_IRQ_LINES;
+          dut_csr &= !DUT_IRQ_LINES;
+        }
         if ref_csr != dut_csr {
           error_msg += &format!("\tcsr {} mismatch! ref={:#x}, dut={:#x}\n", csr_name(i), ref_csr, dut_csr);
           mismatch = true;
@@ -431,9 +815,11 @@ impl Driver {
           pc = dut_pc,
           inst = dut_inst,
           disasm = disasm_result,
-          itrace = self.itrace_stack.iter().rev().take(10).enumerate().map(|(i, t)| 
-            format!("[{:2}] PC: {:#018x} | {:#010x} | {}", 9 - i, t.pc, t.inst, t.disasm)
-          ).collect::<Vec<_>>().join("\n"),
+          itrace = self.itrace_stack.iter().rev().skip(1).take(10).enumerate().map(|(i, t)| {
+            // skipped instructions were never compared
+            let disasm = if t.disasm.is_empty() { self.disasm_raw(t.inst) } else { t.disasm.clone() };
+            format!("[{:2}] PC: {:#018x} | {:#010x} | {}", 9 - i, t.pc, t.inst, disasm)
+          }).collect::<Vec<_>>().join("\n"),
           ref_status = self.refmodule.status()
         );
 
@@ -444,7 +830,9 @@ impl Driver {
       self.gpr = dut_gpr;
       self.a0 = ref_gpr[10];
       self.skip = false;
-      self.itrace_stack.push(Itrace { pc: dut_pc, inst: dut_inst, disasm: disasm_result });
+      if let Some(last) = self.itrace_stack.back_mut() {
+        last.disasm = disasm_result;
+      }
     }
   }
 }
@@ -489,3 +877,33 @@ impl DumpControl {
     }
   }
 }
+
+/// `name+offset` of the ELF symbol covering `addr`, `?` past the end of the closest one below
+fn symbol_at(fn_sym_tab: &FunctionSymTab, addr: u64) -> String {
+  match fn_sym_tab.range(..=addr).next_back() {
+    Some((start, sym)) if addr - start < sym.size => format!("{}+{:#x}", sym.name, addr - start),
+    _ => "?".to_string(),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn symbols_cover_their_size_only() {
+    let mut tab = FunctionSymTab::new();
+    let sym = |name: &str, size| FunctionSym { name: name.to_string(), info: STT_FUNC, size };
+    tab.insert(0x1000, sym("main", 0x20));
+    tab.insert(0x1040, sym("buf", 0x8));
+    tab.insert(0x1080, sym("label", 0));
+    assert_eq!(symbol_at(&tab, 0xfff), "?");
+    assert_eq!(symbol_at(&tab, 0x1000), "main+0x0");
+    assert_eq!(symbol_at(&tab, 0x101f), "main+0x1f");
+    // between main and buf
+    assert_eq!(symbol_at(&tab, 0x1020), "?");
+    assert_eq!(symbol_at(&tab, 0x1044), "buf+0x4");
+    assert_eq!(symbol_at(&tab, 0x1048), "?");
+    assert_eq!(symbol_at(&tab, 0x1080), "?");
+  }
+}
@@ -1,3 +1,5 @@
+use axi::{AxiOrder, CheckLevel};
+use bus::{parse_watchpoints, BusConfig, MemInit, Watchpoint};
 use plusarg::PlusArgMatcher;
 use std::{fs::File, path::PathBuf, sync::Mutex};
 
@@ -5,6 +7,7 @@ use tracing::Level;
 use tracing_subscriber::filter::EnvFilter;
 use tracing_subscriber::{filter::LevelFilter, fmt, prelude::*};
 
+pub mod axi;
 pub mod bus;
 pub mod dpi;
 pub mod drive;
@@ -20,6 +23,37 @@ pub(crate) struct SimArgs {
 
   pub log_level: String,
 
+  /// Path to the bus layout, None = built-in `nexus-am` layout
+  pub bus_config: Option<PathBuf>,
+
+  /// Only build the memory images of the bus layout, then exit
+  pub mem_image_build: bool,
+
+  /// Initial contents of plain RAM, mirrored into the reference model
+  pub mem_init: MemInit,
+
+  /// What reads of never written RAM do
+  pub uninit_check: CheckLevel,
+
+  /// Address ranges whose accesses are logged
+  pub watch: Vec<Watchpoint>,
+
+  /// What the AXI protocol monitor does with violations
+  pub axi_check: CheckLevel,
+
+  /// Order AXI responses are handed back in across IDs
+  pub axi_order: AxiOrder,
+
+  /// Ticks every AXI response is held at least
+  pub axi_hold: u64,
+
+  /// Seed of the AXI stress mode, None = no random timing
+  pub axi_fuzz: Option<u64>,
+  /// Upper bound of the random extra response delay
+  pub axi_fuzz_delay: u64,
+  /// Chance in percent of stalling each ready/valid per cycle
+  pub axi_fuzz_stall: u64,
+
   // ISA config, no use
   //pub set: String,
   //pub lvl: String,
@@ -68,6 +102,14 @@ impl SimArgs {
     Ok(())
   }
 
+  /// The bus layout to simulate
+  pub fn load_bus_config(&self) -> anyhow::Result<BusConfig> {
+    match &self.bus_config {
+      Some(path) => BusConfig::load(path),
+      None => Ok(BusConfig::default()),
+    }
+  }
+
   pub fn from_plusargs(matcher: &PlusArgMatcher) -> Self {
     Self {
       elf_file: matcher.match_("elf-file").into(),
@@ -75,6 +117,20 @@ impl SimArgs {
         matcher.try_match("log-file").unwrap_or("cpuemu.log"),
       )),
       log_level: matcher.try_match("log-level").unwrap_or("info").into(),
+      bus_config: matcher.try_match("bus-config").map(PathBuf::from),
+      mem_image_build: matcher.try_match("mem-image-build").is_some_and(|v| v != "0"),
+      mem_init: matcher.try_match("mem-init").unwrap_or("zero").parse().unwrap(),
+      uninit_check: matcher.try_match("uninit-check").unwrap_or("off").parse().unwrap(),
+      watch: parse_watchpoints(matcher.try_match("watch").unwrap_or_default()).unwrap(),
+      axi_check: matcher.try_match("axi-check").unwrap_or("warn").parse().unwrap(),
+      axi_order: matcher.try_match("axi-order").unwrap_or("in-order").parse().unwrap(),
+      axi_hold: matcher.try_match("axi-hold").unwrap_or("0").parse().unwrap(),
+      axi_fuzz: matcher.try_match("axi-fuzz").map(|seed| match seed {
+        "random" => rand::random(),
+        seed => seed.parse().unwrap(),
+      }),
+      axi_fuzz_delay: matcher.try_match("axi-fuzz-delay").unwrap_or("16").parse().unwrap(),
+      axi_fuzz_stall: matcher.try_match("axi-fuzz-stall").unwrap_or("25").parse().unwrap(),
       #[cfg(feature = "trace")]
       dump_start: matcher.try_match("dump-start").unwrap_or("0").parse().unwrap(),
       #[cfg(feature = "trace")]
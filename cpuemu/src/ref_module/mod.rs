@@ -36,6 +36,11 @@ impl RefModule {
     self.event
   }
 
+  /// Make the ref take interrupt `no` (mcause value, with the interrupt bit set)
+  pub fn raise_intr(&mut self, no: u64) {
+    self.module.raise_intr(no);
+  }
+
   pub fn override_event(&mut self, mut event: NemuEvent) {
     self.module.regcpy(&mut event as *mut NemuEvent as *mut (), DIFFTEST_TO_REF);
   }
@@ -4,4 +4,5 @@ lib.makeScope newScope (scope:{
   cputest = scope.callPackage ./nexus-am.nix { casePrefix = "cputest"; };
   amtest = scope.callPackage ./nexus-am.nix { casePrefix = "amtest"; caseName = "hello"; };
   riscv-tests = scope.callPackage ./riscv-tests.nix { };
+  interrupt = scope.callPackage ./interrupt.nix { };
 })
@@ -0,0 +1,28 @@
+{ pkgs
+, lib
+}:
+
+pkgs.pkgsCross.riscv64-embedded.stdenv.mkDerivation {
+  name = "interrupt-tests";
+
+  src = ../../tests;
+
+  dontConfigure = true;
+
+  buildPhase = ''
+    runHook preBuild
+    for case in interrupt/*.S; do
+      $CC -march=rv64imac_zicsr_zifencei -mabi=lp64 -static -mcmodel=medany \
+        -nostdlib -nostartfiles -Iriscv-test-env/p -Triscv-test-env/p/link.ld \
+        $case -o interrupt/$(basename $case .S).elf
+    done
+    runHook postBuild
+  '';
+
+  installPhase = ''
+    runHook preInstall
+    mkdir -p $out/tests/interrupt
+    cp interrupt/*.elf $out/tests/interrupt
+    runHook postInstall
+  '';
+}